@@ -1,46 +1,440 @@
-use std::{env, process::ExitCode};
+//! Checks a Rumia server is healthy, for use as a container health check. The exit code says why a
+//! check failed:
+//!
+//! | Code | Meaning                                             |
+//! |------|-----------------------------------------------------|
+//! | 0    | Healthy                                             |
+//! | 1    | The server answered with an unexpected status       |
+//! | 2    | The arguments were invalid                          |
+//! | 3    | The server couldn't be reached                      |
+//! | 4    | The server didn't answer in time                    |
+//! | 5    | HTTPS failed, eg the certificate wasn't trusted     |
+//! | 6    | The server answered with an unexpected body         |
+
+use clap::{Parser, ValueEnum};
+use serde_json::Value;
+use std::{fmt::Display, io, path::PathBuf, process::ExitCode, sync::Arc, thread, time::Duration};
+use ureq::{
+    Agent,
+    tls::{PemItem, RootCerts, TlsConfig, parse_pem},
+};
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    /// Base URL of the server. A URL ending in `/health` or `/health/ready`, as older versions
+    /// took, is used as given
+    #[arg(default_value = "http://localhost:10032")]
+    url: String,
+
+    /// Which check to make [default: liveness, or whichever the URL ends in]
+    #[arg(long, value_enum)]
+    endpoint: Option<Endpoint>,
+
+    /// Seconds to wait for each attempt
+    #[arg(long, default_value_t = 10)]
+    timeout: u64,
+
+    /// Status the server should answer with
+    #[arg(long, default_value_t = 200)]
+    expect_status: u16,
+
+    /// PEM file of CA certificates to trust for HTTPS, instead of the usual public roots
+    #[arg(long)]
+    ca_cert: Option<PathBuf>,
+
+    /// How many more times to try if a check fails
+    #[arg(long, default_value_t = 0)]
+    retries: u32,
+
+    /// Seconds to wait between attempts
+    #[arg(long, default_value_t = 1)]
+    retry_delay: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Endpoint {
+    /// `/health`, which answers as long as the server is running
+    Liveness,
+    /// `/health/ready`, which answers once the server can store files
+    Readiness,
+}
+
+/// Why a check failed
+#[derive(Debug)]
+enum Failure {
+    Arguments(String),
+    Status(u16),
+    Connect(String),
+    Timeout,
+    Tls(String),
+    Body(String),
+}
 
-#[inline]
 fn main() -> ExitCode {
-    let args: Vec<String> = env::args().collect();
+    let args = Args::parse();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(failure) => ExitCode::from(failure.code()),
+    }
+}
 
-    if args.len() > 2 {
-        panic!("too many arguments")
-    };
+/// Checks the server, trying again up to `args.retries` times, and returns the last failure
+fn run(args: &Args) -> Result<(), Failure> {
+    let (url, endpoint) = target(args).inspect_err(|failure| eprintln!("{failure}"))?;
+    let agent = agent(args).inspect_err(|failure| eprintln!("{failure}"))?;
 
-    let endpoint = args.last().unwrap();
+    let mut attempt = 0;
+    loop {
+        match check(&agent, &url, endpoint, args) {
+            Ok(()) => return Ok(()),
+            Err(failure) if attempt >= args.retries => {
+                eprintln!("{url}: {failure}");
+                return Err(failure);
+            }
+            Err(failure) => eprintln!("{url}: {failure}, retrying"),
+        }
+        attempt += 1;
+        thread::sleep(Duration::from_secs(args.retry_delay));
+    }
+}
 
-    let resp = minreq::get(endpoint).send();
+/// The URL to check and which check it is. URLs which already end in a check's path are used as
+/// given, so configs written for older versions keep working
+fn target(args: &Args) -> Result<(String, Endpoint), Failure> {
+    let url = args.url.trim_end_matches('/');
+    let given = [Endpoint::Readiness, Endpoint::Liveness]
+        .into_iter()
+        .find(|endpoint| url.ends_with(endpoint.path()));
 
-    if let Err(error) = resp {
-        println!("{}", error);
-        return ExitCode::from(1);
-    };
+    match (given, args.endpoint) {
+        (None, endpoint) => {
+            let endpoint = endpoint.unwrap_or(Endpoint::Liveness);
+            Ok((format!("{url}{}", endpoint.path()), endpoint))
+        }
+        (Some(given), Some(endpoint)) if given != endpoint => Err(Failure::Arguments(format!(
+            "{url} already ends in {}, so can't be used with --endpoint {}. Pass the server's \
+             base URL instead",
+            given.path(),
+            endpoint.name()
+        ))),
+        (Some(given), _) => Ok((String::from(url), given)),
+    }
+}
 
-    let resp = resp.unwrap();
-    let code = resp.status_code;
+fn agent(args: &Args) -> Result<Agent, Failure> {
+    let mut tls = TlsConfig::builder().unversioned_rustls_crypto_provider(Arc::new(
+        rustls::crypto::aws_lc_rs::default_provider(),
+    ));
+    if let Some(path) = &args.ca_cert {
+        let describe = |e: &dyn Display| Failure::Tls(format!("{}: {e}", path.display()));
+        let pem = std::fs::read(path).map_err(|e| describe(&e))?;
+        let certs = parse_pem(&pem)
+            .filter_map(|item| match item {
+                Ok(PemItem::Certificate(cert)) => Some(Ok(cert)),
+                Ok(_) => None,
+                Err(e) => Some(Err(e)),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| describe(&e))?;
+        if certs.is_empty() {
+            return Err(describe(&"no certificates found"));
+        }
+        tls = tls.root_certs(RootCerts::new_with_certs(&certs));
+    }
 
-    if code > 299 {
-        println!("received status code {code}");
-        return ExitCode::from(1);
-    };
+    Ok(Agent::config_builder()
+        .timeout_global(Some(Duration::from_secs(args.timeout)))
+        .http_status_as_error(false)
+        .tls_config(tls.build())
+        .build()
+        .into())
+}
 
-    assert_eq!(resp.as_str().unwrap(), "ok");
+fn check(agent: &Agent, url: &str, endpoint: Endpoint, args: &Args) -> Result<(), Failure> {
+    let mut response = agent.get(url).call()?;
+    let status = response.status().as_u16();
+    if status != args.expect_status {
+        return Err(Failure::Status(status));
+    }
+    // Only successful responses have a body worth checking
+    if status != 200 {
+        return Ok(());
+    }
+
+    let body = response.body_mut().read_to_string()?;
+    endpoint.check(&body)
+}
+
+impl Endpoint {
+    fn path(self) -> &'static str {
+        match self {
+            Endpoint::Liveness => "/health",
+            Endpoint::Readiness => "/health/ready",
+        }
+    }
 
-    ExitCode::from(0)
+    fn name(self) -> &'static str {
+        match self {
+            Endpoint::Liveness => "liveness",
+            Endpoint::Readiness => "readiness",
+        }
+    }
+
+    fn check(self, body: &str) -> Result<(), Failure> {
+        let healthy = match self {
+            Endpoint::Liveness => body.trim() == "ok",
+            Endpoint::Readiness => serde_json::from_str::<Value>(body)
+                .is_ok_and(|json| json.get("status").and_then(Value::as_str) == Some("ready")),
+        };
+        match healthy {
+            true => Ok(()),
+            false => Err(Failure::Body(String::from(body.trim()))),
+        }
+    }
+}
+
+impl Failure {
+    fn code(&self) -> u8 {
+        match self {
+            Failure::Status(_) => 1,
+            Failure::Arguments(_) => 2,
+            Failure::Connect(_) => 3,
+            Failure::Timeout => 4,
+            Failure::Tls(_) => 5,
+            Failure::Body(_) => 6,
+        }
+    }
+}
+
+impl From<ureq::Error> for Failure {
+    fn from(error: ureq::Error) -> Self {
+        match error {
+            ureq::Error::Timeout(_) => Failure::Timeout,
+            ureq::Error::Io(ref e) if e.kind() == io::ErrorKind::TimedOut => Failure::Timeout,
+            ureq::Error::Tls(_) | ureq::Error::Pem(_) | ureq::Error::Rustls(_) => {
+                Failure::Tls(error.to_string())
+            }
+            // Rustls reports failed handshakes, eg an untrusted certificate, as invalid data
+            ureq::Error::Io(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+                Failure::Tls(error.to_string())
+            }
+            error => Failure::Connect(error.to_string()),
+        }
+    }
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Failure::Arguments(error) => write!(f, "invalid arguments: {error}"),
+            Failure::Status(status) => write!(f, "unexpected status {status}"),
+            Failure::Connect(error) => write!(f, "unable to connect: {error}"),
+            Failure::Timeout => write!(f, "timed out"),
+            Failure::Tls(error) => write!(f, "HTTPS failed: {error}"),
+            Failure::Body(body) => write!(f, "unexpected body: {body}"),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use rustls::{
+        ServerConfig, ServerConnection, StreamOwned,
+        pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    };
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        sync::mpsc::{self, Receiver},
+    };
+
+    const TLS_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../resources/test/tls");
+
+    /// What the stand-in server answers a connection with, or `None` to never answer
+    type Reply = Option<(u16, &'static str)>;
+
+    /// Starts a stand-in server which answers each connection with the next of `replies`. Returns
+    /// its base URL and the request lines it receives
+    fn serve(replies: Vec<Reply>, tls: bool) -> (String, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (requests, received) = mpsc::channel();
+        let config = tls.then(server_config);
+
+        thread::spawn(move || {
+            for (reply, stream) in replies.into_iter().zip(listener.incoming()) {
+                let stream = stream.unwrap();
+                let line = match &config {
+                    Some(config) => {
+                        let connection = ServerConnection::new(config.clone()).unwrap();
+                        respond(StreamOwned::new(connection, stream), reply)
+                    }
+                    None => respond(stream, reply),
+                };
+                if let Some(line) = line {
+                    requests.send(line).ok();
+                }
+            }
+        });
+
+        let scheme = if tls { "https" } else { "http" };
+        (format!("{scheme}://127.0.0.1:{port}"), received)
+    }
+
+    /// Reads a request from `stream` and answers it with `reply`, returning the request line
+    fn respond(mut stream: impl Read + Write, reply: Reply) -> Option<String> {
+        let mut request = Vec::new();
+        let mut byte = [0; 1];
+        while !request.ends_with(b"\r\n\r\n") {
+            match stream.read(&mut byte) {
+                Ok(1) => request.push(byte[0]),
+                _ => return None,
+            }
+        }
+
+        match reply {
+            Some((status, body)) => {
+                write!(
+                    stream,
+                    "HTTP/1.1 {status} Stand-in\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                )
+                .ok();
+                stream.flush().ok();
+            }
+            None => thread::sleep(Duration::from_secs(3)),
+        }
+        let request = String::from_utf8_lossy(&request);
+        request.lines().next().map(String::from)
+    }
+
+    fn server_config() -> Arc<ServerConfig> {
+        let certs = CertificateDer::pem_file_iter(format!("{TLS_DIR}/server.pem"))
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let key = PrivateKeyDer::from_pem_file(format!("{TLS_DIR}/server.key")).unwrap();
+        let config = ServerConfig::builder_with_provider(Arc::new(
+            rustls::crypto::aws_lc_rs::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .unwrap();
+        Arc::new(config)
+    }
+
+    fn check(args: &[&str]) -> Result<(), Failure> {
+        let args = [
+            &["healthcheck", "--timeout", "1", "--retry-delay", "0"],
+            args,
+        ]
+        .concat();
+        run(&Args::parse_from(args))
+    }
+
+    #[test]
+    fn passes_when_live() {
+        let (url, requests) = serve(vec![Some((200, "ok"))], false);
+        check(&[&url]).unwrap();
+        assert_eq!(requests.recv().unwrap(), "GET /health HTTP/1.1");
+    }
+
+    #[test]
+    fn fails_on_wrong_body() {
+        let (url, _) = serve(vec![Some((200, "not ok"))], false);
+        assert_eq!(check(&[&url]).unwrap_err().code(), 6);
+    }
+
+    #[test]
+    fn fails_on_wrong_status() {
+        let (url, _) = serve(vec![Some((503, ""))], false);
+        assert_eq!(check(&[&url]).unwrap_err().code(), 1);
+    }
+
+    #[test]
+    fn accepts_expected_status() {
+        let (url, _) = serve(vec![Some((204, ""))], false);
+        check(&[&url, "--expect-status", "204"]).unwrap();
+    }
+
     #[test]
-    fn can_reach_google() {
-        let resp = minreq::get("http://google.com").send();
-        assert!(resp.is_ok())
+    fn checks_readiness() {
+        let ready = r#"{"status":"ready","backend":"filesystem","components":{}}"#;
+        let (url, requests) = serve(vec![Some((200, ready))], false);
+        check(&[&url, "--endpoint", "readiness"]).unwrap();
+        assert_eq!(requests.recv().unwrap(), "GET /health/ready HTTP/1.1");
+
+        let unavailable = r#"{"status":"unavailable","backend":"filesystem","components":{}}"#;
+        let (url, _) = serve(vec![Some((503, unavailable))], false);
+        let failure = check(&[&url, "--endpoint", "readiness"]).unwrap_err();
+        assert_eq!(failure.code(), 1);
+    }
+
+    #[test]
+    fn accepts_the_full_url() {
+        let (url, requests) = serve(vec![Some((200, "ok"))], false);
+        check(&[&format!("{url}/health")]).unwrap();
+        assert_eq!(requests.recv().unwrap(), "GET /health HTTP/1.1");
+
+        let ready = r#"{"status":"ready","backend":"filesystem","components":{}}"#;
+        let (url, requests) = serve(vec![Some((200, ready))], false);
+        check(&[&format!("{url}/health/ready/")]).unwrap();
+        assert_eq!(requests.recv().unwrap(), "GET /health/ready HTTP/1.1");
+
+        let failure = check(&[&format!("{url}/health"), "--endpoint", "readiness"]).unwrap_err();
+        assert_eq!(failure.code(), 2);
+    }
+
+    #[test]
+    fn times_out() {
+        let (url, _) = serve(vec![None], false);
+        assert_eq!(check(&[&url]).unwrap_err().code(), 4);
+    }
+
+    #[test]
+    fn fails_to_connect() {
+        // Bind then drop a listener, so nothing is listening on its port
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let url = format!("http://127.0.0.1:{port}");
+        assert_eq!(check(&[&url]).unwrap_err().code(), 3);
+    }
+
+    #[test]
+    fn retries() {
+        let (url, requests) = serve(vec![Some((503, "")), Some((200, "ok"))], false);
+        check(&[&url, "--retries", "1"]).unwrap();
+        assert_eq!(requests.iter().count(), 2);
+
+        let (url, _) = serve(vec![Some((503, "")), Some((503, ""))], false);
+        assert_eq!(check(&[&url, "--retries", "1"]).unwrap_err().code(), 1);
+    }
+
+    #[test]
+    fn trusts_custom_ca() {
+        let (url, _) = serve(vec![Some((200, "ok"))], true);
+        check(&[&url, "--ca-cert", &format!("{TLS_DIR}/ca.pem")]).unwrap();
+    }
+
+    #[test]
+    fn fails_on_untrusted_certificate() {
+        let (url, _) = serve(vec![Some((200, "ok"))], true);
+        assert_eq!(check(&[&url]).unwrap_err().code(), 5);
+
+        let missing = format!("{TLS_DIR}/missing.pem");
+        assert_eq!(check(&[&url, "--ca-cert", &missing]).unwrap_err().code(), 5);
     }
 
     #[test]
-    fn cant_reach_random() {
-        let resp = minreq::get("http://grioegnpreewfoaipn.local/fdisof").send();
-        assert!(resp.is_err())
+    fn rejects_extra_arguments() {
+        assert!(Args::try_parse_from(["healthcheck", "http://a", "http://b"]).is_err());
+        assert!(Args::try_parse_from(["healthcheck", "--endpoint", "startup"]).is_err());
     }
 }
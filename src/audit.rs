@@ -0,0 +1,278 @@
+//! An audit log of every upload, delete and authentication failure, kept apart from the other logs
+//! as one JSON object per line
+
+use crate::{
+    Context,
+    auth::Authentication,
+    error::ApiKeyError,
+    logging::{RequestId, client_ip, now},
+    settings::Settings,
+};
+use rocket::{
+    Request,
+    fairing::AdHoc,
+    http::Status,
+    outcome::Outcome,
+    request::FromRequest,
+    serde::{Serialize, json::serde_json},
+};
+use std::{
+    convert::Infallible,
+    fs::OpenOptions,
+    io::{self, Write},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+/// Where audit records are written, if anywhere
+#[derive(Default)]
+pub(crate) struct Audit {
+    out: Option<Mutex<Box<dyn Write + Send>>>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub(crate) enum Event {
+    Upload,
+    Delete,
+    AuthFailure,
+}
+
+/// Who did what to which file
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct Entry {
+    event: Event,
+    request_id: Option<String>,
+    /// Name of the API key used, if any
+    key: Option<String>,
+    client_ip: Option<IpAddr>,
+    /// UUID of the file
+    file: Option<String>,
+    size: Option<u64>,
+}
+
+/// One line of the audit log
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Record<'a> {
+    timestamp: String,
+    #[serde(flatten)]
+    entry: &'a Entry,
+    outcome: &'a str,
+    status: Option<u16>,
+}
+
+/// The audit entry for the current request, which its handler fills in and is written once it has
+/// been responded to. Requests whose handler never begins an entry aren't audited
+#[derive(Clone)]
+pub(crate) struct Trail {
+    request_id: String,
+    client_ip: Option<IpAddr>,
+    entry: Arc<Mutex<Option<Entry>>>,
+}
+
+impl Audit {
+    /// Opens the audit log from the settings, appending to it if it already exists
+    pub(crate) fn from_settings(settings: &Settings) -> io::Result<Self> {
+        let out: Box<dyn Write + Send> = match &settings.audit_log {
+            None => return Ok(Audit::default()),
+            Some(path) if path.as_os_str() == "-" => Box::new(io::stdout()),
+            Some(path) => Box::new(OpenOptions::new().create(true).append(true).open(path)?),
+        };
+        Ok(Audit {
+            out: Some(Mutex::new(out)),
+        })
+    }
+
+    pub(crate) fn write(&self, entry: &Entry, outcome: &str, status: Option<u16>) {
+        let Some(out) = &self.out else {
+            return;
+        };
+        let record = Record {
+            timestamp: now(),
+            entry,
+            outcome,
+            status,
+        };
+        let Ok(mut line) = serde_json::to_string(&record) else {
+            return;
+        };
+        line.push('\n');
+
+        let written = out
+            .lock()
+            .map_err(|e| io::Error::other(e.to_string()))
+            .and_then(|mut out| {
+                out.write_all(line.as_bytes())?;
+                out.flush()
+            });
+        if let Err(e) = written {
+            error_!("Unable to write to the audit log: {e}");
+        }
+    }
+
+    /// Writes the entry `trail` has collected, if its handler began one, with the outcome of
+    /// `status`
+    pub(crate) fn finish(&self, trail: &Trail, status: Status) {
+        if let Some(entry) = trail.entry() {
+            self.write(&entry, outcome(status), Some(status.code));
+        }
+    }
+}
+
+impl Entry {
+    /// A file the reaper deleted because it expired
+    pub(crate) fn expired(hash: &str, size: u64) -> Self {
+        Entry {
+            event: Event::Delete,
+            request_id: None,
+            key: None,
+            client_ip: None,
+            file: Some(String::from(hash)),
+            size: Some(size),
+        }
+    }
+}
+
+impl Trail {
+    fn of(request: &Request<'_>) -> Self {
+        request
+            .local_cache(|| Trail {
+                request_id: RequestId::of(request).0,
+                client_ip: client_ip(request),
+                entry: Arc::default(),
+            })
+            .clone()
+    }
+
+    /// Starts auditing the request as `event`, done with `key`
+    pub(crate) fn begin(&self, event: Event, key: Option<&str>) {
+        if let Ok(mut entry) = self.entry.lock() {
+            *entry = Some(Entry {
+                event,
+                request_id: Some(self.request_id.clone()),
+                key: key.map(String::from),
+                client_ip: self.client_ip,
+                file: None,
+                size: None,
+            });
+        }
+    }
+
+    /// Records which file the request is about, once it is known
+    pub(crate) fn file(&self, hash: &str, size: Option<u64>) {
+        if let Ok(mut entry) = self.entry.lock()
+            && let Some(entry) = entry.as_mut()
+        {
+            entry.file = Some(String::from(hash));
+            entry.size = size;
+        }
+    }
+
+    fn entry(&self) -> Option<Entry> {
+        self.entry.lock().ok()?.clone()
+    }
+
+    /// An authentication failure for this request
+    fn auth_failure(&self, key: Option<&str>) -> Entry {
+        Entry {
+            event: Event::AuthFailure,
+            request_id: Some(self.request_id.clone()),
+            key: key.map(String::from),
+            client_ip: self.client_ip,
+            file: None,
+            size: None,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Trail {
+    type Error = Infallible;
+
+    async fn from_request(
+        request: &'r Request<'_>,
+    ) -> Outcome<Trail, (Status, Infallible), Status> {
+        Outcome::Success(Trail::of(request))
+    }
+}
+
+fn outcome(status: Status) -> &'static str {
+    match status.code {
+        202 => "accepted",
+        200..=299 => "success",
+        _ => "failure",
+    }
+}
+
+/// Writes each request's audit entry, and any authentication failure, once it has been responded to
+pub(crate) fn fairing() -> AdHoc {
+    AdHoc::on_response("Audit", |request, response| {
+        Box::pin(async move {
+            let Some(context) = request.rocket().state::<Arc<Context>>() else {
+                return;
+            };
+            let audit = &context.audit;
+            if audit.out.is_none() {
+                return;
+            }
+
+            let status = response.status();
+            let trail = Trail::of(request);
+            audit.finish(&trail, status);
+
+            // Only failures which caused the response count, not eg a missing key when a
+            // deletion token was used instead
+            match request.local_cache(|| Authentication::Skipped) {
+                Authentication::Failed(error) if error.status() == status => {
+                    let reason = match error {
+                        ApiKeyError::Missing => "missing_key",
+                        ApiKeyError::Invalid => "invalid_key",
+                        ApiKeyError::Unknown => "unknown_key",
+                        ApiKeyError::Expired => "expired_key",
+                    };
+                    audit.write(&trail.auth_failure(None), reason, Some(status.code));
+                }
+                Authentication::Succeeded(principal)
+                    if principal.was_refused() && status == Status::Forbidden =>
+                {
+                    let entry = trail.auth_failure(Some(principal.name()));
+                    audit.write(&entry, "missing_scope", Some(status.code));
+                }
+                _ => {}
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_json_lines() {
+        let path = std::env::temp_dir().join(format!("rumia-audit-{}.log", uuid::Uuid::new_v4()));
+        let settings = Settings {
+            audit_log: Some(path.clone()),
+            ..Settings::default()
+        };
+        let audit = Audit::from_settings(&settings).unwrap();
+        audit.write(&Entry::expired("5d41402a", 7), "expired", None);
+        audit.write(&Entry::expired("7d793037", 9), "expired", None);
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = log
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["event"], "delete");
+        assert_eq!(lines[0]["file"], "5d41402a");
+        assert_eq!(lines[0]["size"], 7);
+        assert_eq!(lines[0]["outcome"], "expired");
+        assert!(lines[0]["key"].is_null());
+        assert_eq!(lines[1]["file"], "7d793037");
+        std::fs::remove_file(path).ok();
+    }
+}
@@ -0,0 +1,383 @@
+use crate::{Context, error::ApiKeyError};
+use hmac::{Hmac, KeyInit, Mac};
+use rocket::{
+    Request,
+    http::Status,
+    outcome::Outcome,
+    request::FromRequest,
+    serde::{Deserialize, Serialize},
+};
+use sha2::Sha256;
+use std::{
+    convert::Infallible,
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+/// An action an API key may be allowed to perform
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "kebab-case")]
+pub enum Scope {
+    /// Upload files with `POST /api/upload/file` or tus
+    Upload,
+    /// Upload files from a remote URL with `POST /api/upload/<url>`
+    UploadUrl,
+    /// Delete any file
+    Delete,
+    /// View and list file metadata with `GET /api/files`
+    Metadata,
+    /// Create signed download URLs with `POST /api/sign/<hash>/<filename>`
+    Sign,
+    /// Everything
+    Admin,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct KeyEntry {
+    pub name: String,
+    pub key: String,
+    pub scopes: Vec<Scope>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub expires: Option<OffsetDateTime>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct KeyStore {
+    #[serde(default)]
+    keys: Vec<KeyEntry>,
+}
+
+/// Who made a request, and what they are allowed to do
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub(crate) name: String,
+    scopes: Vec<Scope>,
+    /// Set once the principal has been refused a scope, so the refusal can be audited
+    refused: Arc<AtomicBool>,
+}
+
+/// How the [`Principal`] guard authenticated the current request, kept in its local cache
+pub(crate) enum Authentication {
+    Skipped,
+    Failed(ApiKeyError),
+    Succeeded(Principal),
+}
+
+/// Identifies who made a request. Rumia checks the `x-api-key` header against a [`KeyStore`]
+/// unless it is given another authenticator with [`Rumia::authenticator`](crate::Rumia::authenticator)
+#[rocket::async_trait]
+pub trait Authenticator: Send + Sync {
+    async fn authenticate(&self, request: &Request<'_>) -> Result<Principal, ApiKeyError>;
+}
+
+/// The `x-deletion-token` provided with a request, if any
+pub(crate) struct DeletionToken<'r>(&'r str);
+
+/// Signs and verifies tokens with the server secret
+pub(crate) struct Signer {
+    secret: Vec<u8>,
+    /// Whether the secret was configured, rather than generated for this run only
+    configured: bool,
+}
+
+impl KeyStore {
+    /// Builds the key store from the keys file, if any, plus the admin key from the settings
+    pub(crate) fn new(admin_key: Option<&str>, keys_file: Option<&Path>) -> Result<Self, String> {
+        let mut store = match keys_file {
+            Some(path) => {
+                let keys = std::fs::read_to_string(path)
+                    .map_err(|e| format!("unable to read {}: {e}", path.display()))?;
+                toml::from_str(&keys)
+                    .map_err(|e| format!("unable to parse {}: {e}", path.display()))?
+            }
+            None => KeyStore::default(),
+        };
+
+        if let Some(key) = admin_key {
+            store.keys.push(KeyEntry {
+                name: String::from("admin"),
+                key: String::from(key),
+                scopes: vec![Scope::Admin],
+                expires: None,
+            });
+        }
+
+        Ok(store)
+    }
+
+    pub(crate) fn authenticate_key(&self, key: &str) -> Result<Principal, ApiKeyError> {
+        let entry = self
+            .keys
+            .iter()
+            .find(|entry| constant_time_eq(entry.key.as_bytes(), key.as_bytes()))
+            .ok_or(ApiKeyError::Unknown)?;
+
+        if entry
+            .expires
+            .is_some_and(|expires| expires <= OffsetDateTime::now_utc())
+        {
+            return Err(ApiKeyError::Expired);
+        }
+
+        Ok(Principal::new(entry.name.clone(), entry.scopes.clone()))
+    }
+}
+
+#[rocket::async_trait]
+impl Authenticator for KeyStore {
+    async fn authenticate(&self, request: &Request<'_>) -> Result<Principal, ApiKeyError> {
+        match request.headers().get_one("x-api-key") {
+            None => Err(ApiKeyError::Missing),
+            Some("") => Err(ApiKeyError::Invalid),
+            Some(key) => self.authenticate_key(key),
+        }
+    }
+}
+
+impl Principal {
+    /// A principal called `name`, which may use `scopes`
+    pub fn new(name: impl Into<String>, scopes: Vec<Scope>) -> Self {
+        Principal {
+            name: name.into(),
+            scopes,
+            refused: Arc::default(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn has(&self, scope: Scope) -> bool {
+        self.scopes.contains(&Scope::Admin) || self.scopes.contains(&scope)
+    }
+
+    pub(crate) fn require(&self, scope: Scope) -> Result<(), Status> {
+        if self.has(scope) {
+            Ok(())
+        } else {
+            self.refused.store(true, Ordering::Relaxed);
+            Err(Status::Forbidden)
+        }
+    }
+
+    /// Whether the principal has been refused any scope it was required to have
+    pub(crate) fn was_refused(&self) -> bool {
+        self.refused.load(Ordering::Relaxed)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Principal {
+    type Error = ApiKeyError;
+
+    async fn from_request(
+        request: &'r Request<'_>,
+    ) -> Outcome<Principal, (Status, ApiKeyError), Status> {
+        let Some(context) = request.rocket().state::<Arc<Context>>() else {
+            return Outcome::Error((Status::InternalServerError, ApiKeyError::Unknown));
+        };
+
+        match context.authenticator.authenticate(request).await {
+            Ok(principal) => {
+                info_!("Authenticated as \"{}\"", principal.name);
+                request.local_cache(|| Authentication::Succeeded(principal.clone()));
+                Outcome::Success(principal)
+            }
+            Err(error) => {
+                request.local_cache(|| Authentication::Failed(error.clone()));
+                Outcome::Error((error.status(), error))
+            }
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for DeletionToken<'r> {
+    type Error = Infallible;
+
+    async fn from_request(
+        request: &'r Request<'_>,
+    ) -> Outcome<DeletionToken<'r>, (Status, Infallible), Status> {
+        match request.headers().get_one("x-deletion-token") {
+            Some(token) => Outcome::Success(DeletionToken(token)),
+            None => Outcome::Forward(Status::Unauthorized),
+        }
+    }
+}
+
+impl DeletionToken<'_> {
+    /// Checks the token was issued by `signer` for the file `hash`
+    pub(crate) fn allows(&self, signer: &Signer, hash: &str) -> bool {
+        constant_time_eq(signer.deletion_token(hash).as_bytes(), self.0.as_bytes())
+    }
+}
+
+impl Signer {
+    /// Creates a signer from `secret`, or a random secret if none is provided
+    pub(crate) fn new(secret: Option<&str>) -> Self {
+        let configured = secret.is_some();
+        let secret = match secret {
+            Some(secret) => secret.as_bytes().to_vec(),
+            None => [Uuid::new_v4().into_bytes(), Uuid::new_v4().into_bytes()].concat(),
+        };
+
+        Signer { secret, configured }
+    }
+
+    /// Whether the secret will be the same after a restart, so what it signs stays valid
+    pub(crate) fn is_configured(&self) -> bool {
+        self.configured
+    }
+
+    fn sign(&self, purpose: &str, message: &str) -> String {
+        #[allow(clippy::expect_used)]
+        let mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts keys of any length");
+
+        hex::encode(
+            mac.chain_update(purpose)
+                .chain_update([0])
+                .chain_update(message)
+                .finalize()
+                .into_bytes(),
+        )
+    }
+
+    /// Returns the token allowing the file `hash` to be deleted without an API key
+    pub(crate) fn deletion_token(&self, hash: &str) -> String {
+        self.sign("delete", hash)
+    }
+
+    /// Returns the signature allowing `file` to be downloaded until `expires`, a unix timestamp
+    pub(crate) fn download_signature(&self, file: &str, expires: i64) -> String {
+        self.sign("download", &format!("{file}\n{expires}"))
+    }
+
+    /// Checks a signed download of `file` is genuine and has not expired
+    pub(crate) fn verify_download(&self, file: &str, expires: i64, signature: &str) -> bool {
+        expires > OffsetDateTime::now_utc().unix_timestamp()
+            && constant_time_eq(
+                self.download_signature(file, expires).as_bytes(),
+                signature.as_bytes(),
+            )
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::Duration;
+
+    fn store() -> KeyStore {
+        let mut store: KeyStore = toml::from_str(
+            r#"
+            [[keys]]
+            name = "ci"
+            key = "ci-key"
+            scopes = ["upload", "upload-url"]
+
+            [[keys]]
+            name = "old"
+            key = "old-key"
+            scopes = ["delete"]
+            expires = "2020-01-01T00:00:00Z"
+            "#,
+        )
+        .unwrap();
+
+        store.keys.push(KeyEntry {
+            name: String::from("soon"),
+            key: String::from("soon-key"),
+            scopes: vec![Scope::Delete],
+            expires: Some(OffsetDateTime::now_utc() + Duration::hours(1)),
+        });
+        store
+    }
+
+    #[test]
+    fn authenticates_scoped_keys() {
+        let principal = store().authenticate_key("ci-key").unwrap();
+
+        assert_eq!(principal.name, "ci");
+        assert!(principal.has(Scope::Upload));
+        assert!(principal.has(Scope::UploadUrl));
+        assert_eq!(principal.require(Scope::Delete), Err(Status::Forbidden));
+    }
+
+    #[test]
+    fn admin_has_every_scope() {
+        let store = KeyStore::new(Some("admin-key"), None).unwrap();
+        let principal = store.authenticate_key("admin-key").unwrap();
+
+        assert_eq!(principal.name, "admin");
+        assert!(principal.has(Scope::Upload));
+        assert!(principal.has(Scope::Delete));
+    }
+
+    #[test]
+    fn deletion_tokens_are_tied_to_a_file() {
+        let signer = Signer::new(Some("secret"));
+        let token = signer.deletion_token("39514323-7c35-4e01-95b0-4a6b55c550c1");
+
+        assert_eq!(
+            token,
+            signer.deletion_token("39514323-7c35-4e01-95b0-4a6b55c550c1")
+        );
+        assert_ne!(
+            token,
+            signer.deletion_token("7d52a0f4-6a6b-4b43-9a8e-8d0f3b6e4d2e")
+        );
+        assert_ne!(
+            token,
+            Signer::new(Some("other")).deletion_token("39514323-7c35-4e01-95b0-4a6b55c550c1")
+        );
+    }
+
+    #[test]
+    fn verifies_download_signatures() {
+        let signer = Signer::new(Some("secret"));
+        let file = "39514323-7c35-4e01-95b0-4a6b55c550c1.png";
+        let expires = (OffsetDateTime::now_utc() + Duration::hours(1)).unix_timestamp();
+        let signature = signer.download_signature(file, expires);
+
+        assert!(signer.verify_download(file, expires, &signature));
+        assert!(!signer.verify_download(file, expires + 1, &signature));
+        assert!(!signer.verify_download(
+            "7d52a0f4-6a6b-4b43-9a8e-8d0f3b6e4d2e.png",
+            expires,
+            &signature
+        ));
+        assert!(!signer.verify_download(file, expires, &signer.deletion_token(file)));
+
+        let expired = (OffsetDateTime::now_utc() - Duration::minutes(1)).unix_timestamp();
+        let signature = signer.download_signature(file, expired);
+        assert!(!signer.verify_download(file, expired, &signature));
+    }
+
+    #[test]
+    fn rejects_unknown_and_expired_keys() {
+        let store = store();
+
+        assert!(matches!(
+            store.authenticate_key("nope"),
+            Err(ApiKeyError::Unknown)
+        ));
+        assert!(matches!(
+            store.authenticate_key("old-key"),
+            Err(ApiKeyError::Expired)
+        ));
+        assert!(store.authenticate_key("soon-key").is_ok());
+    }
+}
@@ -0,0 +1,322 @@
+use crate::storage::{FileInfo, StoredFile};
+use rocket::{
+    Request,
+    http::{Header, Status},
+    outcome::Outcome,
+    request::FromRequest,
+    response::{self, Responder, Response},
+};
+use std::{
+    convert::Infallible,
+    ops::Range,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Conditional and range headers sent with a download request
+#[derive(Debug, Default)]
+pub(crate) struct Preconditions<'r> {
+    range: Option<&'r str>,
+    if_range: Option<&'r str>,
+    if_none_match: Option<&'r str>,
+    if_modified_since: Option<&'r str>,
+}
+
+/// How a download request should be answered, once its preconditions have been evaluated
+#[derive(Debug, PartialEq)]
+pub(crate) enum Plan {
+    Full,
+    Partial(Range<u64>),
+    NotModified,
+    Unsatisfiable,
+}
+
+pub(crate) enum Download {
+    Full(FileInfo, StoredFile),
+    Partial(FileInfo, StoredFile, Range<u64>),
+    NotModified(FileInfo),
+    Unsatisfiable(FileInfo),
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Preconditions<'r> {
+    type Error = Infallible;
+
+    async fn from_request(
+        request: &'r Request<'_>,
+    ) -> Outcome<Preconditions<'r>, (Status, Infallible), Status> {
+        let headers = request.headers();
+
+        Outcome::Success(Preconditions {
+            range: headers.get_one("Range"),
+            if_range: headers.get_one("If-Range"),
+            if_none_match: headers.get_one("If-None-Match"),
+            if_modified_since: headers.get_one("If-Modified-Since"),
+        })
+    }
+}
+
+impl Preconditions<'_> {
+    pub(crate) fn evaluate(&self, info: &FileInfo) -> Plan {
+        if self.not_modified(info) {
+            return Plan::NotModified;
+        }
+
+        match self.range {
+            Some(range) if self.if_range_matches(info) => byte_range(range, info.len),
+            _ => Plan::Full,
+        }
+    }
+
+    fn not_modified(&self, info: &FileInfo) -> bool {
+        // If-Modified-Since is ignored when If-None-Match is present (RFC 9110 13.1.3)
+        if let Some(if_none_match) = self.if_none_match {
+            return if_none_match.trim() == "*"
+                || info.etag.as_deref().is_some_and(|etag| {
+                    if_none_match
+                        .split(',')
+                        .any(|candidate| weak_eq(candidate.trim(), etag))
+                });
+        }
+
+        match (self.if_modified_since, info.last_modified) {
+            (Some(since), Some(modified)) => httpdate::parse_http_date(since)
+                .is_ok_and(|since| unix_secs(modified) <= unix_secs(since)),
+            _ => false,
+        }
+    }
+
+    fn if_range_matches(&self, info: &FileInfo) -> bool {
+        let Some(if_range) = self.if_range.map(str::trim) else {
+            return true;
+        };
+
+        if if_range.starts_with('"') || if_range.starts_with("W/") {
+            // If-Range requires a strong comparison
+            !if_range.starts_with("W/")
+                && info
+                    .etag
+                    .as_deref()
+                    .is_some_and(|etag| !etag.starts_with("W/") && etag == if_range)
+        } else {
+            match (httpdate::parse_http_date(if_range), info.last_modified) {
+                (Ok(date), Some(modified)) => unix_secs(modified) == unix_secs(date),
+                _ => false,
+            }
+        }
+    }
+}
+
+/// Parses a `Range` header against a file of `len` bytes.
+///
+/// Malformed headers, other units and multiple ranges are ignored and the full file is served
+fn byte_range(header: &str, len: u64) -> Plan {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return Plan::Full;
+    };
+
+    if spec.contains(',') {
+        return Plan::Full;
+    }
+
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return Plan::Full;
+    };
+
+    let range = if start.is_empty() {
+        match end.parse::<u64>() {
+            Ok(0) => return Plan::Unsatisfiable,
+            Ok(suffix) => len.saturating_sub(suffix)..len,
+            Err(_) => return Plan::Full,
+        }
+    } else {
+        let Ok(start) = start.parse::<u64>() else {
+            return Plan::Full;
+        };
+
+        let end = if end.is_empty() {
+            len
+        } else {
+            match end.parse::<u64>() {
+                Ok(end) if end >= start => end.saturating_add(1).min(len),
+                _ => return Plan::Full,
+            }
+        };
+
+        start..end
+    };
+
+    if range.start >= len {
+        Plan::Unsatisfiable
+    } else {
+        Plan::Partial(range)
+    }
+}
+
+fn weak_eq(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+fn validators(response: &mut Response<'_>, info: &FileInfo) {
+    response.set_header(Header::new("Accept-Ranges", "bytes"));
+
+    if let Some(etag) = &info.etag {
+        response.set_header(Header::new("ETag", etag.clone()));
+    }
+
+    if let Some(modified) = info.last_modified {
+        response.set_header(Header::new(
+            "Last-Modified",
+            httpdate::fmt_http_date(modified),
+        ));
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Download {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let (mut response, info) = match self {
+            Download::Full(info, file) => (file.respond_to(request)?, info),
+            Download::Partial(info, file, range) => {
+                let mut response = file.respond_to(request)?;
+                response.set_status(Status::PartialContent);
+                response.set_header(Header::new(
+                    "Content-Range",
+                    format!(
+                        "bytes {}-{}/{}",
+                        range.start,
+                        range.end.saturating_sub(1),
+                        info.len
+                    ),
+                ));
+                (response, info)
+            }
+            Download::NotModified(info) => (
+                Response::build().status(Status::NotModified).finalize(),
+                info,
+            ),
+            Download::Unsatisfiable(info) => {
+                let response = Response::build()
+                    .status(Status::RangeNotSatisfiable)
+                    .header(Header::new(
+                        "Content-Range",
+                        format!("bytes */{}", info.len),
+                    ))
+                    .finalize();
+                return Ok(response);
+            }
+        };
+
+        validators(&mut response, &info);
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const LEN: u64 = 1000;
+    const ETAG: &str = "\"3e8-abc\"";
+    const MODIFIED: &str = "Sun, 06 Nov 1994 08:49:37 GMT";
+
+    fn info() -> FileInfo {
+        FileInfo {
+            len: LEN,
+            last_modified: httpdate::parse_http_date(MODIFIED).ok(),
+            etag: Some(String::from(ETAG)),
+        }
+    }
+
+    #[test]
+    fn parses_byte_ranges() {
+        assert_eq!(byte_range("bytes=0-99", LEN), Plan::Partial(0..100));
+        assert_eq!(byte_range("bytes=900-", LEN), Plan::Partial(900..1000));
+        assert_eq!(byte_range("bytes=-100", LEN), Plan::Partial(900..1000));
+        assert_eq!(byte_range("bytes=-5000", LEN), Plan::Partial(0..1000));
+        assert_eq!(byte_range("bytes=500-5000", LEN), Plan::Partial(500..1000));
+    }
+
+    #[test]
+    fn ignores_unsupported_ranges() {
+        assert_eq!(byte_range("bytes=0-1,5-6", LEN), Plan::Full);
+        assert_eq!(byte_range("items=0-1", LEN), Plan::Full);
+        assert_eq!(byte_range("bytes=10-5", LEN), Plan::Full);
+        assert_eq!(byte_range("bytes=abc", LEN), Plan::Full);
+    }
+
+    #[test]
+    fn rejects_unsatisfiable_ranges() {
+        assert_eq!(byte_range("bytes=1000-", LEN), Plan::Unsatisfiable);
+        assert_eq!(byte_range("bytes=-0", LEN), Plan::Unsatisfiable);
+    }
+
+    #[test]
+    fn matches_if_none_match() {
+        let matching = Preconditions {
+            if_none_match: Some("\"other\", W/\"3e8-abc\""),
+            ..Preconditions::default()
+        };
+        assert_eq!(matching.evaluate(&info()), Plan::NotModified);
+
+        let other = Preconditions {
+            if_none_match: Some("\"other\""),
+            if_modified_since: Some(MODIFIED),
+            ..Preconditions::default()
+        };
+        assert_eq!(other.evaluate(&info()), Plan::Full);
+    }
+
+    #[test]
+    fn matches_if_modified_since() {
+        let unchanged = Preconditions {
+            if_modified_since: Some(MODIFIED),
+            ..Preconditions::default()
+        };
+        assert_eq!(unchanged.evaluate(&info()), Plan::NotModified);
+
+        let earlier = httpdate::parse_http_date(MODIFIED).unwrap() - Duration::from_secs(60);
+        let header = httpdate::fmt_http_date(earlier);
+        let changed = Preconditions {
+            if_modified_since: Some(&header),
+            ..Preconditions::default()
+        };
+        assert_eq!(changed.evaluate(&info()), Plan::Full);
+    }
+
+    #[test]
+    fn honours_if_range() {
+        let fresh = Preconditions {
+            range: Some("bytes=0-9"),
+            if_range: Some(ETAG),
+            ..Preconditions::default()
+        };
+        assert_eq!(fresh.evaluate(&info()), Plan::Partial(0..10));
+
+        let by_date = Preconditions {
+            range: Some("bytes=0-9"),
+            if_range: Some(MODIFIED),
+            ..Preconditions::default()
+        };
+        assert_eq!(by_date.evaluate(&info()), Plan::Partial(0..10));
+
+        let stale = Preconditions {
+            range: Some("bytes=0-9"),
+            if_range: Some("\"stale\""),
+            ..Preconditions::default()
+        };
+        assert_eq!(stale.evaluate(&info()), Plan::Full);
+
+        let weak = Preconditions {
+            range: Some("bytes=0-9"),
+            if_range: Some("W/\"3e8-abc\""),
+            ..Preconditions::default()
+        };
+        assert_eq!(weak.evaluate(&info()), Plan::Full);
+    }
+}
@@ -0,0 +1,490 @@
+//! Egress policy for fetching remote URLs, which stops uploads from reaching internal services
+
+use crate::{
+    error::EgressError,
+    telemetry::{Telemetry, record},
+};
+use opentelemetry::{
+    KeyValue,
+    trace::{SpanKind, TraceContextExt},
+};
+use reqwest::{Client, Response, header::LOCATION, redirect::Policy};
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    str::FromStr,
+    sync::LazyLock,
+};
+use url::{Host, Url};
+
+/// Ranges which are not publicly routable, blocked unless explicitly allowed
+const NON_PUBLIC: [&str; 24] = [
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.0.2.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "198.51.100.0/24",
+    "203.0.113.0/24",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "64:ff9b::/96",
+    "100::/64",
+    // Teredo and 6to4, which embed an IPv4 address that could be private
+    "2001::/32",
+    "2002::/16",
+    "2001:db8::/32",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+static NON_PUBLIC_CIDRS: LazyLock<Vec<Cidr>> = LazyLock::new(|| {
+    NON_PUBLIC
+        .iter()
+        .filter_map(|cidr| cidr.parse().ok())
+        .collect()
+});
+
+/// Decides which remote URLs may be fetched
+#[derive(Debug)]
+pub(crate) struct EgressPolicy {
+    /// Exceptions to the block on non-public addresses
+    allow: Rules,
+    /// Always blocked, even if also allowed
+    deny: Rules,
+    schemes: Vec<String>,
+    max_redirects: usize,
+}
+
+#[derive(Debug, Default)]
+struct Rules {
+    cidrs: Vec<Cidr>,
+    /// Lowercase hostnames. Those starting with `.` also match any subdomain
+    hosts: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|_| format!("invalid IP address {addr}"))?;
+        let addr = canonical(addr);
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("invalid prefix length in {s}"))?,
+            None => max,
+        };
+
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl Cidr {
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                (u32::from(net) ^ u32::from(ip))
+                    .checked_shr(32 - u32::from(self.prefix))
+                    .unwrap_or(0)
+                    == 0
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                (u128::from(net) ^ u128::from(ip))
+                    .checked_shr(128 - u32::from(self.prefix))
+                    .unwrap_or(0)
+                    == 0
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Rules {
+    /// Parses a list of IP addresses, CIDRs and hostnames
+    fn parse(entries: &[String]) -> Result<Self, String> {
+        let mut rules = Rules::default();
+
+        for entry in entries.iter().map(|entry| entry.trim()) {
+            if entry.is_empty() {
+                continue;
+            }
+
+            if entry.contains('/') || entry.parse::<IpAddr>().is_ok() {
+                rules.cidrs.push(entry.parse()?);
+            } else {
+                rules
+                    .hosts
+                    .push(normalise_host(entry.trim_start_matches('*')));
+            }
+        }
+
+        Ok(rules)
+    }
+
+    fn matches_ip(&self, ip: IpAddr) -> bool {
+        self.cidrs.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    fn matches_host(&self, host: &str) -> bool {
+        self.hosts.iter().any(|rule| match rule.strip_prefix('.') {
+            Some(domain) => host == domain || host.ends_with(rule.as_str()),
+            None => host == rule,
+        })
+    }
+}
+
+impl EgressPolicy {
+    pub(crate) fn new(
+        allow: &[String],
+        deny: &[String],
+        schemes: &[String],
+        max_redirects: usize,
+    ) -> Result<Self, String> {
+        Ok(EgressPolicy {
+            allow: Rules::parse(allow)?,
+            deny: Rules::parse(deny)?,
+            schemes: schemes
+                .iter()
+                .map(|scheme| scheme.trim().to_lowercase())
+                .collect(),
+            max_redirects,
+        })
+    }
+
+    /// Requests `url`, following redirects, checking every hop against the policy. Each hop is
+    /// traced with `telemetry`
+    pub(crate) async fn get(
+        &self,
+        url: Url,
+        telemetry: &Telemetry,
+    ) -> Result<Response, EgressError> {
+        let mut url = url;
+
+        for _ in 0..=self.max_redirects {
+            let span = telemetry.span(
+                "GET",
+                SpanKind::Client,
+                vec![
+                    KeyValue::new("http.request.method", "GET"),
+                    KeyValue::new("server.address", url.host_str().unwrap_or("").to_owned()),
+                    KeyValue::new("url.path", url.path().to_owned()),
+                ],
+            );
+            let response = self.send(&url).await;
+            record(&span, &response);
+            let response = response?;
+            span.span().set_attribute(KeyValue::new(
+                "http.response.status_code",
+                i64::from(response.status().as_u16()),
+            ));
+
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok());
+
+            if !response.status().is_redirection() {
+                return Ok(response);
+            }
+            // Otherwise the body of the redirect would be saved as the file
+            let location = location.ok_or_else(|| {
+                EgressError::InvalidRedirect(format!("{} has no location", response.status()))
+            })?;
+            url = url.join(location).map_err(|_| {
+                EgressError::InvalidRedirect(format!("invalid location {location}"))
+            })?;
+        }
+
+        Err(EgressError::TooManyRedirects)
+    }
+
+    /// Requests `url` once, without following redirects
+    async fn send(&self, url: &Url) -> Result<Response, EgressError> {
+        let mut client = Client::builder().redirect(Policy::none()).no_proxy();
+        // Pin the host to the addresses which were checked, so it cannot be re-resolved elsewhere
+        if let Some((domain, addrs)) = self.resolve(url).await? {
+            client = client.resolve_to_addrs(&domain, &addrs);
+        }
+
+        client
+            .build()
+            .map_err(EgressError::Request)?
+            .get(url.clone())
+            .send()
+            .await
+            .map_err(EgressError::Request)
+    }
+
+    /// Checks `url` is allowed, returning its domain and the addresses it resolved to, if it has one
+    async fn resolve(&self, url: &Url) -> Result<Option<(String, Vec<SocketAddr>)>, EgressError> {
+        if !self.schemes.iter().any(|scheme| scheme == url.scheme()) {
+            return Err(EgressError::Forbidden(format!(
+                "scheme {} is not allowed",
+                url.scheme()
+            )));
+        }
+
+        let port = url
+            .port_or_known_default()
+            .ok_or_else(|| EgressError::Forbidden(String::from("URL has no port")))?;
+
+        match url.host() {
+            None => Err(EgressError::Forbidden(String::from("URL has no host"))),
+            Some(Host::Ipv4(ip)) => self.check_ip(ip.into(), false).map(|_| None),
+            Some(Host::Ipv6(ip)) => self.check_ip(ip.into(), false).map(|_| None),
+            Some(Host::Domain(domain)) => {
+                let host = normalise_host(domain);
+                if self.deny.matches_host(&host) {
+                    return Err(EgressError::Forbidden(format!("host {host} is denied")));
+                }
+
+                let addrs: Vec<SocketAddr> = tokio::net::lookup_host((domain, port))
+                    .await
+                    .map_err(|e| EgressError::Resolve(e.to_string()))?
+                    .collect();
+                if addrs.is_empty() {
+                    return Err(EgressError::Resolve(format!("{host} has no addresses")));
+                }
+
+                let allowed = self.allow.matches_host(&host);
+                for addr in &addrs {
+                    self.check_ip(addr.ip(), allowed)?;
+                }
+
+                Ok(Some((String::from(domain), addrs)))
+            }
+        }
+    }
+
+    fn check_ip(&self, ip: IpAddr, host_allowed: bool) -> Result<(), EgressError> {
+        let ip = canonical(ip);
+
+        if self.deny.matches_ip(ip) {
+            return Err(EgressError::Forbidden(format!("address {ip} is denied")));
+        }
+
+        if host_allowed
+            || self.allow.matches_ip(ip)
+            || !NON_PUBLIC_CIDRS.iter().any(|cidr| cidr.contains(ip))
+        {
+            Ok(())
+        } else {
+            Err(EgressError::Forbidden(format!(
+                "address {ip} is not publicly routable"
+            )))
+        }
+    }
+}
+
+/// Converts IPv4-mapped and IPv4-compatible IPv6 addresses to the IPv4 address they contain, so
+/// they are checked against the same rules
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        // `::` and `::1` are IPv6's own unspecified and loopback addresses
+        IpAddr::V6(v6) if u128::from(v6) >> 32 == 0 && u128::from(v6) > 1 => {
+            u32::try_from(u128::from(v6)).map_or(ip, |v4| IpAddr::V4(Ipv4Addr::from(v4)))
+        }
+        ip => ip.to_canonical(),
+    }
+}
+
+fn normalise_host(host: &str) -> String {
+    host.trim_end_matches('.').to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    fn policy(allow: &[&str], deny: &[&str], max_redirects: usize) -> EgressPolicy {
+        let list = |entries: &[&str]| entries.iter().map(|s| String::from(*s)).collect::<Vec<_>>();
+        EgressPolicy::new(
+            &list(allow),
+            &list(deny),
+            &list(&["http", "https"]),
+            max_redirects,
+        )
+        .unwrap()
+    }
+
+    /// Spawns a server which redirects every request to `location`, or gives no location if `None`
+    async fn spawn_redirect(location: Option<&'static str>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buffer = [0; 1024];
+                let _ = stream.read(&mut buffer).await;
+                let location = location
+                    .map(|location| format!("Location: {location}\r\n"))
+                    .unwrap_or_default();
+                let response = format!(
+                    "HTTP/1.1 302 Found\r\n{location}Content-Length: 5\r\nConnection: close\r\n\r\nmoved"
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+
+        format!("http://{addr}/")
+    }
+
+    #[test]
+    fn parses_cidrs() {
+        let cidr: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(cidr.contains("10.1.2.3".parse().unwrap()));
+        assert!(!cidr.contains("10.2.0.1".parse().unwrap()));
+        assert!(cidr.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(cidr.contains("::10.1.2.3".parse().unwrap()));
+
+        let single: Cidr = "fd00::1".parse().unwrap();
+        assert_eq!(single.prefix, 128);
+        assert!(single.contains("fd00::1".parse().unwrap()));
+        assert!(!single.contains("fd00::2".parse().unwrap()));
+
+        let everything: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains("8.8.8.8".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("nope/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn blocks_non_public_addresses() {
+        let policy = policy(&[], &[], 5);
+
+        for ip in [
+            "127.0.0.1",
+            "10.0.0.5",
+            "172.20.1.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.100.100.200",
+            "0.0.0.0",
+            "::1",
+            "::ffff:127.0.0.1",
+            "::127.0.0.1",
+            "::a9fe:a9fe",
+            "2002:7f00:1::1",
+            "2001:0:4136:e378:8000:63bf:80ff:fffe",
+            "fd00:ec2::254",
+            "fe80::1",
+        ] {
+            assert!(policy.check_ip(ip.parse().unwrap(), false).is_err(), "{ip}");
+        }
+
+        assert!(
+            policy
+                .check_ip("93.184.215.14".parse().unwrap(), false)
+                .is_ok()
+        );
+        assert!(
+            policy
+                .check_ip("2606:2800:21f:cb07::1".parse().unwrap(), false)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn allow_and_deny_lists() {
+        let policy = policy(
+            &["10.1.0.0/16", "files.internal", "*.corp.example"],
+            &["10.1.2.3", "93.184.215.0/24", "evil.example.com"],
+            5,
+        );
+
+        assert!(policy.check_ip("10.1.0.1".parse().unwrap(), false).is_ok());
+        assert!(policy.check_ip("10.1.2.3".parse().unwrap(), false).is_err());
+        assert!(
+            policy
+                .check_ip("93.184.215.14".parse().unwrap(), false)
+                .is_err()
+        );
+        assert!(
+            policy
+                .check_ip("192.168.0.1".parse().unwrap(), true)
+                .is_ok()
+        );
+
+        assert!(policy.allow.matches_host("files.internal"));
+        assert!(policy.allow.matches_host("a.b.corp.example"));
+        assert!(policy.allow.matches_host("corp.example"));
+        assert!(!policy.allow.matches_host("notcorp.example"));
+        assert!(policy.deny.matches_host("evil.example.com"));
+        assert!(!policy.deny.matches_host("example.com"));
+    }
+
+    #[tokio::test]
+    async fn rejects_disallowed_urls() {
+        let policy = policy(&[], &["localhost.example"], 5);
+
+        for url in [
+            "ftp://example.com/file.png",
+            "file:///etc/passwd",
+            "http://127.0.0.1/file.png",
+            "http://[::1]/file.png",
+            "http://169.254.169.254/latest/meta-data",
+            "http://localhost/file.png",
+            "http://LOCALHOST.example./file.png",
+        ] {
+            let error = policy
+                .get(Url::parse(url).unwrap(), &Telemetry::default())
+                .await
+                .unwrap_err();
+            assert!(matches!(error, EgressError::Forbidden(_)), "{url}: {error}");
+        }
+    }
+
+    #[tokio::test]
+    async fn checks_every_redirect() {
+        let url = spawn_redirect(Some("http://169.254.169.254/latest/meta-data")).await;
+        let error = policy(&["127.0.0.1"], &[], 5)
+            .get(Url::parse(&url).unwrap(), &Telemetry::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(error, EgressError::Forbidden(_)), "{error}");
+
+        let url = spawn_redirect(Some("/again")).await;
+        let error = policy(&["127.0.0.1"], &[], 3)
+            .get(Url::parse(&url).unwrap(), &Telemetry::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(error, EgressError::TooManyRedirects), "{error}");
+
+        let url = spawn_redirect(None).await;
+        let error = policy(&["127.0.0.1"], &[], 3)
+            .get(Url::parse(&url).unwrap(), &Telemetry::default())
+            .await
+            .unwrap_err();
+        assert!(matches!(error, EgressError::InvalidRedirect(_)), "{error}");
+        assert_eq!(error.status(), rocket::http::Status::BadGateway);
+    }
+}
@@ -1,15 +1,19 @@
+use rocket::http::Status;
 use std::fmt::{Display, Formatter};
 
 #[derive(Debug, Clone)]
 pub enum LoadError {
     FileNotExist(String),
     PermissionDenied(String),
+    Backend(String),
 }
 
 impl Display for LoadError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            LoadError::FileNotExist(string) | LoadError::PermissionDenied(string) => {
+            LoadError::FileNotExist(string)
+            | LoadError::PermissionDenied(string)
+            | LoadError::Backend(string) => {
                 write!(f, "{string}")
             }
         }
@@ -54,8 +58,91 @@ impl Display for DeleteError {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum ApiKeyError {
     Missing,
     Invalid,
+    Unknown,
+    Expired,
 }
+
+impl ApiKeyError {
+    pub fn status(&self) -> Status {
+        match self {
+            ApiKeyError::Invalid => Status::BadRequest,
+            ApiKeyError::Missing | ApiKeyError::Unknown | ApiKeyError::Expired => {
+                Status::Unauthorized
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum EgressError {
+    /// The URL is not allowed by the egress policy
+    Forbidden(String),
+    Resolve(String),
+    TooManyRedirects,
+    /// A redirect without a usable `Location`
+    InvalidRedirect(String),
+    Request(reqwest::Error),
+}
+
+impl EgressError {
+    pub fn status(&self) -> Status {
+        match self {
+            EgressError::Forbidden(_) => Status::Forbidden,
+            EgressError::Request(error) if error.is_timeout() => Status::GatewayTimeout,
+            EgressError::Resolve(_)
+            | EgressError::TooManyRedirects
+            | EgressError::InvalidRedirect(_)
+            | EgressError::Request(_) => Status::BadGateway,
+        }
+    }
+}
+
+impl Display for EgressError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EgressError::Forbidden(reason) => write!(f, "forbidden by egress policy: {reason}"),
+            EgressError::Resolve(reason) => write!(f, "unable to resolve host: {reason}"),
+            EgressError::TooManyRedirects => write!(f, "too many redirects"),
+            EgressError::InvalidRedirect(reason) => write!(f, "invalid redirect: {reason}"),
+            EgressError::Request(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+/// A mistake in the settings, found while building a server so it can't fail on a later request
+#[derive(Debug)]
+pub enum ConfigError {
+    KeysFile(String),
+    /// API keys are configured without a `secret` to sign tokens with
+    MissingSecret,
+    EgressPolicy(String),
+    UploadPolicy(String),
+    AuditLog(std::io::Error),
+    Telemetry(String),
+    Tls(String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::KeysFile(error) => write!(f, "invalid API keys file: {error}"),
+            ConfigError::MissingSecret => write!(
+                f,
+                "a secret must be set to sign tokens with when API keys are configured"
+            ),
+            ConfigError::EgressPolicy(error) => write!(f, "invalid egress policy: {error}"),
+            ConfigError::UploadPolicy(error) => write!(f, "invalid upload policy: {error}"),
+            ConfigError::AuditLog(error) => write!(f, "unable to open the audit log: {error}"),
+            ConfigError::Telemetry(error) => {
+                write!(f, "unable to configure the OTLP exporter: {error}")
+            }
+            ConfigError::Tls(error) => write!(f, "unable to load the TLS certificate: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
@@ -3,52 +3,255 @@ compile_error!(
     "feature \"cli\" and feature \"docker\" cannot be enabled at the same time. Use \"--no-default-features --features docker\" if you wish to build for docker"
 );
 
-use crate::settings::StorageCommands;
+use crate::{
+    audit::Audit,
+    auth::{Authenticator, KeyStore, Signer},
+    egress::EgressPolicy,
+    error::ConfigError,
+    listen::Listen,
+    metrics::{MeteredStorage, Metrics, RequestMetrics},
+    policy::UploadPolicy,
+    routes::{Jobs, UploadLocks},
+    settings::{ListenAddress, LogFormat},
+    telemetry::{RequestSpans, Telemetry, TracedStorage},
+    tls::Tls,
+};
 use rocket::{
-    Build, Rocket,
+    Build, Rocket, Route, State,
     config::LogLevel,
-    data::{Limits, ToByteUnit},
+    data::{ByteUnit, Limits, ToByteUnit},
+    fairing::AdHoc,
+    http::{Status, uncased::Uncased},
+    serde::{Serialize, json::Json},
+};
+use routes::{
+    delete_file, file_metadata, get_file, get_metrics, job_status, list_files, sign_file,
+    tus_create, tus_head, tus_options, tus_patch, tus_terminate, upload_file, upload_file_url,
 };
-use routes::{delete_file, get_file, upload_file, upload_file_url};
 use settings::Settings;
-use std::{net::Ipv4Addr, sync::LazyLock};
-use storage::Storage;
+use std::{
+    collections::BTreeMap,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use storage::{Check, Storage};
 
-mod error;
+mod audit;
+pub mod auth;
+mod download;
+mod egress;
+pub mod error;
+mod listen;
+mod logging;
+mod metrics;
+mod policy;
+mod reaper;
+mod remote;
 mod routes;
-mod settings;
+pub mod settings;
 pub mod storage;
+mod telemetry;
+mod tls;
 
 #[macro_use]
 extern crate rocket;
 
-pub(crate) static SETTINGS: LazyLock<Settings> = {
-    if cfg!(debug_assertions) {
-        LazyLock::new(|| Settings {
-            api_key: "12345",
-            port: 10032,
-            url: "http://localhost",
-            verbose: true,
-            ip: Ipv4Addr::UNSPECIFIED,
-            storage_type: StorageCommands::Debug,
+/// How long the storage backend has to answer a readiness check before it is reported as down
+const READY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Everything a server instance needs, shared with its routes as Rocket managed state
+pub(crate) struct Context {
+    pub(crate) settings: Settings,
+    pub(crate) storage: Box<dyn Storage>,
+    pub(crate) authenticator: Box<dyn Authenticator>,
+    pub(crate) signer: Signer,
+    pub(crate) egress: EgressPolicy,
+    pub(crate) upload_policy: UploadPolicy,
+    pub(crate) jobs: Jobs,
+    pub(crate) tus_locks: UploadLocks,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) audit: Audit,
+    pub(crate) telemetry: Telemetry,
+}
+
+impl Context {
+    /// Reads the keys file and policies, so mistakes in them are found at startup rather than on
+    /// the first request
+    fn new(settings: Settings, storage: Box<dyn Storage>) -> Result<Self, ConfigError> {
+        let metrics = Arc::new(Metrics::new());
+        let telemetry = Telemetry::from_settings(&settings)
+            .map_err(|e| ConfigError::Telemetry(e.to_string()))?;
+        let storage = Box::new(TracedStorage::new(storage, telemetry.clone()));
+        let authenticator =
+            KeyStore::new(settings.api_key.as_deref(), settings.keys_file.as_deref())
+                .map_err(ConfigError::KeysFile)?;
+        // A random secret would change on every restart, breaking every token handed out so far
+        let keys = settings.api_key.is_some() || settings.keys_file.is_some();
+        if keys && settings.secret.is_none() {
+            return Err(ConfigError::MissingSecret);
+        }
+        Ok(Context {
+            authenticator: Box::new(authenticator),
+            signer: Signer::new(settings.secret.as_deref()),
+            egress: EgressPolicy::new(
+                &settings.egress_allow,
+                &settings.egress_deny,
+                &settings.egress_schemes,
+                settings.egress_max_redirects,
+            )
+            .map_err(ConfigError::EgressPolicy)?,
+            upload_policy: UploadPolicy::from_settings(&settings)
+                .map_err(ConfigError::UploadPolicy)?,
+            jobs: Jobs::new(settings.job_concurrency),
+            tus_locks: UploadLocks::default(),
+            audit: Audit::from_settings(&settings).map_err(ConfigError::AuditLog)?,
+            settings,
+            storage: Box::new(MeteredStorage::new(storage, Arc::clone(&metrics))),
+            metrics,
+            telemetry,
         })
-    } else {
-        LazyLock::new(Settings::new)
     }
-};
+}
+
+/// Rumia's routes and state, for mounting inside another Rocket app.
+///
+/// Only one can be attached to each Rocket instance, since its state is managed by type
+pub struct Rumia {
+    context: Context,
+}
+
+impl Rumia {
+    /// Creates Rumia from `settings`, storing files in `storage` rather than the backend chosen by
+    /// `settings.storage_type`
+    ///
+    /// # Errors
+    ///
+    /// If the keys file, policies, audit log or OTLP exporter in the settings are invalid, or API
+    /// keys are configured without a secret
+    pub fn new(settings: Settings, storage: Box<dyn Storage>) -> Result<Self, ConfigError> {
+        Ok(Rumia {
+            context: Context::new(settings, storage)?,
+        })
+    }
+
+    /// Identifies requests with `authenticator`, instead of the API keys from the settings
+    #[must_use]
+    pub fn authenticator(mut self, authenticator: impl Authenticator + 'static) -> Self {
+        self.context.authenticator = Box::new(authenticator);
+        self
+    }
+
+    /// The largest upload allowed, which the `data-form` and `file` limits should be raised to
+    #[must_use]
+    pub fn largest_upload(&self) -> ByteUnit {
+        self.context.upload_policy.largest_size().bytes()
+    }
+
+    /// Every route, which can be mounted under any prefix. The URLs they return are the `url`
+    /// setting followed by the prefix, so leave `url` empty to get paths relative to the host
+    #[must_use]
+    pub fn routes(&self) -> Vec<Route> {
+        telemetry::instrument(routes![
+            upload_file,
+            upload_file_url,
+            job_status,
+            tus_options,
+            tus_create,
+            tus_head,
+            tus_patch,
+            tus_terminate,
+            delete_file,
+            get_file,
+            sign_file,
+            file_metadata,
+            list_files,
+            readiness,
+        ])
+    }
+
+    /// The Prometheus `GET /metrics` route. It needs no API key, so it isn't part of
+    /// [`Rumia::routes`] and apps choose whether and where to mount it
+    #[must_use]
+    pub fn metrics_routes(&self) -> Vec<Route> {
+        telemetry::instrument(routes![get_metrics])
+    }
 
-pub static STORAGE: LazyLock<Box<dyn Storage>> =
-    LazyLock::new(|| storage::init(&SETTINGS.storage_type));
+    /// Manages the state the routes need, gives requests IDs, records metrics, traces and audit
+    /// logs about requests, and deletes expired files once the server has launched
+    #[must_use]
+    pub fn fairing(self) -> AdHoc {
+        let context = Arc::new(self.context);
+        AdHoc::on_ignite("Rumia", |rocket| async move {
+            rocket
+                .manage(context)
+                .attach(logging::Requests)
+                .attach(RequestSpans)
+                .attach(RequestMetrics)
+                .attach(audit::fairing())
+                .attach(reaper::fairing())
+        })
+    }
+}
+
+/// Builds a server from the CLI args, env vars and config file
+///
+/// # Errors
+///
+/// If the settings are invalid, see [`server_with`]
+pub fn server() -> Result<Rocket<Build>, ConfigError> {
+    let settings = Settings::new();
+    let storage = storage::init(&settings.storage_type);
+    server_with(settings, storage)
+}
+
+/// Builds a server from `settings`, storing files in `storage` rather than the backend chosen by
+/// `settings.storage_type`.
+///
+/// Nothing is shared between servers, so several can run in the same process
+///
+/// # Errors
+///
+/// If the settings are invalid, eg the keys file can't be read or the TLS certificate doesn't
+/// match its key
+pub fn server_with(
+    settings: Settings,
+    storage: Box<dyn Storage>,
+) -> Result<Rocket<Build>, ConfigError> {
+    let tls = Tls::from_settings(&settings).map_err(ConfigError::Tls)?;
+    let addresses = match settings.listen.is_empty() {
+        true => vec![ListenAddress::Tcp(SocketAddr::new(
+            settings.ip,
+            settings.port,
+        ))],
+        false => settings.listen.clone(),
+    };
+    let reload_interval = Duration::from_secs(settings.tls_reload_interval);
+
+    // Rocket can bind a single TCP address itself, otherwise it is only reached through the
+    // listeners on the public addresses
+    let direct = match (&tls, addresses.as_slice()) {
+        (None, [ListenAddress::Tcp(address)]) => Some(*address),
+        _ => None,
+    };
+    let listen = direct.unwrap_or(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)));
+
+    if settings.log_format == LogFormat::Json {
+        logging::init(settings.verbose);
+    }
+
+    let rumia = Rumia::new(settings, storage)?;
+    let settings = &rumia.context.settings;
 
-#[must_use]
-pub fn server() -> Rocket<Build> {
     let config = rocket::Config {
-        port: SETTINGS.port,
-        address: SETTINGS.ip.into(),
+        port: listen.port(),
+        address: listen.ip(),
+        // Rumia reads the client's IP itself, only trusting a header if one is configured
+        ip_header: settings.ip_header.clone().map(Uncased::from_owned),
         limits: Limits::default()
-            .limit("data-form", 200.megabytes())
-            .limit("file", 200.megabytes()),
-        log_level: if SETTINGS.verbose {
+            .limit("data-form", rumia.largest_upload())
+            .limit("file", rumia.largest_upload()),
+        log_level: if settings.verbose {
             LogLevel::Normal
         } else {
             LogLevel::Critical
@@ -56,19 +259,52 @@ pub fn server() -> Rocket<Build> {
         ..rocket::Config::default()
     };
 
-    rocket::custom(config).mount(
-        "/",
-        routes![
-            healthcheck,
-            upload_file,
-            upload_file_url,
-            delete_file,
-            get_file,
-        ],
-    )
+    let rocket = rocket::custom(config)
+        .mount("/", routes![healthcheck])
+        .mount("/", rumia.routes())
+        .mount("/", rumia.metrics_routes())
+        .attach(rumia.fairing());
+
+    Ok(match direct {
+        Some(_) => rocket,
+        None => rocket.attach(Listen::new(addresses, tls, reload_interval)),
+    })
 }
 
 #[get("/health")]
 async fn healthcheck() -> &'static str {
     "ok"
 }
+
+/// Whether the server can store files, answered by `GET /health/ready`
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Readiness {
+    status: &'static str,
+    backend: &'static str,
+    components: BTreeMap<&'static str, Check>,
+}
+
+#[get("/health/ready")]
+async fn readiness(context: &State<Arc<Context>>) -> (Status, Json<Readiness>) {
+    let checks = match tokio::time::timeout(READY_TIMEOUT, context.storage.health()).await {
+        Ok(checks) => checks,
+        Err(_) => vec![Check::down("storage", "timed out")],
+    };
+    let ready = checks.iter().all(|check| check.up);
+
+    let readiness = Readiness {
+        status: if ready { "ready" } else { "unavailable" },
+        backend: context.storage.name(),
+        components: checks
+            .into_iter()
+            .map(|check| (check.component, check))
+            .collect(),
+    };
+    let status = if ready {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+    (status, Json(readiness))
+}
@@ -0,0 +1,424 @@
+//! Accepts connections on every listen address and forwards them to Rocket, which can only bind a
+//! single TCP address itself and so listens on loopback. Connections are served over HTTPS if TLS
+//! is enabled.
+//!
+//! Other processes on the host could connect to Rocket's loopback address directly, skipping TLS
+//! and client certificates, so requests which weren't forwarded are refused by a route ranked
+//! ahead of all the others
+
+use crate::{settings::ListenAddress, tls::Tls};
+use rocket::{
+    Build, Data, Orbit, Request, Rocket, Route,
+    fairing::{self, Fairing, Info, Kind},
+    http::{Method, Status},
+    route::{self, Handler},
+};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+};
+
+/// Binds every address at ignition, then forwards their connections to Rocket once it has
+/// launched
+pub(crate) struct Listen {
+    addresses: Vec<ListenAddress>,
+    tls: Option<Arc<Tls>>,
+    reload_interval: Duration,
+    listeners: Mutex<Vec<Listener>>,
+    peers: Peers,
+}
+
+/// The client at the other end of each connection forwarded to Rocket, keyed by the local address
+/// of the forwarded connection, since that is all Rocket sees. Unix socket clients have no IP
+#[derive(Clone, Default)]
+pub(crate) struct Peers(Arc<Mutex<HashMap<SocketAddr, Option<IpAddr>>>>);
+
+/// Sends accepted connections on to Rocket at `backend`
+#[derive(Clone)]
+pub(crate) struct Forwarder {
+    tls: Option<Arc<Tls>>,
+    backend: SocketAddr,
+    peers: Peers,
+}
+
+/// Answers requests which reached Rocket without being forwarded with `403 - Forbidden`, and
+/// forwards the rest on to the other routes. It is mounted for every method ahead of them all
+#[derive(Clone)]
+struct Refuse(Peers);
+
+enum Listener {
+    Tcp(std::net::TcpListener),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener),
+}
+
+impl Listen {
+    /// Listens on `addresses`, serving HTTPS with `tls` if it is set and checking its files for
+    /// changes every `reload_interval`
+    pub(crate) fn new(
+        addresses: Vec<ListenAddress>,
+        tls: Option<Tls>,
+        reload_interval: Duration,
+    ) -> Self {
+        Listen {
+            addresses,
+            tls: tls.map(Arc::new),
+            reload_interval,
+            listeners: Mutex::default(),
+            peers: Peers::default(),
+        }
+    }
+}
+
+impl Peers {
+    /// The client which Rocket's connection from `forwarded` was accepted from, if it was
+    /// forwarded at all
+    pub(crate) fn get(&self, forwarded: SocketAddr) -> Option<Option<IpAddr>> {
+        self.0.lock().ok()?.get(&forwarded).copied()
+    }
+
+    fn insert(&self, forwarded: SocketAddr, peer: Option<IpAddr>) {
+        if let Ok(mut peers) = self.0.lock() {
+            peers.insert(forwarded, peer);
+        }
+    }
+
+    fn remove(&self, forwarded: SocketAddr) {
+        if let Ok(mut peers) = self.0.lock() {
+            peers.remove(&forwarded);
+        }
+    }
+}
+
+impl Listener {
+    fn bind(address: &ListenAddress) -> io::Result<Self> {
+        match address {
+            ListenAddress::Tcp(address) => {
+                let listener = std::net::TcpListener::bind(address)?;
+                listener.set_nonblocking(true)?;
+                Ok(Listener::Tcp(listener))
+            }
+            #[cfg(unix)]
+            ListenAddress::Unix(path) => {
+                use std::os::unix::fs::FileTypeExt;
+
+                // A socket left behind by a previous run would stop us binding, but don't delete
+                // anything else which happens to be at the path
+                if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                    std::fs::remove_file(path)?;
+                }
+                let listener = std::os::unix::net::UnixListener::bind(path)?;
+                listener.set_nonblocking(true)?;
+                Ok(Listener::Unix(listener))
+            }
+            #[cfg(not(unix))]
+            ListenAddress::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Unix sockets are not supported on this platform",
+            )),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for Listen {
+    fn info(&self) -> Info {
+        Info {
+            name: "Listen",
+            kind: Kind::Ignite | Kind::Liftoff | Kind::Singleton,
+        }
+    }
+
+    /// Binds every address now, so launch fails if any are unavailable
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        let mut listeners = Vec::with_capacity(self.addresses.len());
+        for address in &self.addresses {
+            match Listener::bind(address) {
+                Ok(listener) => listeners.push(listener),
+                Err(e) => {
+                    error_!("Unable to bind {address}: {e}");
+                    return Err(rocket);
+                }
+            }
+        }
+
+        match self.listeners.lock() {
+            Ok(mut slot) => {
+                *slot = listeners;
+                let refuse = Refuse(self.peers.clone());
+                Ok(rocket
+                    .manage(self.peers.clone())
+                    .mount("/", refuse.routes()))
+            }
+            Err(_) => Err(rocket),
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let listeners = match self.listeners.lock() {
+            Ok(mut slot) => std::mem::take(&mut *slot),
+            Err(_) => Vec::new(),
+        };
+        let forwarder = Forwarder {
+            tls: self.tls.clone(),
+            backend: SocketAddr::new(rocket.config().address, rocket.config().port),
+            peers: self.peers.clone(),
+        };
+        let scheme = if self.tls.is_some() { "https" } else { "http" };
+
+        for (listener, address) in listeners.into_iter().zip(&self.addresses) {
+            let forwarder = forwarder.clone();
+            let served = match listener {
+                Listener::Tcp(listener) => TcpListener::from_std(listener)
+                    .map(|listener| tokio::spawn(serve_tcp(listener, forwarder))),
+                #[cfg(unix)]
+                Listener::Unix(listener) => tokio::net::UnixListener::from_std(listener)
+                    .map(|listener| tokio::spawn(serve_unix(listener, forwarder))),
+            };
+            match served {
+                Ok(_) => info_!("Serving {scheme} on {address}"),
+                Err(e) => error_!("Unable to serve {scheme} on {address}: {e}"),
+            }
+        }
+
+        if let Some(tls) = &self.tls {
+            Arc::clone(tls).watch(self.reload_interval);
+        }
+    }
+}
+
+impl Refuse {
+    /// A catch-all route for every method, ranked ahead of any other
+    fn routes(self) -> Vec<Route> {
+        [
+            Method::Get,
+            Method::Put,
+            Method::Post,
+            Method::Delete,
+            Method::Options,
+            Method::Head,
+            Method::Trace,
+            Method::Connect,
+            Method::Patch,
+        ]
+        .into_iter()
+        .map(|method| {
+            let mut route = Route::ranked(isize::MIN, method, "/<_..>", self.clone());
+            route.name = Some(Cow::Borrowed("unforwarded"));
+            route
+        })
+        .collect()
+    }
+}
+
+#[rocket::async_trait]
+impl Handler for Refuse {
+    async fn handle<'r>(&self, request: &'r Request<'_>, data: Data<'r>) -> route::Outcome<'r> {
+        let forwarded = request
+            .remote()
+            .is_some_and(|remote| self.0.get(remote).is_some());
+        if forwarded {
+            route::Outcome::forward(data, Status::NotFound)
+        } else {
+            route::Outcome::error(Status::Forbidden)
+        }
+    }
+}
+
+/// Accepts connections on `listener`, forwarding each to Rocket
+pub(crate) async fn serve_tcp(listener: TcpListener, forwarder: Forwarder) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => forwarder.forward(stream, Some(peer.ip())),
+            Err(e) => warn_!("Unable to accept a connection: {e}"),
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn serve_unix(listener: tokio::net::UnixListener, forwarder: Forwarder) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => forwarder.forward(stream, None),
+            Err(e) => warn_!("Unable to accept a connection: {e}"),
+        }
+    }
+}
+
+impl Forwarder {
+    #[cfg(test)]
+    pub(crate) fn new(tls: Option<Arc<Tls>>, backend: SocketAddr) -> Self {
+        Forwarder {
+            tls,
+            backend,
+            peers: Peers::default(),
+        }
+    }
+
+    /// Forwards `stream` from `peer` to Rocket, after a TLS handshake if TLS is enabled
+    fn forward<S>(&self, stream: S, peer: Option<IpAddr>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let acceptor = match self.tls.as_deref().map(Tls::acceptor) {
+            Some(Some(acceptor)) => Some(acceptor),
+            Some(None) => return,
+            None => None,
+        };
+
+        let forwarder = self.clone();
+        tokio::spawn(async move {
+            match acceptor {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(stream) => forwarder.pipe(stream, peer).await,
+                    Err(e) => info_!("TLS handshake with {} failed: {e}", describe(peer)),
+                },
+                None => forwarder.pipe(stream, peer).await,
+            }
+        });
+    }
+
+    async fn pipe<S: AsyncRead + AsyncWrite + Unpin>(&self, mut stream: S, peer: Option<IpAddr>) {
+        let Ok(mut upstream) = TcpStream::connect(self.backend).await else {
+            warn_!(
+                "Unable to forward a connection from {} to {}",
+                describe(peer),
+                self.backend
+            );
+            return;
+        };
+
+        let forwarded = upstream.local_addr().ok();
+        if let Some(forwarded) = forwarded {
+            self.peers.insert(forwarded, peer);
+        }
+        tokio::io::copy_bidirectional(&mut stream, &mut upstream)
+            .await
+            .ok();
+        if let Some(forwarded) = forwarded {
+            self.peers.remove(forwarded);
+        }
+    }
+}
+
+fn describe(peer: Option<IpAddr>) -> String {
+    peer.map_or_else(|| String::from("a Unix socket"), |peer| peer.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::settings::{ListenAddress, Settings};
+    use std::{net::SocketAddr, path::Path, time::Duration};
+    use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    async fn health<S: AsyncRead + AsyncWrite + Unpin>(stream: S) -> String {
+        get(stream, "/health").await
+    }
+
+    async fn get<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, path: &str) -> String {
+        let request =
+            format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    /// Waits for the server to start listening on `address`
+    async fn connect(address: SocketAddr) -> tokio::net::TcpStream {
+        for _ in 0..50 {
+            if let Ok(stream) = tokio::net::TcpStream::connect(address).await {
+                return stream;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        unreachable!("the server never listened on {address}");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn serves_every_address() {
+        let ipv4 = SocketAddr::from(([127, 0, 0, 1], free_port()));
+        let ipv6 = SocketAddr::from((std::net::Ipv6Addr::LOCALHOST, free_port()));
+        let socket = std::env::temp_dir().join(format!("rumia-{}.sock", uuid::Uuid::new_v4()));
+        let settings = Settings {
+            listen: vec![
+                ListenAddress::Tcp(ipv4),
+                ListenAddress::Tcp(ipv6),
+                ListenAddress::Unix(socket.clone()),
+            ],
+            ..Settings::default()
+        };
+        let storage = Box::new(crate::storage::DebugStorage::new());
+        tokio::spawn(crate::server_with(settings, storage).unwrap().launch());
+
+        assert!(health(connect(ipv4).await).await.ends_with("ok"));
+        assert!(health(connect(ipv6).await).await.ends_with("ok"));
+        assert!(Path::new(&socket).exists());
+        let stream = tokio::net::UnixStream::connect(&socket).await.unwrap();
+        assert!(health(stream).await.ends_with("ok"));
+        std::fs::remove_file(socket).ok();
+    }
+
+    #[tokio::test]
+    async fn refuses_direct_connections() {
+        let listen = SocketAddr::from(([127, 0, 0, 1], free_port()));
+        let other = SocketAddr::from(([127, 0, 0, 1], free_port()));
+        let settings = Settings {
+            listen: vec![ListenAddress::Tcp(listen), ListenAddress::Tcp(other)],
+            ..Settings::default()
+        };
+        let storage = Box::new(crate::storage::DebugStorage::new());
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        let sender = std::sync::Mutex::new(Some(sender));
+        let rocket = crate::server_with(settings, storage).unwrap().attach(
+            rocket::fairing::AdHoc::on_liftoff("Backend", move |rocket| {
+                let backend = SocketAddr::new(rocket.config().address, rocket.config().port);
+                if let Some(sender) = sender.lock().unwrap().take() {
+                    sender.send(backend).ok();
+                }
+                Box::pin(async {})
+            }),
+        );
+        tokio::spawn(rocket.launch());
+
+        let backend = receiver.await.unwrap();
+        assert!(health(connect(listen).await).await.ends_with("ok"));
+        let response = get(connect(listen).await, "/missing").await;
+        assert!(response.starts_with("HTTP/1.1 404"), "{response}");
+
+        let response = health(connect(backend).await).await;
+        assert!(response.starts_with("HTTP/1.1 403"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn binds_ipv6_directly() {
+        let port = free_port();
+        let settings = Settings {
+            ip: std::net::Ipv6Addr::LOCALHOST.into(),
+            port,
+            ..Settings::default()
+        };
+        let storage = Box::new(crate::storage::DebugStorage::new());
+        tokio::spawn(crate::server_with(settings, storage).unwrap().launch());
+
+        let address = SocketAddr::from((std::net::Ipv6Addr::LOCALHOST, port));
+        assert!(health(connect(address).await).await.ends_with("ok"));
+    }
+}
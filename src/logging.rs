@@ -0,0 +1,335 @@
+//! JSON logs, and the request ID and access log record given to every request
+
+use crate::{Context, listen::Peers, settings::LogFormat};
+use log::{
+    LevelFilter, Log, Metadata, Record,
+    kv::{self, Key, Value, VisitSource},
+};
+use rocket::{
+    Data, Request, Response,
+    fairing::{Fairing, Info, Kind},
+    http::{Header, Status},
+    outcome::Outcome,
+    request::FromRequest,
+    serde::json::{Value as Json, serde_json::Map},
+};
+use std::{convert::Infallible, net::IpAddr, sync::Arc, time::Instant};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+use uuid::Uuid;
+
+/// The target access log records are written with
+const ACCESS_TARGET: &str = "rumia::access";
+const REQUEST_ID_HEADER: &str = "X-Request-Id";
+/// Longest request ID accepted from a client, rather than generating one
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Writes every log record as a JSON object on its own line. Access log records are always
+/// written, and other info records only when `verbose`
+struct JsonLogger {
+    verbose: bool,
+}
+
+/// Gives every request an ID, returned in the `X-Request-Id` header, and writes an access log
+/// record for it when logging JSON
+pub(crate) struct Requests;
+
+/// Identifies a request in logs, either the `X-Request-Id` it was sent with or a new UUID
+#[derive(Debug, Clone)]
+pub(crate) struct RequestId(pub(crate) String);
+
+/// When the current request started, kept in its local cache
+struct Started(Instant);
+
+/// Collects key-value pairs from a log record into a JSON object
+struct Fields<'a>(&'a mut Map<String, Json>);
+
+/// Logs JSON instead of Rocket's text logs. Must be called before Rocket is built, since Rocket
+/// installs its own logger unless there already is one
+pub(crate) fn init(verbose: bool) {
+    if log::set_boxed_logger(Box::new(JsonLogger { verbose })).is_ok() {
+        // The access log is at info level, so the logger filters by verbosity itself
+        log::set_max_level(LevelFilter::Info);
+    }
+}
+
+impl JsonLogger {
+    /// The line to write for `record`, if it should be written
+    fn line(&self, record: &Record<'_>) -> Option<Json> {
+        if !self.enabled(record.metadata()) {
+            return None;
+        }
+        // Rocket logs each request, and its dependencies are chatty, but the access log covers
+        // the same ground
+        let ours = record
+            .module_path()
+            .is_some_and(|module| module.starts_with("rumia"));
+        if !ours && record.level() > log::Level::Warn {
+            return None;
+        }
+
+        Some(format(record))
+    }
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        let max_level = match self.verbose || metadata.target() == ACCESS_TARGET {
+            true => log::Level::Info,
+            false => log::Level::Warn,
+        };
+        metadata.level() <= max_level
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if let Some(line) = self.line(record) {
+            println!("{line}");
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Formats `record` as a JSON object, with its key-value pairs as extra fields
+fn format(record: &Record<'_>) -> Json {
+    let mut object = Map::new();
+    object.insert(String::from("timestamp"), Json::from(now()));
+    object.insert(
+        String::from("level"),
+        Json::from(record.level().as_str().to_lowercase()),
+    );
+    // Rocket's logging macros all share a target, so use where they were called from instead
+    let target = match record.target() {
+        target if target.starts_with("rocket::log") => record.module_path().unwrap_or(target),
+        target => target,
+    };
+    object.insert(String::from("target"), Json::from(target));
+    object.insert(
+        String::from("message"),
+        Json::from(record.args().to_string()),
+    );
+    record.key_values().visit(&mut Fields(&mut object)).ok();
+
+    Json::Object(object)
+}
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(value) = value.to_u64() {
+            Json::from(value)
+        } else if let Some(value) = value.to_i64() {
+            Json::from(value)
+        } else if let Some(value) = value.to_bool() {
+            Json::from(value)
+        } else if let Some(value) = value.to_f64() {
+            Json::from(value)
+        } else {
+            Json::from(value.to_string())
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+/// The current time as an RFC 3339 timestamp
+pub(crate) fn now() -> String {
+    OffsetDateTime::now_utc()
+        .format(&Rfc3339)
+        .unwrap_or_default()
+}
+
+/// The IP address of the client which made `request`. This is read from the header set by a
+/// trusted proxy if `ip_header` is configured, otherwise it is the peer's address. Connections
+/// forwarded by Rumia's own listeners are looked up, since to Rocket they come from loopback
+pub(crate) fn client_ip(request: &Request<'_>) -> Option<IpAddr> {
+    let header = request
+        .rocket()
+        .state::<Arc<Context>>()
+        .and_then(|context| context.settings.ip_header.as_deref());
+    if let Some(header) = header {
+        return request.headers().get_one(header).and_then(forwarded_ip);
+    }
+
+    let remote = request.remote()?;
+    match request
+        .rocket()
+        .state::<Peers>()
+        .and_then(|peers| peers.get(remote))
+    {
+        Some(peer) => peer,
+        None => Some(remote.ip()),
+    }
+}
+
+/// The client IP in a proxy header. Proxies append to lists such as `X-Forwarded-For`, so only
+/// the last address was added by the trusted proxy
+fn forwarded_ip(value: &str) -> Option<IpAddr> {
+    value.rsplit(',').next()?.trim().parse().ok()
+}
+
+impl RequestId {
+    fn new(request: &Request<'_>) -> Self {
+        let sent = request
+            .headers()
+            .get_one(REQUEST_ID_HEADER)
+            .filter(|id| valid_request_id(id));
+        RequestId(sent.map_or_else(|| Uuid::new_v4().to_string(), String::from))
+    }
+
+    pub(crate) fn of(request: &Request<'_>) -> Self {
+        request.local_cache(|| RequestId::new(request)).clone()
+    }
+}
+
+/// Whether an ID sent by a client is safe to repeat in logs and headers
+fn valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"-_.:".contains(&byte))
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestId {
+    type Error = Infallible;
+
+    async fn from_request(
+        request: &'r Request<'_>,
+    ) -> Outcome<RequestId, (Status, Infallible), Status> {
+        Outcome::Success(RequestId::of(request))
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for Requests {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request IDs",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| Started(Instant::now()));
+        RequestId::of(request);
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let id = RequestId::of(request);
+        response.set_header(Header::new(REQUEST_ID_HEADER, id.0.clone()));
+
+        let json = request
+            .rocket()
+            .state::<Arc<Context>>()
+            .is_some_and(|context| context.settings.log_format == LogFormat::Json);
+        if !json {
+            return;
+        }
+
+        let started = request.local_cache(|| Started(Instant::now()));
+        let duration_ms = started.0.elapsed().as_secs_f64() * 1000.0;
+        let route = request
+            .route()
+            .and_then(|route| route.name.as_deref())
+            .unwrap_or("unmatched");
+        let client_ip = client_ip(request).map(|ip| ip.to_string());
+        log::info!(
+            target: ACCESS_TARGET,
+            request_id = id.0.as_str(),
+            method = request.method().as_str(),
+            path = request.uri().path().as_str(),
+            route,
+            status = response.status().code,
+            duration_ms,
+            client_ip;
+            "{} {} {}",
+            request.method(),
+            request.uri().path(),
+            response.status().code
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_records_as_json() {
+        let kvs = [("request_id", "abc"), ("route", "healthcheck")];
+        let record = Record::builder()
+            .args(format_args!("GET /health 200"))
+            .level(log::Level::Info)
+            .target(ACCESS_TARGET)
+            .key_values(&kvs)
+            .build();
+
+        let json = format(&record);
+        assert_eq!(json["level"], "info");
+        assert_eq!(json["target"], ACCESS_TARGET);
+        assert_eq!(json["message"], "GET /health 200");
+        assert_eq!(json["request_id"], "abc");
+        assert_eq!(json["route"], "healthcheck");
+        assert!(json["timestamp"].is_string());
+    }
+
+    #[test]
+    fn always_writes_access_records() {
+        let quiet = JsonLogger { verbose: false };
+        let access = Record::builder()
+            .args(format_args!("GET /health 200"))
+            .level(log::Level::Info)
+            .target(ACCESS_TARGET)
+            .module_path(Some("rumia::logging"))
+            .build();
+        let line = quiet.line(&access).unwrap();
+        assert_eq!(line["target"], ACCESS_TARGET);
+        assert_eq!(line["message"], "GET /health 200");
+
+        let info = Record::builder()
+            .args(format_args!("Deleted 2 expired files"))
+            .level(log::Level::Info)
+            .target("rumia::reaper")
+            .module_path(Some("rumia::reaper"))
+            .build();
+        assert!(quiet.line(&info).is_none());
+        assert!(JsonLogger { verbose: true }.line(&info).is_some());
+
+        let rocket = Record::builder()
+            .args(format_args!("GET /health"))
+            .level(log::Level::Info)
+            .target("rocket::server")
+            .module_path(Some("rocket::server"))
+            .build();
+        assert!(JsonLogger { verbose: true }.line(&rocket).is_none());
+    }
+
+    #[test]
+    fn uses_where_rocket_macros_were_called() {
+        let record = Record::builder()
+            .args(format_args!("Deleted 2 expired files"))
+            .level(log::Level::Warn)
+            .target("rocket::log::_")
+            .module_path(Some("rumia::reaper"))
+            .build();
+        assert_eq!(format(&record)["target"], "rumia::reaper");
+    }
+
+    #[test]
+    fn reads_the_last_forwarded_ip() {
+        assert_eq!(forwarded_ip("203.0.113.7"), "203.0.113.7".parse().ok());
+        assert_eq!(
+            forwarded_ip("10.0.0.1, 2001:db8::7"),
+            "2001:db8::7".parse().ok()
+        );
+        assert_eq!(forwarded_ip("unknown"), None);
+    }
+
+    #[test]
+    fn only_accepts_safe_request_ids() {
+        assert!(valid_request_id("5d41402a-bc4b-2a76"));
+        assert!(!valid_request_id(""));
+        assert!(!valid_request_id("line\nbreak"));
+        assert!(!valid_request_id(&"a".repeat(MAX_REQUEST_ID_LEN + 1)));
+    }
+}
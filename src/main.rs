@@ -2,6 +2,6 @@ use rumia::server;
 
 #[rocket::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    server().launch().await?;
+    server()?.launch().await?;
     Ok(())
 }
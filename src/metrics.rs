@@ -0,0 +1,360 @@
+//! Prometheus metrics for requests, storage and remote fetches, served by `GET /metrics`
+
+use crate::{
+    Context,
+    error::{DeleteError, LoadError, SaveError},
+    storage::{Check, FileInfo, InputFile, Metadata, Page, Storage, StoredFile},
+};
+use prometheus_client::{
+    encoding::{EncodeLabelSet, text::encode},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{Histogram, exponential_buckets},
+    },
+    registry::Registry,
+};
+use rocket::{
+    Data, Request, Response,
+    fairing::{Fairing, Info, Kind},
+    http::Status,
+};
+use std::{ops::Range, sync::Arc, time::Instant};
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RequestLabels {
+    route: String,
+    status: u16,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RouteLabels {
+    route: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct StorageLabels {
+    backend: &'static str,
+    operation: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct OutcomeLabels {
+    outcome: &'static str,
+}
+
+type HistogramFamily<L> = Family<L, Histogram, fn() -> Histogram>;
+
+/// Every metric a server records, kept separately for each server
+pub(crate) struct Metrics {
+    registry: Registry,
+    requests: Family<RequestLabels, Counter>,
+    request_duration: HistogramFamily<RouteLabels>,
+    received_bytes: Family<RouteLabels, Counter>,
+    sent_bytes: Family<RouteLabels, Counter>,
+    storage_duration: HistogramFamily<StorageLabels>,
+    remote_fetches: Family<OutcomeLabels, Counter>,
+    remote_bytes: Counter,
+    stored_files: Gauge,
+    stored_bytes: Gauge,
+}
+
+/// Records how long each operation on the wrapped storage takes
+pub(crate) struct MeteredStorage {
+    storage: Box<dyn Storage>,
+    metrics: Arc<Metrics>,
+}
+
+/// Counts requests by route and status, along with the bytes they send and receive
+pub(crate) struct RequestMetrics;
+
+/// When the current request started, kept in its local cache
+struct Started(Instant);
+
+impl Metrics {
+    pub(crate) fn new() -> Self {
+        let mut registry = Registry::with_prefix("rumia");
+
+        let requests = Family::default();
+        registry.register(
+            "requests",
+            "Requests handled, by route and status",
+            requests.clone(),
+        );
+        let request_duration: HistogramFamily<RouteLabels> =
+            Family::new_with_constructor(duration_histogram);
+        registry.register(
+            "request_duration_seconds",
+            "Time taken to respond to requests, by route",
+            request_duration.clone(),
+        );
+        let received_bytes = Family::default();
+        registry.register(
+            "received_bytes",
+            "Bytes received in request bodies, by route",
+            received_bytes.clone(),
+        );
+        let sent_bytes = Family::default();
+        registry.register(
+            "sent_bytes",
+            "Bytes sent in response bodies, by route",
+            sent_bytes.clone(),
+        );
+        let storage_duration: HistogramFamily<StorageLabels> =
+            Family::new_with_constructor(duration_histogram);
+        registry.register(
+            "storage_operation_duration_seconds",
+            "Time taken by storage operations, by backend and operation",
+            storage_duration.clone(),
+        );
+        let remote_fetches = Family::default();
+        registry.register(
+            "remote_fetches",
+            "Remote URL uploads, by outcome",
+            remote_fetches.clone(),
+        );
+        let remote_bytes = Counter::default();
+        registry.register(
+            "remote_fetch_bytes",
+            "Bytes saved from remote URL uploads",
+            remote_bytes.clone(),
+        );
+        let stored_files = Gauge::default();
+        registry.register("stored_files", "Files in storage", stored_files.clone());
+        let stored_bytes = Gauge::default();
+        registry.register(
+            "stored_bytes",
+            "Total size of the files in storage",
+            stored_bytes.clone(),
+        );
+
+        Metrics {
+            registry,
+            requests,
+            request_duration,
+            received_bytes,
+            sent_bytes,
+            storage_duration,
+            remote_fetches,
+            remote_bytes,
+            stored_files,
+            stored_bytes,
+        }
+    }
+
+    /// Every metric in the OpenMetrics text format
+    pub(crate) fn encode(&self) -> Result<String, std::fmt::Error> {
+        let mut text = String::new();
+        encode(&mut text, &self.registry)?;
+        Ok(text)
+    }
+
+    /// Records the outcome of a remote URL upload, and how large the file was if it succeeded
+    pub(crate) fn remote_fetch(&self, result: Result<u64, Status>) {
+        let outcome = match result {
+            Ok(size) => {
+                self.remote_bytes.inc_by(size);
+                "success"
+            }
+            Err(status) => match status.code {
+                403 => "forbidden",
+                413 => "too_large",
+                415 => "unsupported_type",
+                504 => "timeout",
+                _ => "error",
+            },
+        };
+        self.remote_fetches
+            .get_or_create(&OutcomeLabels { outcome })
+            .inc();
+    }
+
+    /// Sets the files in storage to what the reaper counted, correcting any drift from files
+    /// saved or deleted while it was counting
+    pub(crate) fn set_stored(&self, files: u64, bytes: u64) {
+        self.stored_files
+            .set(i64::try_from(files).unwrap_or(i64::MAX));
+        self.stored_bytes
+            .set(i64::try_from(bytes).unwrap_or(i64::MAX));
+    }
+
+    /// Counts a file of `size` bytes in or out of storage
+    fn add_stored(&self, files: i64, size: u64) {
+        let size = i64::try_from(size).unwrap_or(i64::MAX);
+        self.stored_files.inc_by(files);
+        self.stored_bytes.inc_by(files.saturating_mul(size));
+    }
+}
+
+fn duration_histogram() -> Histogram {
+    // 1ms up to about 30s
+    Histogram::new(exponential_buckets(0.001, 2.0, 16))
+}
+
+impl MeteredStorage {
+    pub(crate) fn new(storage: Box<dyn Storage>, metrics: Arc<Metrics>) -> Self {
+        MeteredStorage { storage, metrics }
+    }
+
+    async fn time<T>(&self, operation: &'static str, future: impl Future<Output = T>) -> T {
+        let start = Instant::now();
+        let result = future.await;
+        let labels = StorageLabels {
+            backend: self.storage.name(),
+            operation,
+        };
+        self.metrics
+            .storage_duration
+            .get_or_create(&labels)
+            .observe(start.elapsed().as_secs_f64());
+        result
+    }
+}
+
+#[rocket::async_trait]
+impl Storage for MeteredStorage {
+    fn name(&self) -> &'static str {
+        self.storage.name()
+    }
+
+    async fn save<'r>(&self, file: InputFile<'r>, filename: &str) -> Result<(), SaveError> {
+        self.time("save", self.storage.save(file, filename)).await
+    }
+
+    async fn load(&self, filename: &str) -> Result<StoredFile, LoadError> {
+        self.time("load", self.storage.load(filename)).await
+    }
+
+    async fn stat(&self, filename: &str) -> Result<FileInfo, LoadError> {
+        self.time("stat", self.storage.stat(filename)).await
+    }
+
+    async fn delete(&self, filename: &str) -> Result<(), DeleteError> {
+        self.time("delete", self.storage.delete(filename)).await
+    }
+
+    async fn list(&self, after: Option<&str>, limit: usize) -> Result<Page, LoadError> {
+        self.time("list", self.storage.list(after, limit)).await
+    }
+
+    async fn load_range(&self, filename: &str, range: Range<u64>) -> Result<StoredFile, LoadError> {
+        self.time("load_range", self.storage.load_range(filename, range))
+            .await
+    }
+
+    /// Files are counted once their metadata is saved, as the reaper counts them
+    async fn save_metadata(&self, hash: &str, metadata: &Metadata) -> Result<(), SaveError> {
+        self.time("save_metadata", self.storage.save_metadata(hash, metadata))
+            .await?;
+        self.metrics.add_stored(1, metadata.size);
+        Ok(())
+    }
+
+    async fn load_metadata(&self, hash: &str) -> Result<Metadata, LoadError> {
+        self.time("load_metadata", self.storage.load_metadata(hash))
+            .await
+    }
+
+    async fn delete_metadata(&self, hash: &str) -> Result<(), DeleteError> {
+        let metadata = self
+            .time("load_metadata", self.storage.load_metadata(hash))
+            .await;
+        self.time("delete_metadata", self.storage.delete_metadata(hash))
+            .await?;
+        if let Ok(metadata) = metadata {
+            self.metrics.add_stored(-1, metadata.size);
+        }
+        Ok(())
+    }
+
+    async fn health(&self) -> Vec<Check> {
+        self.time("health", self.storage.health()).await
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for RequestMetrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| Started(Instant::now()));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let Some(context) = request.rocket().state::<Arc<Context>>() else {
+            return;
+        };
+        let metrics = &context.metrics;
+        let route = request
+            .route()
+            .and_then(|route| route.name.as_deref())
+            .unwrap_or("unmatched");
+        let labels = RouteLabels {
+            route: String::from(route),
+        };
+
+        metrics
+            .requests
+            .get_or_create(&RequestLabels {
+                route: String::from(route),
+                status: response.status().code,
+            })
+            .inc();
+        let started = request.local_cache(|| Started(Instant::now()));
+        metrics
+            .request_duration
+            .get_or_create(&labels)
+            .observe(started.0.elapsed().as_secs_f64());
+
+        if let Some(len) = content_length(request.headers().get_one("Content-Length")) {
+            metrics.received_bytes.get_or_create(&labels).inc_by(len);
+        }
+        let sent = response
+            .body()
+            .preset_size()
+            .map(|len| len as u64)
+            .or_else(|| content_length(response.headers().get_one("Content-Length")));
+        if let Some(len) = sent {
+            metrics.sent_bytes.get_or_create(&labels).inc_by(len);
+        }
+    }
+}
+
+fn content_length(header: Option<&str>) -> Option<u64> {
+    header.and_then(|len| len.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::debug::DebugStorage;
+
+    #[rocket::async_test]
+    async fn counts_stored_files() {
+        let metrics = Arc::new(Metrics::new());
+        let storage = MeteredStorage::new(Box::new(DebugStorage::new()), Arc::clone(&metrics));
+
+        let mut input = InputFile::Bytes(b"count me");
+        let metadata = Metadata::describe(&mut input, "counted", "test.txt", "txt", "test")
+            .await
+            .unwrap();
+        storage.save(input, &metadata.file).await.unwrap();
+        storage.save_metadata("counted", &metadata).await.unwrap();
+
+        let text = metrics.encode().unwrap();
+        assert!(text.contains("rumia_stored_files 1\n"), "{text}");
+        assert!(text.contains("rumia_stored_bytes 8\n"), "{text}");
+
+        storage.delete(&metadata.file).await.unwrap();
+        storage.delete_metadata("counted").await.unwrap();
+        let text = metrics.encode().unwrap();
+        assert!(text.contains("rumia_stored_files 0\n"), "{text}");
+        assert!(text.contains("rumia_stored_bytes 0\n"), "{text}");
+    }
+}
@@ -0,0 +1,360 @@
+//! Upload policy, which decides which files may be uploaded from their name, size and the type
+//! detected from their contents
+
+use crate::settings::Settings;
+use infer::MatcherType;
+use rocket::{
+    data::ByteUnit,
+    http::{ContentType, Status},
+};
+use std::str::FromStr;
+
+/// How much of the start of a file is read to detect its type
+pub(crate) const SNIFF_LEN: usize = 8192;
+
+/// Decides which files may be uploaded
+#[derive(Debug)]
+pub(crate) struct UploadPolicy {
+    /// If either list is not empty, only uploads it contains are allowed
+    allow: Rules,
+    /// Always rejected, even if also allowed
+    deny: Rules,
+    /// Lowercase parts of filenames which are always rejected, such as private keys
+    deny_names: Vec<String>,
+    /// Largest upload allowed, unless one of `size_limits` applies
+    max_size: u64,
+    size_limits: Vec<SizeLimit>,
+}
+
+/// The largest upload allowed for an extension or MIME type
+#[derive(Debug)]
+struct SizeLimit {
+    /// Lowercase extension or MIME type, which may end in `/*` to match every subtype
+    rule: String,
+    size: u64,
+}
+
+#[derive(Debug, Default)]
+struct Rules {
+    /// Lowercase MIME types. Those ending in `/*` match every subtype
+    types: Vec<String>,
+    /// Lowercase extensions, without the leading `.`
+    extensions: Vec<String>,
+}
+
+impl UploadPolicy {
+    pub(crate) fn new(
+        allow_types: &[String],
+        deny_types: &[String],
+        allow_extensions: &[String],
+        deny_extensions: &[String],
+    ) -> Self {
+        UploadPolicy {
+            allow: Rules::parse(allow_types, allow_extensions),
+            deny: Rules::parse(deny_types, deny_extensions),
+            deny_names: Vec::new(),
+            max_size: u64::MAX,
+            size_limits: Vec::new(),
+        }
+    }
+
+    pub(crate) fn from_settings(settings: &Settings) -> Result<Self, String> {
+        Ok(UploadPolicy {
+            deny_names: settings
+                .upload_deny_names
+                .iter()
+                .map(|name| name.trim().to_lowercase())
+                .filter(|name| !name.is_empty())
+                .collect(),
+            max_size: settings.upload_max_size,
+            size_limits: settings
+                .upload_size_limits
+                .iter()
+                .map(|limit| limit.parse())
+                .collect::<Result<_, _>>()?,
+            ..UploadPolicy::new(
+                &settings.upload_allow_types,
+                &settings.upload_deny_types,
+                &settings.upload_allow_extensions,
+                &settings.upload_deny_extensions,
+            )
+        })
+    }
+
+    /// Checks whether a file named `filename` with `extension` may be uploaded, before its
+    /// contents are known
+    pub(crate) fn check_name(&self, filename: &str, extension: &str) -> Result<(), Status> {
+        let filename = filename.to_lowercase();
+        let extension = extension.to_lowercase();
+
+        if self.deny_names.iter().any(|name| filename.contains(name)) {
+            return Err(Status::UnsupportedMediaType);
+        }
+
+        let allowed =
+            self.allow.extensions.is_empty() || self.allow.extensions.contains(&extension);
+        if !allowed || self.deny.extensions.contains(&extension) {
+            return Err(Status::UnsupportedMediaType);
+        }
+
+        self.check_type(&claimed_type(&extension))
+    }
+
+    /// Detects the type of a file from its first bytes, `head`, and checks that it matches
+    /// `extension` and is allowed. Returns the content type of the file
+    pub(crate) fn check_content(&self, extension: &str, head: &[u8]) -> Result<String, Status> {
+        let extension = extension.to_lowercase();
+        let claimed = claimed_type(&extension);
+
+        let content_type = match infer::get(head) {
+            // Text is only detected by a guess at its first few bytes, so rather than needing to
+            // match the extension it is just checked against the deny list, eg HTML named `.txt`
+            Some(kind) if kind.matcher_type() == MatcherType::Text => {
+                if self.deny.has_type(kind.mime_type()) {
+                    return Err(Status::UnsupportedMediaType);
+                }
+                claimed
+            }
+            Some(kind) => {
+                if kind.extension() != extension && kind.mime_type() != claimed {
+                    info_!(
+                        "Rejected upload with extension {extension} containing {}",
+                        kind.mime_type()
+                    );
+                    return Err(Status::UnsupportedMediaType);
+                }
+                String::from(kind.mime_type())
+            }
+            None => claimed,
+        };
+
+        self.check_type(&content_type)?;
+        Ok(content_type)
+    }
+
+    /// Checks that a file of `size` bytes is not too large for its `extension` and `content_type`.
+    /// If the content type is not yet known, the one implied by the extension is used
+    pub(crate) fn check_size(
+        &self,
+        extension: &str,
+        content_type: Option<&str>,
+        size: u64,
+    ) -> Result<(), Status> {
+        if size > self.size_limit(extension, content_type) {
+            Err(Status::PayloadTooLarge)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The size limit for a file with `extension` and `content_type`. If the content type is not
+    /// yet known, the one implied by the extension is used
+    pub(crate) fn size_limit(&self, extension: &str, content_type: Option<&str>) -> u64 {
+        let extension = extension.to_lowercase();
+        let content_type = content_type.map_or_else(|| claimed_type(&extension), String::from);
+        self.max_size(&extension, &content_type)
+    }
+
+    /// The largest upload of any type, which request bodies are limited to
+    pub(crate) fn largest_size(&self) -> u64 {
+        self.size_limits
+            .iter()
+            .map(|limit| limit.size)
+            .fold(self.max_size, u64::max)
+    }
+
+    /// Finds the size limit for an upload, preferring one for its extension, then its exact
+    /// MIME type, then its top level type
+    fn max_size(&self, extension: &str, content_type: &str) -> u64 {
+        let top = content_type.split('/').next().unwrap_or_default();
+        let wildcard = format!("{top}/*");
+
+        [extension, content_type, &wildcard]
+            .iter()
+            .find_map(|rule| {
+                self.size_limits
+                    .iter()
+                    .find(|limit| limit.rule == *rule)
+                    .map(|limit| limit.size)
+            })
+            .unwrap_or(self.max_size)
+    }
+
+    fn check_type(&self, content_type: &str) -> Result<(), Status> {
+        let allowed = self.allow.types.is_empty() || self.allow.has_type(content_type);
+        if allowed && !self.deny.has_type(content_type) {
+            Ok(())
+        } else {
+            Err(Status::UnsupportedMediaType)
+        }
+    }
+}
+
+impl Rules {
+    fn parse(types: &[String], extensions: &[String]) -> Self {
+        let normalise = |entry: &String| {
+            let entry = entry.trim().trim_start_matches('.').to_lowercase();
+            (!entry.is_empty()).then_some(entry)
+        };
+
+        Rules {
+            types: types.iter().filter_map(normalise).collect(),
+            extensions: extensions.iter().filter_map(normalise).collect(),
+        }
+    }
+
+    fn has_type(&self, content_type: &str) -> bool {
+        let top = content_type.split('/').next().unwrap_or_default();
+        self.types.iter().any(|rule| {
+            rule == content_type || rule.strip_suffix("/*").is_some_and(|rule| rule == top)
+        })
+    }
+}
+
+impl FromStr for SizeLimit {
+    type Err = String;
+
+    /// Parses `<extension or MIME type>=<size>`, where the size is either bytes or a unit like `10MiB`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (rule, size) = s
+            .split_once('=')
+            .ok_or_else(|| format!("size limit {s} should be <type>=<size>"))?;
+        let size = size.trim();
+        let size = size
+            .parse::<u64>()
+            .ok()
+            .or_else(|| size.parse::<ByteUnit>().ok().map(ByteUnit::as_u64))
+            .ok_or_else(|| format!("invalid size in size limit {s}"))?;
+
+        Ok(SizeLimit {
+            rule: rule.trim().trim_start_matches('.').to_lowercase(),
+            size,
+        })
+    }
+}
+
+/// The MIME type implied by `extension`, without any parameters
+fn claimed_type(extension: &str) -> String {
+    let content_type = ContentType::from_extension(extension).unwrap_or(ContentType::Binary);
+    format!("{}/{}", content_type.top(), content_type.sub()).to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+    const EXE: &[u8] = b"MZ\x90\0\x03\0\0\0\x04\0\0\0\xff\xff\0\0";
+
+    fn list(entries: &[&str]) -> Vec<String> {
+        entries.iter().map(|entry| String::from(*entry)).collect()
+    }
+
+    #[test]
+    fn rejects_mismatched_content() {
+        let policy = UploadPolicy::new(&[], &[], &[], &[]);
+
+        assert_eq!(
+            policy.check_content("png", PNG),
+            Ok(String::from("image/png"))
+        );
+        assert_eq!(
+            policy.check_content("PNG", PNG),
+            Ok(String::from("image/png"))
+        );
+        assert_eq!(
+            policy.check_content("png", EXE),
+            Err(Status::UnsupportedMediaType)
+        );
+        assert_eq!(
+            policy.check_content("jpg", PNG),
+            Err(Status::UnsupportedMediaType)
+        );
+        assert_eq!(
+            policy.check_content("txt", b"hello world"),
+            Ok(String::from("text/plain"))
+        );
+    }
+
+    #[test]
+    fn denies_types_and_extensions() {
+        let policy = UploadPolicy::new(&[], &list(&["text/html"]), &[], &list(&[".exe"]));
+
+        assert_eq!(
+            policy.check_name("test.EXE", "EXE"),
+            Err(Status::UnsupportedMediaType)
+        );
+        assert_eq!(
+            policy.check_name("test.htm", "htm"),
+            Err(Status::UnsupportedMediaType)
+        );
+        assert_eq!(
+            policy.check_content("txt", b"<!DOCTYPE html><html></html>"),
+            Err(Status::UnsupportedMediaType)
+        );
+        assert_eq!(policy.check_name("test.png", "png"), Ok(()));
+    }
+
+    #[test]
+    fn allows_only_listed() {
+        let policy = UploadPolicy::new(&list(&["image/*"]), &list(&["image/gif"]), &[], &[]);
+
+        assert_eq!(policy.check_name("test.png", "png"), Ok(()));
+        assert_eq!(
+            policy.check_name("test.gif", "gif"),
+            Err(Status::UnsupportedMediaType)
+        );
+        assert_eq!(
+            policy.check_name("test.zip", "zip"),
+            Err(Status::UnsupportedMediaType)
+        );
+
+        let policy = UploadPolicy::new(&[], &[], &list(&["png"]), &[]);
+        assert_eq!(policy.check_name("test.png", "png"), Ok(()));
+        assert_eq!(
+            policy.check_name("test.jpg", "jpg"),
+            Err(Status::UnsupportedMediaType)
+        );
+    }
+
+    #[test]
+    fn denies_names() {
+        let policy = UploadPolicy {
+            deny_names: list(&["_rsa"]),
+            ..UploadPolicy::new(&[], &[], &[], &[])
+        };
+
+        assert_eq!(
+            policy.check_name("ID_RSA.pub", "pub"),
+            Err(Status::UnsupportedMediaType)
+        );
+        assert_eq!(policy.check_name("id.txt", "txt"), Ok(()));
+    }
+
+    #[test]
+    fn limits_sizes_by_type() {
+        let policy = UploadPolicy {
+            max_size: 100,
+            size_limits: ["image/*=10", "image/png=1kB", ".zip = 2GiB"]
+                .iter()
+                .map(|limit| limit.parse().unwrap())
+                .collect(),
+            ..UploadPolicy::new(&[], &[], &[], &[])
+        };
+
+        assert_eq!(policy.check_size("txt", None, 100), Ok(()));
+        assert_eq!(
+            policy.check_size("txt", None, 101),
+            Err(Status::PayloadTooLarge)
+        );
+        assert_eq!(
+            policy.check_size("jpg", Some("image/jpeg"), 11),
+            Err(Status::PayloadTooLarge)
+        );
+        assert_eq!(policy.check_size("png", None, 1000), Ok(()));
+        assert_eq!(policy.check_size("ZIP", None, 2 << 30), Ok(()));
+        assert_eq!(policy.largest_size(), 2 << 30);
+        assert!("zip".parse::<SizeLimit>().is_err());
+        assert!("zip=lots".parse::<SizeLimit>().is_err());
+    }
+}
@@ -0,0 +1,190 @@
+//! Deletes uploads once they have expired, along with stale resumable uploads
+
+use crate::{
+    Context,
+    audit::{Audit, Entry},
+    error::LoadError,
+    routes,
+    storage::{Metadata, Storage},
+};
+use opentelemetry::{context::FutureExt, trace::SpanKind};
+use rocket::fairing::AdHoc;
+use std::{sync::Arc, time::Duration};
+use tokio::time::{Instant, interval_at};
+
+const PAGE_SIZE: usize = 1000;
+
+/// Spawns the reaper once the server has launched
+pub(crate) fn fairing() -> AdHoc {
+    AdHoc::on_liftoff("Reaper", |rocket| {
+        let context = rocket.state::<Arc<Context>>().map(Arc::clone);
+        Box::pin(async move {
+            let Some(context) = context else {
+                return;
+            };
+            let interval = context.settings.reap_interval;
+            let period = Duration::from_secs(interval);
+
+            tokio::spawn(async move {
+                // Count the files already in storage, rather than waiting for the first pass
+                match sweep(&*context.storage, &context.audit, Mode::Count).await {
+                    Ok(sweep) => context.metrics.set_stored(sweep.files, sweep.bytes),
+                    Err(e) => warn_!("Unable to list files to count: {e}"),
+                }
+                if interval == 0 {
+                    return;
+                }
+
+                let mut interval = interval_at(Instant::now() + period, period);
+                loop {
+                    interval.tick().await;
+                    let span = context
+                        .telemetry
+                        .span("reap", SpanKind::Internal, Vec::new());
+                    match reap(&*context.storage, &context.audit)
+                        .with_context(span)
+                        .await
+                    {
+                        Ok(sweep) => {
+                            context.metrics.set_stored(sweep.files, sweep.bytes);
+                            if sweep.reaped > 0 {
+                                info_!("Deleted {} expired files", sweep.reaped);
+                            }
+                        }
+                        Err(e) => warn_!("Unable to list files to reap: {e}"),
+                    }
+
+                    let expired = routes::expire_uploads(&context).await;
+                    if expired > 0 {
+                        info_!("Deleted {expired} stale resumable uploads");
+                    }
+                }
+            });
+        })
+    })
+}
+
+/// What the reaper found while looking through storage
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Sweep {
+    /// Expired files which were deleted
+    pub(crate) reaped: usize,
+    /// Files with metadata which are left, and their total size
+    pub(crate) files: u64,
+    pub(crate) bytes: u64,
+}
+
+/// Whether a sweep deletes the expired files it finds, or only counts them
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    Count,
+    Reap,
+}
+
+/// Deletes every expired file in `storage` along with its metadata, counting the files left behind
+/// and recording each deletion in `audit`
+pub(crate) async fn reap(storage: &dyn Storage, audit: &Audit) -> Result<Sweep, LoadError> {
+    sweep(storage, audit, Mode::Reap).await
+}
+
+/// Counts the files in `storage`, deleting the expired ones as [`reap`] does in [`Mode::Reap`]
+async fn sweep(storage: &dyn Storage, audit: &Audit, mode: Mode) -> Result<Sweep, LoadError> {
+    let mut sweep = Sweep::default();
+    let mut after = None;
+
+    loop {
+        let page = storage.list(after.as_deref(), PAGE_SIZE).await?;
+
+        for hash in Metadata::hashes(&page.names) {
+            let Ok(metadata) = storage.load_metadata(hash).await else {
+                continue;
+            };
+
+            if mode == Mode::Count || !metadata.is_expired() {
+                sweep.files += 1;
+                sweep.bytes += metadata.size;
+                continue;
+            }
+
+            // The file may already have been deleted, leaving only its metadata behind
+            storage.delete(&metadata.file).await.ok();
+            if storage.delete_metadata(hash).await.is_ok() {
+                sweep.reaped += 1;
+                audit.write(&Entry::expired(hash, metadata.size), "expired", None);
+            }
+        }
+
+        match page.next {
+            Some(next) => after = Some(next),
+            None => return Ok(sweep),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{InputFile, debug::DebugStorage};
+    use time::{Duration, OffsetDateTime};
+
+    async fn upload(storage: &dyn Storage, hash: &str, expires: OffsetDateTime) {
+        let mut input = InputFile::Bytes(b"reap me");
+        let metadata = Metadata::describe(&mut input, hash, "test.txt", "txt", "test")
+            .await
+            .unwrap();
+        storage.save(input, &metadata.file).await.unwrap();
+        storage
+            .save_metadata(
+                hash,
+                &Metadata {
+                    expires: Some(expires),
+                    ..metadata
+                },
+            )
+            .await
+            .unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn reaps_expired_files() {
+        let storage = DebugStorage::new();
+        let now = OffsetDateTime::now_utc();
+        upload(&storage, "expired", now - Duration::minutes(1)).await;
+        upload(&storage, "fresh", now + Duration::hours(1)).await;
+
+        let sweep = reap(&storage, &Audit::default()).await.unwrap();
+        assert_eq!(
+            sweep,
+            Sweep {
+                reaped: 1,
+                files: 1,
+                bytes: 7
+            }
+        );
+        assert!(storage.stat("expired.txt").await.is_err());
+        assert!(storage.load_metadata("expired").await.is_err());
+        assert!(storage.stat("fresh.txt").await.is_ok());
+        assert_eq!(reap(&storage, &Audit::default()).await.unwrap().reaped, 0);
+    }
+
+    #[rocket::async_test]
+    async fn counts_without_reaping() {
+        let storage = DebugStorage::new();
+        let now = OffsetDateTime::now_utc();
+        upload(&storage, "expired", now - Duration::minutes(1)).await;
+        upload(&storage, "fresh", now + Duration::hours(1)).await;
+
+        let sweep = sweep(&storage, &Audit::default(), Mode::Count)
+            .await
+            .unwrap();
+        assert_eq!(
+            sweep,
+            Sweep {
+                reaped: 0,
+                files: 2,
+                bytes: 14
+            }
+        );
+        assert!(storage.load_metadata("expired").await.is_ok());
+    }
+}
@@ -0,0 +1,284 @@
+//! Streams remote URL uploads into storage, enforcing size and time limits
+
+use crate::{settings::Settings, storage::InputFile};
+use reqwest::Response;
+use rocket::{
+    futures::{Stream, StreamExt, stream},
+    http::Status,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    io,
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::oneshot,
+    time::{Instant, timeout_at},
+};
+use tokio_util::io::StreamReader;
+
+/// Limits applied to a remote download
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Limits {
+    pub(crate) max_size: u64,
+    /// When the whole download must have finished by
+    pub(crate) deadline: Instant,
+    /// The longest to wait for the next chunk
+    pub(crate) idle_timeout: Duration,
+}
+
+impl Limits {
+    /// Limits from the settings, for a download starting now
+    pub(crate) fn from_settings(settings: &Settings) -> Self {
+        Limits {
+            max_size: settings.remote_max_size,
+            deadline: Instant::now() + Duration::from_secs(settings.remote_timeout),
+            idle_timeout: Duration::from_secs(settings.remote_idle_timeout),
+        }
+    }
+}
+
+/// How much of a remote download has been received so far
+#[derive(Debug, Default)]
+pub(crate) struct Progress {
+    received: AtomicU64,
+    /// The length the upstream server reported, if any
+    total: OnceLock<u64>,
+}
+
+impl Progress {
+    pub(crate) fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn total(&self) -> Option<u64> {
+        self.total.get().copied()
+    }
+}
+
+/// The outcome of a remote download, available once it has been saved
+pub(crate) struct Transfer {
+    result: oneshot::Receiver<Result<(u64, String), Status>>,
+    /// The most which may be downloaded, which can be lowered once the type of the file is known
+    max_size: Arc<AtomicU64>,
+}
+
+struct State<S> {
+    stream: S,
+    limits: Limits,
+    max_size: Arc<AtomicU64>,
+    len: u64,
+    hasher: Sha256,
+    progress: Arc<Progress>,
+    result: oneshot::Sender<Result<(u64, String), Status>>,
+}
+
+/// Wraps the body of `response` as an [`InputFile::Stream`], which is digested and recorded in
+/// `progress` as it is read, and fails once it breaks any of the `limits`
+pub(crate) fn stream(
+    response: Response,
+    limits: Limits,
+    progress: Arc<Progress>,
+) -> Result<(InputFile<'static>, Transfer), Status> {
+    if let Some(len) = response.content_length() {
+        if len > limits.max_size {
+            return Err(Status::PayloadTooLarge);
+        }
+        progress.total.set(len).ok();
+    }
+
+    let (sender, receiver) = oneshot::channel();
+    let max_size = Arc::new(AtomicU64::new(limits.max_size));
+    let state = State {
+        stream: response.bytes_stream(),
+        limits,
+        max_size: Arc::clone(&max_size),
+        len: 0,
+        hasher: Sha256::new(),
+        progress,
+        result: sender,
+    };
+
+    let reader = StreamReader::new(stream::unfold(Some(state), next_chunk).boxed());
+    let transfer = Transfer {
+        result: receiver,
+        max_size,
+    };
+    Ok((InputFile::Stream(Box::pin(reader)), transfer))
+}
+
+async fn next_chunk<S, B>(state: Option<State<S>>) -> Option<(io::Result<B>, Option<State<S>>)>
+where
+    S: Stream<Item = reqwest::Result<B>> + Unpin,
+    B: AsRef<[u8]>,
+{
+    let mut state = state?;
+    let limits = state.limits;
+    let idle_deadline = (Instant::now() + limits.idle_timeout).min(limits.deadline);
+
+    let next = timeout_at(idle_deadline, state.stream.next()).await;
+    let max_size = state.max_size.load(Ordering::Relaxed);
+    let failure = match next {
+        Ok(Some(Ok(chunk))) => {
+            state.len += chunk.as_ref().len() as u64;
+            if state.len > max_size {
+                Status::PayloadTooLarge
+            } else {
+                state.hasher.update(chunk.as_ref());
+                state.progress.received.store(state.len, Ordering::Relaxed);
+                return Some((Ok(chunk), Some(state)));
+            }
+        }
+        // The limit may have been lowered after the last chunk was read
+        Ok(None) if state.len > max_size => Status::PayloadTooLarge,
+        Ok(None) => {
+            let digest = hex::encode(state.hasher.finalize());
+            state.result.send(Ok((state.len, digest))).ok();
+            return None;
+        }
+        Ok(Some(Err(_))) => Status::FailedDependency,
+        Err(_) => Status::GatewayTimeout,
+    };
+
+    state.result.send(Err(failure)).ok();
+    Some((Err(io::Error::other(failure.to_string())), None))
+}
+
+impl Transfer {
+    /// Lowers the size limit to `max_size`, eg once the type of the file is known, failing the
+    /// rest of the download if it is larger
+    pub(crate) fn restrict(&self, max_size: u64) {
+        self.max_size.fetch_min(max_size, Ordering::Relaxed);
+    }
+
+    /// Returns the length and SHA-256 digest of the download, or why it failed.
+    ///
+    /// If the download was never finished, the storage backend must have failed
+    pub(crate) async fn finish(self) -> Result<(u64, String), Status> {
+        self.result
+            .await
+            .unwrap_or(Err(Status::InternalServerError))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Storage, debug::DebugStorage};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    /// Spawns a server which answers once with `body`, optionally with a Content-Length, then
+    /// stalls rather than closing the connection if `stall` is set
+    async fn serve(body: &'static [u8], content_length: bool, stall: bool) -> Response {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0; 1024];
+            let _ = stream.read(&mut buffer).await;
+
+            let length = if content_length {
+                format!("Content-Length: {}\r\n", body.len())
+            } else {
+                String::from("Connection: close\r\n")
+            };
+            let head = format!("HTTP/1.1 200 OK\r\n{length}\r\n");
+            stream.write_all(head.as_bytes()).await.unwrap();
+            stream.write_all(body).await.unwrap();
+
+            if stall {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+            }
+        });
+
+        reqwest::get(format!("http://{addr}/")).await.unwrap()
+    }
+
+    fn limits(max_size: u64) -> Limits {
+        Limits {
+            max_size,
+            deadline: Instant::now() + Duration::from_secs(5),
+            idle_timeout: Duration::from_millis(200),
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_and_digests() {
+        let storage = DebugStorage::new();
+        let progress = Arc::new(Progress::default());
+        let (input, transfer) = stream(
+            serve(b"hello world", true, false).await,
+            limits(100),
+            Arc::clone(&progress),
+        )
+        .unwrap();
+
+        storage.save(input, "hello.txt").await.unwrap();
+        let (len, digest) = transfer.finish().await.unwrap();
+
+        assert_eq!(len, 11);
+        assert_eq!(digest, hex::encode(Sha256::digest(b"hello world")));
+        assert_eq!(storage.stat("hello.txt").await.unwrap().len, 11);
+        assert_eq!(progress.received(), 11);
+        assert_eq!(progress.total(), Some(11));
+    }
+
+    #[tokio::test]
+    async fn rejects_large_downloads() {
+        let result = stream(
+            serve(b"hello world", true, false).await,
+            limits(5),
+            Arc::default(),
+        );
+        assert_eq!(result.err(), Some(Status::PayloadTooLarge));
+
+        let storage = DebugStorage::new();
+        let (input, transfer) = stream(
+            serve(b"hello world", false, false).await,
+            limits(5),
+            Arc::default(),
+        )
+        .unwrap();
+
+        assert!(storage.save(input, "hello.txt").await.is_err());
+        assert_eq!(transfer.finish().await, Err(Status::PayloadTooLarge));
+    }
+
+    #[tokio::test]
+    async fn applies_lowered_limits() {
+        let storage = DebugStorage::new();
+        let (mut input, transfer) = stream(
+            serve(b"hello world", false, false).await,
+            limits(100),
+            Arc::default(),
+        )
+        .unwrap();
+
+        assert_eq!(input.head(5).await.unwrap(), b"hello");
+        transfer.restrict(8);
+        assert!(storage.save(input, "hello.txt").await.is_err());
+        assert_eq!(transfer.finish().await, Err(Status::PayloadTooLarge));
+    }
+
+    #[tokio::test]
+    async fn times_out_idle_downloads() {
+        let storage = DebugStorage::new();
+        let (input, transfer) = stream(
+            serve(b"hello", false, true).await,
+            limits(100),
+            Arc::default(),
+        )
+        .unwrap();
+
+        assert!(storage.save(input, "hello.txt").await.is_err());
+        assert_eq!(transfer.finish().await, Err(Status::GatewayTimeout));
+    }
+}
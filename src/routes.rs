@@ -1,70 +1,146 @@
-use crate::{SETTINGS, STORAGE, error::ApiKeyError, storage::InputFile};
+use crate::{
+    Context,
+    audit::{Event, Trail},
+    auth::{DeletionToken, Principal, Scope, Signer},
+    download::{Download, Plan, Preconditions},
+    error::ApiKeyError,
+    policy::{SNIFF_LEN, UploadPolicy},
+    remote::{self, Limits, Progress},
+    storage::{InputFile, Metadata, Storage},
+    telemetry::record,
+};
+use jobs::{Accepted, RespondAsync};
+use opentelemetry::{KeyValue, context::FutureExt, trace::SpanKind};
 use rocket::{
-    Request,
+    Request, State,
     form::{Form, Strict},
-    fs::{NamedFile, TempFile},
-    http::Status,
+    fs::TempFile,
+    http::{ContentType, Header, Status},
     outcome::Outcome,
     request::FromRequest,
 };
-use std::{borrow::Cow, path::Path, str::FromStr};
+use std::{borrow::Cow, convert::Infallible, path::Path, str::FromStr, sync::Arc};
+use time::{Duration, OffsetDateTime, format_description::well_known::Rfc3339};
+use tokio::time::timeout_at;
 use url::Url;
 use uuid::Uuid;
 
-#[derive(PartialEq)]
-pub(crate) struct ApiKey<'r>(&'r str);
+mod files;
+mod jobs;
+mod tus;
+
+pub(crate) use files::{file_metadata, list_files};
+pub(crate) use jobs::{Jobs, job_status};
+pub(crate) use tus::{
+    UploadLocks, expire_uploads, tus_create, tus_head, tus_options, tus_patch, tus_terminate,
+};
 
 #[derive(FromForm)]
 pub(crate) struct Upload<'r> {
     file: TempFile<'r>,
     filename: String,
+    /// Seconds until the file expires
+    ttl: Option<u64>,
+    /// RFC 3339 timestamp the file expires at
+    expires: Option<String>,
 }
 
-const BLACKLISTED_EXT: [&str; 6] = ["exe", "dll", "html", "css", "php", "pub"];
-const BLACKLISTED_NAME: [&str; 2] = ["_rsa", "_ed25519"];
+/// The URL of a newly uploaded file, along with the token allowing it to be deleted
+#[derive(Responder)]
+pub(crate) struct Uploaded {
+    url: String,
+    deletion_token: Header<'static>,
+}
 
-#[rocket::async_trait]
-impl<'r> FromRequest<'r> for ApiKey<'r> {
-    type Error = ApiKeyError;
+/// Where the routes can be reached, ie the `url` setting followed by the prefix they are mounted
+/// under, without a trailing `/`
+pub(crate) struct BaseUrl(String);
 
-    async fn from_request(
-        request: &'r Request<'_>,
-    ) -> Outcome<ApiKey<'r>, (Status, ApiKeyError), Status> {
-        fn is_valid(key: &str) -> bool {
-            !key.is_empty()
-        }
+/// A file to download from a remote URL into storage
+pub(crate) struct RemoteUpload {
+    url: Url,
+    name: String,
+    extension: String,
+    uploader: String,
+    expires: Option<OffsetDateTime>,
+}
 
-        match request.headers().get_one("x-api-key") {
-            None => Outcome::Error((Status::Unauthorized, ApiKeyError::Missing)),
-            Some(key) if is_valid(key) => Outcome::Success(ApiKey(key)),
-            Some(_) => Outcome::Error((Status::BadRequest, ApiKeyError::Invalid)),
-        }
-    }
+/// A remote URL upload, either finished or left running in the background
+#[derive(Responder)]
+pub(crate) enum UrlUpload {
+    Finished(Uploaded),
+    Queued(Accepted),
 }
 
+/// How long signed download URLs last for when no expiry is requested, in seconds
+const DEFAULT_SIGNED_TTL: i64 = 60 * 60;
+
 #[post("/api/upload/file", data = "<upload>")]
 pub(crate) async fn upload_file(
-    key: ApiKey<'_>,
-    mut upload: Form<Strict<Upload<'_>>>,
-) -> Result<String, Status> {
-    validate_key(key)?;
+    context: &State<Arc<Context>>,
+    base: BaseUrl,
+    principal: Principal,
+    trail: Trail,
+    upload: Form<Strict<Upload<'_>>>,
+) -> Result<Uploaded, Status> {
+    principal.require(Scope::Upload)?;
+    trail.begin(Event::Upload, Some(&principal.name));
+    let mut upload = upload.into_inner().into_inner();
 
-    let (filename, extension) = validate_file(&upload.filename)?;
+    let (filename, extension) = validate_file(&context.upload_policy, &upload.filename)?;
+    let expires = validate_expiry(upload.ttl, upload.expires.as_deref())?;
     let hash = Uuid::new_v4().to_string();
     let save_name = format!("{hash}.{extension}");
-    let return_url = format!("{}/attachment/{hash}/{filename}", SETTINGS.url);
+    let return_url = format!("{}/attachment/{hash}/{filename}", base.0);
 
-    STORAGE
-        .save(InputFile::TempFile(&mut upload.file), &save_name)
+    let size = upload.file.len();
+    trail.file(&hash, Some(size));
+    let mut input = InputFile::TempFile(&mut upload.file);
+    let head = input
+        .head(SNIFF_LEN)
         .await
         .map_err(|_| Status::InternalServerError)?;
+    let content_type = context.upload_policy.check_content(&extension, &head)?;
+    context
+        .upload_policy
+        .check_size(&extension, Some(&content_type), size)?;
+    let metadata = Metadata::describe(&mut input, &hash, &filename, &extension, &principal.name)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+
+    context
+        .storage
+        .save(input, &save_name)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    save_metadata(
+        &*context.storage,
+        Metadata {
+            content_type,
+            expires,
+            ..metadata
+        },
+    )
+    .await?;
 
-    Ok(return_url)
+    Ok(Uploaded::new(&context.signer, return_url, &hash))
 }
 
-#[post("/api/upload/<url>")]
-pub(crate) async fn upload_file_url(key: ApiKey<'_>, url: &str) -> Result<String, Status> {
-    validate_key(key)?;
+#[allow(clippy::too_many_arguments)]
+#[post("/api/upload/<url>?<ttl>&<expires>")]
+pub(crate) async fn upload_file_url(
+    context: &State<Arc<Context>>,
+    base: BaseUrl,
+    principal: Principal,
+    trail: Trail,
+    respond_async: RespondAsync,
+    url: &str,
+    ttl: Option<u64>,
+    expires: Option<&str>,
+) -> Result<UrlUpload, Status> {
+    principal.require(Scope::UploadUrl)?;
+    trail.begin(Event::Upload, Some(&principal.name));
+    let expires = validate_expiry(ttl, expires)?;
 
     let url = Url::parse(url).map_err(|_| Status::BadRequest)?;
 
@@ -73,52 +149,216 @@ pub(crate) async fn upload_file_url(key: ApiKey<'_>, url: &str) -> Result<String
         .ok_or(Status::BadRequest)?
         .to_string_lossy();
 
-    let (_, extension) = validate_file(&filename)?;
-    let hash = Uuid::new_v4().to_string();
-    let save_name = format!("{hash}.{extension}");
+    let (name, extension) = validate_file(&context.upload_policy, &filename)?;
+    let upload = RemoteUpload {
+        name: name.into_owned(),
+        extension: extension.into_owned(),
+        uploader: principal.name,
+        url,
+        expires,
+    };
 
-    #[allow(clippy::unwrap_used)]
-    let resp = reqwest::get(url.clone())
-        .await
-        .map_err(|_| Status::BadGateway)?
-        .error_for_status()
-        .map_err(|error| {
-            let status = error.status().unwrap();
-            Status::from_code(status.as_u16()).unwrap()
-        })?;
+    if respond_async.0 {
+        let context = Arc::clone(context.inner());
+        return jobs::submit(context, base, upload, trail).map(UrlUpload::Queued);
+    }
+
+    let hash = fetch_remote(context, &upload, &trail, Arc::default()).await?;
 
-    let bytes = resp.bytes().await.map_err(|_| Status::FailedDependency)?;
+    Ok(UrlUpload::Finished(Uploaded::new(
+        &context.signer,
+        format!("{}/attachment/{hash}/{}", base.0, upload.name),
+        &hash,
+    )))
+}
+
+#[get("/attachment/<hash>/<filename>?<expires>&<signature>")]
+pub(crate) async fn get_file(
+    context: &State<Arc<Context>>,
+    hash: &str,
+    filename: &str,
+    expires: Option<i64>,
+    signature: Option<&str>,
+    preconditions: Preconditions<'_>,
+) -> Result<Download, Status> {
+    let (_, extension) = validate_file(&context.upload_policy, filename)?;
+    let hash = validate_hash(hash)?;
+    let filename = format!("{hash}.{extension}");
 
-    STORAGE
-        .save(InputFile::Bytes(&bytes), &save_name)
+    if context.settings.private {
+        let (Some(expires), Some(signature)) = (expires, signature) else {
+            return Err(Status::Unauthorized);
+        };
+
+        if !context
+            .signer
+            .verify_download(&filename, expires, signature)
+        {
+            return Err(Status::Forbidden);
+        }
+    }
+
+    if context
+        .storage
+        .load_metadata(&hash)
         .await
-        .map_err(|_| Status::InternalServerError)?;
+        .is_ok_and(|metadata| metadata.is_expired())
+    {
+        return Err(Status::Gone);
+    }
+
+    let info = context
+        .storage
+        .stat(&filename)
+        .await
+        .map_err(|_| Status::NotFound)?;
 
-    Ok(format!("{}/attachment/{hash}/{filename}", SETTINGS.url))
+    Ok(match preconditions.evaluate(&info) {
+        Plan::Full => {
+            let file = context
+                .storage
+                .load(&filename)
+                .await
+                .map_err(|_| Status::NotFound)?;
+            Download::Full(info, file)
+        }
+        Plan::Partial(range) => {
+            let file = context
+                .storage
+                .load_range(&filename, range.clone())
+                .await
+                .map_err(|_| Status::NotFound)?;
+            Download::Partial(info, file, range)
+        }
+        Plan::NotModified => Download::NotModified(info),
+        Plan::Unsatisfiable => Download::Unsatisfiable(info),
+    })
 }
 
-#[get("/attachment/<hash>/<filename>")]
-pub(crate) async fn get_file(hash: &str, filename: &str) -> Result<NamedFile, Status> {
-    let (_, extension) = validate_file(filename)?;
+#[post("/api/sign/<hash>/<filename>?<ttl>&<expires>")]
+pub(crate) async fn sign_file(
+    context: &State<Arc<Context>>,
+    base: BaseUrl,
+    principal: Principal,
+    hash: &str,
+    filename: &str,
+    ttl: Option<u64>,
+    expires: Option<&str>,
+) -> Result<String, Status> {
+    principal.require(Scope::Sign)?;
+    // URLs signed with a random secret would stop working on the next restart
+    if !context.signer.is_configured() {
+        return Err(Status::ServiceUnavailable);
+    }
+
+    let (filename, extension) = validate_file(&context.upload_policy, filename)?;
     let hash = validate_hash(hash)?;
-    let filename = format!("{hash}.{extension}");
+    let save_name = format!("{hash}.{extension}");
+    let expires = validate_expiry(ttl, expires)?
+        .unwrap_or_else(|| OffsetDateTime::now_utc() + Duration::seconds(DEFAULT_SIGNED_TTL))
+        .unix_timestamp();
+
+    context
+        .storage
+        .stat(&save_name)
+        .await
+        .map_err(|_| Status::NotFound)?;
 
-    STORAGE.load(&filename).await.map_err(|_| Status::NotFound)
+    let signature = context.signer.download_signature(&save_name, expires);
+    Ok(format!(
+        "{}/attachment/{hash}/{filename}?expires={expires}&signature={signature}",
+        base.0
+    ))
 }
 
 #[delete("/attachment/<hash>/<filename>")]
-pub async fn delete_file(key: ApiKey<'_>, hash: &str, filename: &str) -> Result<(), Status> {
-    validate_key(key)?;
-    let (_, extension) = validate_file(filename)?;
+pub async fn delete_file(
+    context: &State<Arc<Context>>,
+    principal: Result<Principal, ApiKeyError>,
+    token: Option<DeletionToken<'_>>,
+    trail: Trail,
+    hash: &str,
+    filename: &str,
+) -> Result<(), Status> {
+    let token_allowed = token.is_some_and(|token| {
+        validate_hash(hash).is_ok_and(|hash| token.allows(&context.signer, &hash))
+    });
+
+    let key = match principal {
+        Ok(principal) if token_allowed => Some(principal.name),
+        Ok(principal) => {
+            principal.require(Scope::Delete)?;
+            Some(principal.name)
+        }
+        Err(_) if token_allowed => None,
+        Err(error) => return Err(error.status()),
+    };
+    trail.begin(Event::Delete, key.as_deref());
+
+    let (_, extension) = validate_file(&context.upload_policy, filename)?;
     let hash = validate_hash(hash)?;
     let filename = format!("{hash}.{extension}");
-    STORAGE
+    let size = context
+        .storage
+        .load_metadata(&hash)
+        .await
+        .ok()
+        .map(|metadata| metadata.size);
+    trail.file(&hash, size);
+    context
+        .storage
         .delete(&filename)
         .await
-        .map_err(|_| Status::NotFound)
+        .map_err(|_| Status::NotFound)?;
+    context.storage.delete_metadata(&hash).await.ok();
+    Ok(())
+}
+
+#[get("/metrics")]
+pub(crate) fn get_metrics(context: &State<Arc<Context>>) -> Result<(ContentType, String), Status> {
+    let text = context
+        .metrics
+        .encode()
+        .map_err(|_| Status::InternalServerError)?;
+    let content_type = ContentType::new("application", "openmetrics-text")
+        .with_params([("version", "1.0.0"), ("charset", "utf-8")]);
+    Ok((content_type, text))
 }
 
-fn validate_file(filename: &str) -> Result<(Cow<'_, str>, Cow<'_, str>), Status> {
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for BaseUrl {
+    type Error = Infallible;
+
+    async fn from_request(
+        request: &'r Request<'_>,
+    ) -> Outcome<BaseUrl, (Status, Infallible), Status> {
+        let url = request
+            .rocket()
+            .state::<Arc<Context>>()
+            .map_or("", |context| context.settings.url.as_str());
+        let prefix = request.route().map_or("/", |route| route.uri.base());
+
+        Outcome::Success(BaseUrl(format!(
+            "{}{}",
+            url.trim_end_matches('/'),
+            prefix.trim_end_matches('/')
+        )))
+    }
+}
+
+impl Uploaded {
+    pub(crate) fn new(signer: &Signer, url: String, hash: &str) -> Self {
+        Uploaded {
+            url,
+            deletion_token: Header::new("X-Deletion-Token", signer.deletion_token(hash)),
+        }
+    }
+}
+
+fn validate_file<'a>(
+    policy: &UploadPolicy,
+    filename: &'a str,
+) -> Result<(Cow<'a, str>, Cow<'a, str>), Status> {
     let path = Path::new(filename);
 
     let filename = path
@@ -131,13 +371,9 @@ fn validate_file(filename: &str) -> Result<(Cow<'_, str>, Cow<'_, str>), Status>
         .ok_or(Status::BadRequest)?
         .to_string_lossy();
 
-    if BLACKLISTED_EXT.iter().any(|ext| *ext == extension)
-        || BLACKLISTED_NAME.iter().any(|name| filename.contains(name))
-    {
-        Err(Status::UnsupportedMediaType)
-    } else {
-        Ok((filename, extension))
-    }
+    policy.check_name(&filename, &extension)?;
+
+    Ok((filename, extension))
 }
 
 fn validate_hash<T: AsRef<str>>(hash: T) -> Result<String, Status> {
@@ -146,10 +382,153 @@ fn validate_hash<T: AsRef<str>>(hash: T) -> Result<String, Status> {
         .to_string())
 }
 
-fn validate_key(provided: ApiKey<'_>) -> Result<(), Status> {
-    if provided.0 == SETTINGS.api_key {
-        Ok(())
-    } else {
-        Err(Status::Unauthorized)
+/// Works out when an upload expires from either a `ttl` in seconds, or an RFC 3339 `expires` timestamp
+fn validate_expiry(
+    ttl: Option<u64>,
+    expires: Option<&str>,
+) -> Result<Option<OffsetDateTime>, Status> {
+    let now = OffsetDateTime::now_utc();
+    let expires = match (ttl, expires) {
+        (None, None) => return Ok(None),
+        (Some(_), Some(_)) => return Err(Status::BadRequest),
+        (Some(ttl), None) => i64::try_from(ttl)
+            .ok()
+            .and_then(|ttl| now.checked_add(Duration::seconds(ttl)))
+            .ok_or(Status::BadRequest)?,
+        (None, Some(expires)) => {
+            OffsetDateTime::parse(expires, &Rfc3339).map_err(|_| Status::BadRequest)?
+        }
+    };
+
+    if expires <= now {
+        return Err(Status::BadRequest);
     }
+
+    Ok(Some(expires))
+}
+
+/// Downloads `upload` into storage, recording its progress in `progress` and the file in
+/// `trail`. Returns the hash it was saved under
+async fn fetch_remote(
+    context: &Context,
+    upload: &RemoteUpload,
+    trail: &Trail,
+    progress: Arc<Progress>,
+) -> Result<String, Status> {
+    let span = context.telemetry.span(
+        "fetch_remote",
+        SpanKind::Internal,
+        vec![KeyValue::new(
+            "server.address",
+            upload.url.host_str().unwrap_or("").to_owned(),
+        )],
+    );
+    let fetched = fetch(context, upload, progress)
+        .with_context(span.clone())
+        .await;
+    record(&span, &fetched);
+    context.metrics.remote_fetch(
+        fetched
+            .as_ref()
+            .map(|(_, size)| *size)
+            .map_err(|status| *status),
+    );
+    let (hash, size) = fetched?;
+    trail.file(&hash, Some(size));
+    Ok(hash)
+}
+
+/// The status to answer with when the upstream server answered with an error `status`. Codes
+/// Rocket doesn't know, such as 520, are a bad gateway
+fn upstream_status(status: Option<reqwest::StatusCode>) -> Status {
+    status
+        .and_then(|status| Status::from_code(status.as_u16()))
+        .unwrap_or(Status::BadGateway)
+}
+
+/// Does the work of [`fetch_remote`], also returning the size of the file
+async fn fetch(
+    context: &Context,
+    upload: &RemoteUpload,
+    progress: Arc<Progress>,
+) -> Result<(String, u64), Status> {
+    let RemoteUpload {
+        url,
+        name,
+        extension,
+        uploader,
+        expires,
+    } = upload;
+    let hash = Uuid::new_v4().to_string();
+    let save_name = format!("{hash}.{extension}");
+
+    let limits = Limits::from_settings(&context.settings);
+
+    let request = context.egress.get(url.clone(), &context.telemetry);
+    let resp = timeout_at(limits.deadline, request)
+        .await
+        .map_err(|_| Status::GatewayTimeout)?
+        .map_err(|error| {
+            info_!("Unable to fetch {url}: {error}");
+            error.status()
+        })?
+        .error_for_status()
+        .map_err(|error| upstream_status(error.status()))?;
+
+    let (mut input, transfer) = remote::stream(resp, limits, Arc::clone(&progress))?;
+    let content_type = match input.head(SNIFF_LEN).await {
+        Ok(head) => context.upload_policy.check_content(extension, &head)?,
+        Err(_) => return Err(transfer.finish().await.err().unwrap_or(Status::BadGateway)),
+    };
+    if let Some(total) = progress.total() {
+        context
+            .upload_policy
+            .check_size(extension, Some(&content_type), total)?;
+    }
+    // The limit for the type is enforced as the rest is downloaded, since there may be no length
+    transfer.restrict(
+        context
+            .upload_policy
+            .size_limit(extension, Some(&content_type)),
+    );
+
+    let saved = context.storage.save(input, &save_name).await;
+    let (size, sha256) = match (saved, transfer.finish().await) {
+        (Ok(()), Ok(digest)) => digest,
+        (_, Err(status)) => {
+            context.storage.delete(&save_name).await.ok();
+            return Err(status);
+        }
+        (Err(_), Ok(_)) => {
+            context.storage.delete(&save_name).await.ok();
+            return Err(Status::InternalServerError);
+        }
+    };
+    let metadata = Metadata::new(&hash, name, extension, uploader, size, sha256);
+
+    save_metadata(
+        &*context.storage,
+        Metadata {
+            content_type,
+            expires: *expires,
+            ..metadata
+        },
+    )
+    .await?;
+
+    Ok((hash, size))
+}
+
+/// Records the metadata for a freshly saved file, removing the file again if that fails
+pub(crate) async fn save_metadata(storage: &dyn Storage, metadata: Metadata) -> Result<(), Status> {
+    if storage
+        .save_metadata(&metadata.hash, &metadata)
+        .await
+        .is_err()
+    {
+        storage.delete(&metadata.file).await.ok();
+        return Err(Status::InternalServerError);
+    }
+
+    Ok(())
 }
@@ -0,0 +1,209 @@
+//! Looking up and listing the metadata of stored files
+
+use super::validate_hash;
+use crate::{
+    Context,
+    auth::{Principal, Scope},
+    storage::{Metadata, Storage, metadata_name},
+};
+use rocket::{
+    State,
+    http::Status,
+    serde::{Serialize, json::Json},
+};
+use std::{path::Path, sync::Arc};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+/// How many names to request from storage at a time while filtering
+const BATCH_SIZE: usize = 1000;
+/// Most files whose metadata is read for one page, so a filter matching few files can't make a
+/// request read all of storage
+const MAX_SCANNED: usize = 10_000;
+
+#[derive(FromForm)]
+pub(crate) struct FileQuery<'r> {
+    /// The `next` cursor returned with the previous page
+    cursor: Option<&'r str>,
+    limit: Option<usize>,
+    extension: Option<&'r str>,
+    /// Name of the API key which uploaded the files
+    uploader: Option<&'r str>,
+    /// RFC 3339 timestamp the files were uploaded at or after
+    from: Option<&'r str>,
+    /// RFC 3339 timestamp the files were uploaded before
+    to: Option<&'r str>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct FileList {
+    files: Vec<Metadata>,
+    /// Cursor for the next page, if there may be more files
+    next: Option<String>,
+}
+
+struct Filter<'r> {
+    extension: Option<&'r str>,
+    uploader: Option<&'r str>,
+    from: Option<OffsetDateTime>,
+    to: Option<OffsetDateTime>,
+}
+
+impl<'r> Filter<'r> {
+    fn new(query: &FileQuery<'r>) -> Result<Self, Status> {
+        Ok(Filter {
+            extension: query
+                .extension
+                .map(|extension| extension.trim_start_matches('.')),
+            uploader: query.uploader,
+            from: query.from.map(parse_timestamp).transpose()?,
+            to: query.to.map(parse_timestamp).transpose()?,
+        })
+    }
+
+    fn matches(&self, metadata: &Metadata) -> bool {
+        self.extension.is_none_or(|extension| {
+            Path::new(&metadata.file)
+                .extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case(extension))
+        }) && self
+            .uploader
+            .is_none_or(|uploader| metadata.uploader == uploader)
+            && self.from.is_none_or(|from| metadata.uploaded >= from)
+            && self.to.is_none_or(|to| metadata.uploaded < to)
+    }
+}
+
+#[get("/api/files?<query..>")]
+pub(crate) async fn list_files(
+    context: &State<Arc<Context>>,
+    principal: Principal,
+    query: FileQuery<'_>,
+) -> Result<Json<FileList>, Status> {
+    principal.require(Scope::Metadata)?;
+
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let filter = Filter::new(&query)?;
+    let cursor = query.cursor.map(validate_hash).transpose()?;
+
+    scan(
+        &*context.storage,
+        cursor.as_deref(),
+        &filter,
+        limit,
+        MAX_SCANNED,
+    )
+    .await
+    .map(Json)
+}
+
+/// Finds up to `limit` files matching `filter` after the `cursor` hash, reading the metadata of at
+/// most `max_scanned` files. The page may be short, or even empty, but still have a `next` cursor
+/// when the scan stopped early
+async fn scan(
+    storage: &dyn Storage,
+    cursor: Option<&str>,
+    filter: &Filter<'_>,
+    limit: usize,
+    max_scanned: usize,
+) -> Result<FileList, Status> {
+    let mut after = cursor.map(metadata_name);
+    let mut files = Vec::new();
+    let mut scanned = 0;
+
+    loop {
+        let page = storage
+            .list(after.as_deref(), BATCH_SIZE)
+            .await
+            .map_err(|_| Status::InternalServerError)?;
+
+        for hash in Metadata::hashes(&page.names) {
+            if let Ok(metadata) = storage.load_metadata(hash).await
+                && filter.matches(&metadata)
+            {
+                files.push(metadata);
+            }
+
+            scanned += 1;
+            if files.len() == limit || scanned == max_scanned {
+                return Ok(FileList {
+                    files,
+                    next: Some(String::from(hash)),
+                });
+            }
+        }
+
+        match page.next {
+            Some(next) => after = Some(next),
+            None => return Ok(FileList { files, next: None }),
+        }
+    }
+}
+
+#[get("/api/files/<hash>")]
+pub(crate) async fn file_metadata(
+    context: &State<Arc<Context>>,
+    principal: Principal,
+    hash: &str,
+) -> Result<Json<Metadata>, Status> {
+    principal.require(Scope::Metadata)?;
+
+    let hash = validate_hash(hash)?;
+    context
+        .storage
+        .load_metadata(&hash)
+        .await
+        .map(Json)
+        .map_err(|_| Status::NotFound)
+}
+
+fn parse_timestamp(timestamp: &str) -> Result<OffsetDateTime, Status> {
+    OffsetDateTime::parse(timestamp, &Rfc3339).map_err(|_| Status::BadRequest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{InputFile, debug::DebugStorage};
+
+    #[rocket::async_test]
+    async fn stops_scanning_early() {
+        let storage = DebugStorage::new();
+        for extension in ["png", "txt", "txt", "png", "png"] {
+            let hash = uuid::Uuid::new_v4().to_string();
+            let mut input = InputFile::Bytes(b"listed");
+            let metadata = Metadata::describe(&mut input, &hash, "test", extension, "test")
+                .await
+                .unwrap();
+            storage.save_metadata(&hash, &metadata).await.unwrap();
+        }
+
+        let filter = Filter {
+            extension: Some("txt"),
+            uploader: None,
+            from: None,
+            to: None,
+        };
+        let mut cursor = None;
+        let mut found = 0;
+        let mut pages = 0;
+        // Each page reads two files' metadata, so may stop before finding any
+        loop {
+            let list = scan(&storage, cursor.as_deref(), &filter, 10, 2)
+                .await
+                .unwrap();
+            assert!(list.files.len() <= 2);
+            found += list.files.len();
+            pages += 1;
+            match list.next {
+                Some(next) => cursor = Some(next),
+                None => break,
+            }
+        }
+
+        assert_eq!(found, 2);
+        assert_eq!(pages, 3);
+    }
+}
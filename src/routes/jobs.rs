@@ -0,0 +1,224 @@
+//! Remote URL uploads which run in the background, so clients are not kept waiting on slow
+//! upstream servers
+
+use super::{BaseUrl, RemoteUpload, Uploaded, fetch_remote};
+use crate::{
+    Context,
+    audit::Trail,
+    auth::{Principal, Scope},
+    remote::Progress,
+};
+use opentelemetry::{Context as TraceContext, context::FutureExt};
+use rocket::{
+    Request, State,
+    futures::FutureExt as _,
+    http::{Header, Status},
+    outcome::Outcome,
+    request::FromRequest,
+    serde::{Serialize, json::Json},
+};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    panic::AssertUnwindSafe,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::Semaphore;
+use uuid::Uuid;
+
+/// How long a finished job can still be looked up for
+const RETENTION: Duration = Duration::from_secs(60 * 60);
+
+/// The background uploads of one server
+pub(crate) struct Jobs {
+    jobs: Mutex<HashMap<String, Job>>,
+    /// Limits how many jobs download at once. The rest wait their turn in the order they were
+    /// queued
+    workers: Semaphore,
+}
+
+struct Job {
+    owner: String,
+    state: JobState,
+    progress: Arc<Progress>,
+    /// Set once the job has completed or failed
+    finished: Option<Instant>,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(crate = "rocket::serde", tag = "status", rename_all = "snake_case")]
+enum JobState {
+    Queued,
+    Running,
+    Completed { url: String, deletion_token: String },
+    Failed { code: u16, error: String },
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct JobStatus {
+    id: String,
+    #[serde(flatten)]
+    state: JobState,
+    /// Bytes downloaded so far
+    received: u64,
+    /// The size reported by the upstream server, if any
+    total: Option<u64>,
+}
+
+/// Whether the client asked for the upload to run in the background with `Prefer: respond-async`
+pub(crate) struct RespondAsync(pub(crate) bool);
+
+/// A newly queued job, along with where its status can be found
+#[derive(Responder)]
+#[response(status = 202)]
+pub(crate) struct Accepted {
+    status: Json<JobStatus>,
+    location: Header<'static>,
+    preference_applied: Header<'static>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RespondAsync {
+    type Error = Infallible;
+
+    async fn from_request(
+        request: &'r Request<'_>,
+    ) -> Outcome<RespondAsync, (Status, Infallible), Status> {
+        let respond_async = request
+            .headers()
+            .get("Prefer")
+            .flat_map(|prefer| prefer.split(','))
+            .any(|preference| preference.trim().eq_ignore_ascii_case("respond-async"));
+
+        Outcome::Success(RespondAsync(respond_async))
+    }
+}
+
+#[get("/api/jobs/<id>")]
+pub(crate) async fn job_status(
+    context: &State<Arc<Context>>,
+    principal: Principal,
+    id: &str,
+) -> Result<Json<JobStatus>, Status> {
+    principal.require(Scope::UploadUrl)?;
+
+    let jobs = context
+        .jobs
+        .jobs
+        .lock()
+        .map_err(|_| Status::InternalServerError)?;
+    let job = jobs
+        .get(id)
+        .filter(|job| job.owner == principal.name || principal.has(Scope::Admin))
+        .ok_or(Status::NotFound)?;
+
+    Ok(Json(job.status(id)))
+}
+
+/// Queues `upload` to be downloaded into storage in the background, auditing the result once it
+/// has finished
+pub(crate) fn submit(
+    context: Arc<Context>,
+    base: BaseUrl,
+    upload: RemoteUpload,
+    trail: Trail,
+) -> Result<Accepted, Status> {
+    let id = Uuid::new_v4().to_string();
+    let progress = Arc::new(Progress::default());
+    let job = Job {
+        owner: upload.uploader.clone(),
+        state: JobState::Queued,
+        progress: Arc::clone(&progress),
+        finished: None,
+    };
+
+    let status = {
+        let mut jobs = context
+            .jobs
+            .jobs
+            .lock()
+            .map_err(|_| Status::InternalServerError)?;
+        jobs.retain(|_, job| job.finished.is_none_or(|time| time.elapsed() < RETENTION));
+        let status = job.status(&id);
+        jobs.insert(id.clone(), job);
+        status
+    };
+
+    let location = format!("{}/api/jobs/{id}", base.0);
+    // Keep the job in the trace of the request which queued it
+    let trace = TraceContext::current();
+    let run = async move {
+        let jobs = &context.jobs;
+        let Ok(_permit) = jobs.workers.acquire().await else {
+            return;
+        };
+
+        jobs.update(&id, JobState::Running);
+        // A panic would otherwise leave the job running forever, and never audited
+        let fetched = AssertUnwindSafe(fetch_remote(&context, &upload, &trail, progress))
+            .catch_unwind()
+            .await
+            .unwrap_or(Err(Status::InternalServerError));
+        context
+            .audit
+            .finish(&trail, fetched.as_ref().map_or_else(|s| *s, |_| Status::Ok));
+        let state = match fetched {
+            Ok(hash) => {
+                let url = format!("{}/attachment/{hash}/{}", base.0, upload.name);
+                let Uploaded {
+                    url,
+                    deletion_token,
+                } = Uploaded::new(&context.signer, url, &hash);
+                JobState::Completed {
+                    url,
+                    deletion_token: deletion_token.value().to_owned(),
+                }
+            }
+            Err(status) => JobState::Failed {
+                code: status.code,
+                error: status.reason_lossy().to_owned(),
+            },
+        };
+        jobs.update(&id, state);
+    };
+    tokio::spawn(run.with_context(trace));
+
+    Ok(Accepted {
+        status: Json(status),
+        location: Header::new("Location", location),
+        preference_applied: Header::new("Preference-Applied", "respond-async"),
+    })
+}
+
+impl Jobs {
+    pub(crate) fn new(concurrency: usize) -> Self {
+        Jobs {
+            jobs: Mutex::default(),
+            workers: Semaphore::new(concurrency.max(1)),
+        }
+    }
+
+    fn update(&self, id: &str, state: JobState) {
+        if let Ok(mut jobs) = self.jobs.lock()
+            && let Some(job) = jobs.get_mut(id)
+        {
+            if matches!(state, JobState::Completed { .. } | JobState::Failed { .. }) {
+                job.finished = Some(Instant::now());
+            }
+            job.state = state;
+        }
+    }
+}
+
+impl Job {
+    fn status(&self, id: &str) -> JobStatus {
+        JobStatus {
+            id: String::from(id),
+            state: self.state.clone(),
+            received: self.progress.received(),
+            total: self.progress.total(),
+        }
+    }
+}
@@ -0,0 +1,570 @@
+//! Resumable uploads, using the tus 1.0 protocol with the creation and termination extensions
+
+use super::{BaseUrl, save_metadata, validate_file, validate_hash};
+use crate::{
+    Context,
+    audit::{Event, Trail},
+    auth::{Principal, Scope, Signer},
+    policy::SNIFF_LEN,
+    settings::Settings,
+    storage::{InputFile, Metadata},
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use rocket::{
+    Request, State,
+    data::{Data, ToByteUnit},
+    http::{Header, Status},
+    outcome::Outcome,
+    request::FromRequest,
+    response::{self, Responder, Response},
+    serde::{Deserialize, Serialize, json::serde_json},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,termination";
+/// How long an upload can go untouched before it is deleted from the staging directory, whether
+/// it was abandoned or completed
+const UPLOAD_EXPIRY: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct UploadInfo {
+    length: u64,
+    filename: String,
+    /// Name of the principal which created the upload, the only one allowed to use it
+    owner: String,
+    /// Set once the upload has been completed and saved to storage
+    completed: Option<CompletedUpload>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct CompletedUpload {
+    hash: String,
+    url: String,
+}
+
+pub(crate) struct TusHeaders<'r> {
+    resumable: Option<&'r str>,
+    upload_length: Option<&'r str>,
+    upload_offset: Option<&'r str>,
+    upload_metadata: Option<&'r str>,
+    content_type: Option<&'r str>,
+}
+
+pub(crate) struct TusResponse {
+    status: Status,
+    headers: Vec<Header<'static>>,
+}
+
+/// Uploads currently receiving data, which must not be written to concurrently
+#[derive(Default)]
+pub(crate) struct UploadLocks(Mutex<HashSet<String>>);
+
+/// Releases the lock on an upload when dropped
+struct UploadLock<'a> {
+    locks: &'a UploadLocks,
+    id: String,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for TusHeaders<'r> {
+    type Error = Infallible;
+
+    async fn from_request(
+        request: &'r Request<'_>,
+    ) -> Outcome<TusHeaders<'r>, (Status, Infallible), Status> {
+        let headers = request.headers();
+
+        Outcome::Success(TusHeaders {
+            resumable: headers.get_one("Tus-Resumable"),
+            upload_length: headers.get_one("Upload-Length"),
+            upload_offset: headers.get_one("Upload-Offset"),
+            upload_metadata: headers.get_one("Upload-Metadata"),
+            content_type: headers.get_one("Content-Type"),
+        })
+    }
+}
+
+impl TusHeaders<'_> {
+    fn check_version(&self) -> Result<(), TusResponse> {
+        if self.resumable == Some(TUS_VERSION) {
+            Ok(())
+        } else {
+            Err(TusResponse::new(Status::PreconditionFailed).header("Tus-Version", TUS_VERSION))
+        }
+    }
+
+    fn filename(&self) -> Option<String> {
+        self.upload_metadata?
+            .split(',')
+            .filter_map(|pair| pair.trim().split_once(' '))
+            .find(|(key, _)| *key == "filename")
+            .and_then(|(_, value)| STANDARD.decode(value).ok())
+            .and_then(|value| String::from_utf8(value).ok())
+    }
+}
+
+impl TusResponse {
+    fn new(status: Status) -> Self {
+        TusResponse {
+            status,
+            headers: Vec::new(),
+        }
+    }
+
+    fn header<V: ToString>(mut self, name: &'static str, value: V) -> Self {
+        self.headers.push(Header::new(name, value.to_string()));
+        self
+    }
+}
+
+impl From<Status> for TusResponse {
+    fn from(status: Status) -> Self {
+        TusResponse::new(status)
+    }
+}
+
+impl<'r> Responder<'r, 'static> for TusResponse {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build();
+        response
+            .status(self.status)
+            .header(Header::new("Tus-Resumable", TUS_VERSION));
+
+        for header in self.headers {
+            response.header_adjoin(header);
+        }
+
+        response.ok()
+    }
+}
+
+impl CompletedUpload {
+    fn headers(self, signer: &Signer, response: TusResponse) -> TusResponse {
+        let token = signer.deletion_token(&self.hash);
+        response
+            .header("X-Attachment-Url", self.url)
+            .header("X-Deletion-Token", token)
+    }
+}
+
+impl UploadLocks {
+    fn acquire(&self, id: &str) -> Result<UploadLock<'_>, Status> {
+        let mut locked = self.0.lock().map_err(|_| Status::InternalServerError)?;
+        if locked.insert(String::from(id)) {
+            Ok(UploadLock {
+                locks: self,
+                id: String::from(id),
+            })
+        } else {
+            Err(Status::Locked)
+        }
+    }
+}
+
+impl Drop for UploadLock<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut locked) = self.locks.0.lock() {
+            locked.remove(&self.id);
+        }
+    }
+}
+
+#[options("/api/upload/tus")]
+pub(crate) fn tus_options(context: &State<Arc<Context>>) -> TusResponse {
+    TusResponse::new(Status::NoContent)
+        .header("Tus-Version", TUS_VERSION)
+        .header("Tus-Extension", TUS_EXTENSIONS)
+        .header("Tus-Max-Size", context.upload_policy.largest_size())
+}
+
+#[post("/api/upload/tus")]
+pub(crate) async fn tus_create(
+    context: &State<Arc<Context>>,
+    base: BaseUrl,
+    principal: Principal,
+    headers: TusHeaders<'_>,
+) -> Result<TusResponse, TusResponse> {
+    principal.require(Scope::Upload)?;
+    headers.check_version()?;
+
+    let length = headers
+        .upload_length
+        .and_then(|length| length.parse::<u64>().ok())
+        .ok_or(Status::BadRequest)?;
+
+    let filename = headers.filename().ok_or(Status::BadRequest)?;
+    let (filename, extension) = validate_file(&context.upload_policy, &filename)?;
+    context.upload_policy.check_size(&extension, None, length)?;
+
+    let id = Uuid::new_v4().to_string();
+    let staging = staging_dir(&context.settings);
+    tokio::fs::create_dir_all(&staging)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    tokio::fs::File::create(data_path(&staging, &id))
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    write_info(
+        &staging,
+        &id,
+        &UploadInfo {
+            length,
+            filename: filename.into_owned(),
+            owner: principal.name.clone(),
+            completed: None,
+        },
+    )
+    .await?;
+
+    Ok(TusResponse::new(Status::Created)
+        .header("Location", format!("{}/api/upload/tus/{id}", base.0)))
+}
+
+#[head("/api/upload/tus/<id>")]
+pub(crate) async fn tus_head(
+    context: &State<Arc<Context>>,
+    principal: Principal,
+    headers: TusHeaders<'_>,
+    id: &str,
+) -> Result<TusResponse, TusResponse> {
+    principal.require(Scope::Upload)?;
+    headers.check_version()?;
+
+    let id = validate_hash(id)?;
+    let staging = staging_dir(&context.settings);
+    let info = read_own_info(&staging, &id, &principal).await?;
+    let offset = match info.completed {
+        Some(_) => info.length,
+        None => current_offset(&staging, &id).await?,
+    };
+
+    let response = TusResponse::new(Status::Ok)
+        .header("Upload-Offset", offset)
+        .header("Upload-Length", info.length)
+        .header("Cache-Control", "no-store");
+
+    Ok(match info.completed {
+        Some(completed) => completed.headers(&context.signer, response),
+        None => response,
+    })
+}
+
+#[patch("/api/upload/tus/<id>", data = "<data>")]
+pub(crate) async fn tus_patch(
+    context: &State<Arc<Context>>,
+    base: BaseUrl,
+    principal: Principal,
+    trail: Trail,
+    headers: TusHeaders<'_>,
+    id: &str,
+    data: Data<'_>,
+) -> Result<TusResponse, TusResponse> {
+    principal.require(Scope::Upload)?;
+    headers.check_version()?;
+
+    if headers.content_type != Some("application/offset+octet-stream") {
+        return Err(Status::UnsupportedMediaType.into());
+    }
+
+    let id = validate_hash(id)?;
+    let _lock = context.tus_locks.acquire(&id)?;
+    let staging = staging_dir(&context.settings);
+    let info = read_own_info(&staging, &id, &principal).await?;
+
+    if info.completed.is_some() {
+        return Err(Status::Conflict.into());
+    }
+
+    let offset = current_offset(&staging, &id).await?;
+    if headers
+        .upload_offset
+        .and_then(|offset| offset.parse::<u64>().ok())
+        .ok_or(Status::BadRequest)?
+        != offset
+    {
+        return Err(Status::Conflict.into());
+    }
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .append(true)
+        .open(data_path(&staging, &id))
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+
+    let remaining = info.length.saturating_sub(offset);
+    let result = data.open(remaining.bytes()).stream_to(&mut file).await;
+    file.flush()
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+
+    match result {
+        Ok(written) if written.complete => {}
+        Ok(_) => {
+            // The client sent more than the declared Upload-Length
+            file.set_len(offset)
+                .await
+                .map_err(|_| Status::InternalServerError)?;
+            return Err(Status::PayloadTooLarge.into());
+        }
+        // Keep whatever was received, so the client can resume from the new offset
+        Err(_) => {}
+    }
+
+    let offset = current_offset(&staging, &id).await?;
+    let response = TusResponse::new(Status::NoContent).header("Upload-Offset", offset);
+
+    if offset < info.length {
+        return Ok(response);
+    }
+
+    // Only the request which completes the upload is audited
+    trail.begin(Event::Upload, Some(&principal.name));
+    let length = info.length;
+    let upload = finish_upload(context, &base, &staging, &id, info);
+    let completed = upload.await?;
+    trail.file(&completed.hash, Some(length));
+    Ok(completed.headers(&context.signer, response))
+}
+
+#[delete("/api/upload/tus/<id>")]
+pub(crate) async fn tus_terminate(
+    context: &State<Arc<Context>>,
+    principal: Principal,
+    headers: TusHeaders<'_>,
+    id: &str,
+) -> Result<TusResponse, TusResponse> {
+    principal.require(Scope::Upload)?;
+    headers.check_version()?;
+
+    let id = validate_hash(id)?;
+    let _lock = context.tus_locks.acquire(&id)?;
+    let staging = staging_dir(&context.settings);
+    read_own_info(&staging, &id, &principal).await?;
+
+    tokio::fs::remove_file(data_path(&staging, &id)).await.ok();
+    tokio::fs::remove_file(info_path(&staging, &id))
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+
+    Ok(TusResponse::new(Status::NoContent))
+}
+
+/// Saves a completed upload to storage, the same as a multipart upload
+async fn finish_upload(
+    context: &Context,
+    base: &BaseUrl,
+    staging: &Path,
+    id: &str,
+    mut info: UploadInfo,
+) -> Result<CompletedUpload, Status> {
+    let policy = &context.upload_policy;
+    let (filename, extension) = validate_file(policy, &info.filename)?;
+    let hash = Uuid::new_v4().to_string();
+    let save_name = format!("{hash}.{extension}");
+    let url = format!("{}/attachment/{hash}/{filename}", base.0);
+
+    let path = data_path(staging, id);
+    let mut input = InputFile::Path(&path);
+    let head = input
+        .head(SNIFF_LEN)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    let checked = policy
+        .check_content(&extension, &head)
+        .and_then(|content_type| {
+            policy.check_size(&extension, Some(&content_type), info.length)?;
+            Ok(content_type)
+        });
+    let content_type = match checked {
+        Ok(content_type) => content_type,
+        Err(status) => {
+            // The upload can never be completed, so there is no point keeping it around
+            tokio::fs::remove_file(&path).await.ok();
+            tokio::fs::remove_file(info_path(staging, id)).await.ok();
+            return Err(status);
+        }
+    };
+    let metadata = Metadata::describe(&mut input, &hash, &filename, &extension, &info.owner)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+
+    context
+        .storage
+        .save(input, &save_name)
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    save_metadata(
+        &*context.storage,
+        Metadata {
+            content_type,
+            ..metadata
+        },
+    )
+    .await?;
+
+    tokio::fs::remove_file(&path).await.ok();
+    info.completed = Some(CompletedUpload { hash, url });
+    write_info(staging, id, &info).await?;
+
+    info.completed.ok_or(Status::InternalServerError)
+}
+
+/// Deletes uploads which haven't been touched for [`UPLOAD_EXPIRY`] from the staging directory,
+/// returning how many there were
+pub(crate) async fn expire_uploads(context: &Context) -> usize {
+    let staging = staging_dir(&context.settings);
+    expire_stale(&staging, &context.tus_locks, UPLOAD_EXPIRY).await
+}
+
+async fn expire_stale(staging: &Path, locks: &UploadLocks, max_age: Duration) -> usize {
+    let Ok(mut entries) = tokio::fs::read_dir(staging).await else {
+        return 0;
+    };
+
+    // An upload's info is only written when it is created and completed, so it is as recent as
+    // the newer of its two files
+    let mut touched = HashMap::<String, SystemTime>::new();
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name = entry.file_name();
+        let Some(id) = name.to_str().map(|name| name.trim_end_matches(".info")) else {
+            continue;
+        };
+        let Ok(id) = validate_hash(id) else {
+            continue;
+        };
+        let Ok(modified) = entry
+            .metadata()
+            .await
+            .and_then(|metadata| metadata.modified())
+        else {
+            continue;
+        };
+        touched
+            .entry(id)
+            .and_modify(|newest| *newest = modified.max(*newest))
+            .or_insert(modified);
+    }
+
+    let mut expired = 0;
+    for (id, modified) in touched {
+        if !modified.elapsed().is_ok_and(|age| age > max_age) {
+            continue;
+        }
+        // Uploads which are locked are receiving data right now
+        let Ok(_lock) = locks.acquire(&id) else {
+            continue;
+        };
+        tokio::fs::remove_file(data_path(staging, &id)).await.ok();
+        if tokio::fs::remove_file(info_path(staging, &id))
+            .await
+            .is_ok()
+        {
+            expired += 1;
+        }
+    }
+    expired
+}
+
+fn staging_dir(settings: &Settings) -> PathBuf {
+    settings
+        .tus_path
+        .clone()
+        .unwrap_or_else(|| std::env::temp_dir().join("rumia-tus"))
+}
+
+fn data_path(staging: &Path, id: &str) -> PathBuf {
+    staging.join(id)
+}
+
+fn info_path(staging: &Path, id: &str) -> PathBuf {
+    staging.join(format!("{id}.info"))
+}
+
+async fn current_offset(staging: &Path, id: &str) -> Result<u64, Status> {
+    tokio::fs::metadata(data_path(staging, id))
+        .await
+        .map(|metadata| metadata.len())
+        .map_err(|_| Status::NotFound)
+}
+
+async fn read_info(staging: &Path, id: &str) -> Result<UploadInfo, Status> {
+    let bytes = tokio::fs::read(info_path(staging, id))
+        .await
+        .map_err(|_| Status::NotFound)?;
+    serde_json::from_slice(&bytes).map_err(|_| Status::InternalServerError)
+}
+
+/// Reads the upload's info if `principal` created it. Other principals are told it doesn't exist,
+/// so they can't learn its deletion token or write to it
+async fn read_own_info(
+    staging: &Path,
+    id: &str,
+    principal: &Principal,
+) -> Result<UploadInfo, Status> {
+    let info = read_info(staging, id).await?;
+    if info.owner == principal.name {
+        Ok(info)
+    } else {
+        Err(Status::NotFound)
+    }
+}
+
+async fn write_info(staging: &Path, id: &str, info: &UploadInfo) -> Result<(), Status> {
+    let bytes = serde_json::to_vec(info).map_err(|_| Status::InternalServerError)?;
+    tokio::fs::write(info_path(staging, id), bytes)
+        .await
+        .map_err(|_| Status::InternalServerError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn touch(path: &Path, modified: SystemTime) {
+        std::fs::write(path, b"").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn expires_stale_uploads() {
+        let staging = std::env::temp_dir().join(format!("rumia-tus-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&staging).unwrap();
+        let old = SystemTime::now() - Duration::from_secs(120);
+        let [abandoned, active, locked] = [(); 3].map(|_| Uuid::new_v4().to_string());
+
+        touch(&data_path(&staging, &abandoned), old);
+        touch(&info_path(&staging, &abandoned), old);
+        // Still receiving data, though it was created long ago
+        touch(&data_path(&staging, &active), SystemTime::now());
+        touch(&info_path(&staging, &active), old);
+        touch(&info_path(&staging, &locked), old);
+
+        let locks = UploadLocks::default();
+        let lock = locks.acquire(&locked).unwrap();
+        let expired = expire_stale(&staging, &locks, Duration::from_secs(60)).await;
+        drop(lock);
+
+        assert_eq!(expired, 1);
+        assert!(!data_path(&staging, &abandoned).exists());
+        assert!(!info_path(&staging, &abandoned).exists());
+        assert!(info_path(&staging, &active).exists());
+        assert!(info_path(&staging, &locked).exists());
+        std::fs::remove_dir_all(staging).ok();
+    }
+}
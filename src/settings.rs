@@ -1,18 +1,33 @@
 use dotenv::dotenv;
-#[cfg(feature = "docker")]
-use std::env;
-use std::{net::Ipv4Addr, path::Path, str::FromStr};
+use std::{
+    env, fmt,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+};
+use toml::{Table, Value};
+use url::Url;
 #[cfg(feature = "cli")]
 use {
-    clap::{Parser, Subcommand, ValueEnum},
-    std::convert::Infallible,
+    clap::{
+        Arg, ArgGroup, Command, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum,
+        error::ErrorKind,
+    },
+    std::ffi::OsString,
 };
 
+/// Executables and pages which could be served from our domain
+pub(crate) const DEFAULT_DENY_TYPES: &str = "application/vnd.microsoft.portable-executable,application/x-executable,application/x-mach-binary,text/html";
+pub(crate) const DEFAULT_DENY_EXTENSIONS: &str = "exe,dll,html,css,php,pub";
+/// Private keys
+pub(crate) const DEFAULT_DENY_NAMES: &str = "_rsa,_ed25519";
+
 #[derive(Default, Clone, Debug)]
 #[cfg_attr(feature = "cli", derive(ValueEnum))]
 pub enum StorageType {
     #[default]
     File,
+    S3,
 }
 
 impl FromStr for StorageType {
@@ -20,19 +35,118 @@ impl FromStr for StorageType {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s.to_lowercase().as_str() {
             "file" => Ok(Self::File),
+            "s3" => Ok(Self::S3),
             _ => Err(()),
         }
     }
 }
 
+/// How log records are written
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+pub enum LogFormat {
+    /// Rocket's human readable logs
+    #[default]
+    Text,
+    /// One JSON object per line, including an access log record for every request
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+/// An address to accept connections on
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddress {
+    /// A TCP address, eg `0.0.0.0:10032` or `[::]:10032`
+    Tcp(SocketAddr),
+    /// A Unix domain socket, written as `unix:/path/to/socket`
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddress {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().strip_prefix("unix:") {
+            Some("") => Err(String::from("a Unix socket needs a path, eg unix:/run/rumia.sock")),
+            Some(path) => Ok(Self::Unix(PathBuf::from(path))),
+            None => s.trim().parse().map(Self::Tcp).map_err(|_| {
+                format!("invalid listen address {s}, expected eg 0.0.0.0:10032, [::]:10032 or unix:/run/rumia.sock")
+            }),
+        }
+    }
+}
+
+/// Parses the URL of an S3-compatible object store, which must have a host
+pub(crate) fn s3_endpoint(s: &str) -> Result<Url, String> {
+    match Url::parse(s.trim()) {
+        Ok(url) if url.host_str().is_some() => Ok(url),
+        Ok(_) => Err(format!("invalid S3 endpoint {s}, it has no host")),
+        Err(e) => Err(format!("invalid S3 endpoint {s}: {e}")),
+    }
+}
+
+impl fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(address) => address.fmt(f),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
 #[derive(Debug)]
 #[cfg_attr(feature = "cli", derive(Subcommand))]
 #[cfg_attr(feature = "cli", command(about, version))]
 pub enum StorageCommands {
     /// Use the filesystem for file storage
     FileSystem {
-        #[cfg_attr(feature = "cli", arg(long, env = "RUMIA_FILESYSTEM_PATH", value_parser = return_leaked_path))]
-        path: &'static Path,
+        #[cfg_attr(feature = "cli", arg(long, env = "RUMIA_FILESYSTEM_PATH"))]
+        path: PathBuf,
+
+        /// Free space, in bytes, below which the server is reported as not ready
+        #[cfg_attr(
+            feature = "cli",
+            arg(long, env = "RUMIA_FILESYSTEM_MIN_FREE", default_value_t = 100 * 1024 * 1024)
+        )]
+        min_free: u64,
+    },
+    /// Use an S3-compatible object store for file storage
+    S3 {
+        #[cfg_attr(
+            feature = "cli",
+            arg(long, env = "RUMIA_S3_ENDPOINT", value_parser = s3_endpoint)
+        )]
+        endpoint: Url,
+
+        #[cfg_attr(feature = "cli", arg(long, env = "RUMIA_S3_BUCKET"))]
+        bucket: String,
+
+        #[cfg_attr(
+            feature = "cli",
+            arg(long, env = "RUMIA_S3_REGION", default_value = "us-east-1")
+        )]
+        region: String,
+
+        #[cfg_attr(feature = "cli", arg(long, env = "RUMIA_S3_ACCESS_KEY"))]
+        access_key: String,
+
+        #[cfg_attr(feature = "cli", arg(long, env = "RUMIA_S3_SECRET_KEY"))]
+        secret_key: String,
+
+        #[cfg_attr(
+            feature = "cli",
+            arg(long, env = "RUMIA_S3_PATH_STYLE", default_value_t = false)
+        )]
+        path_style: bool,
     },
     Debug,
 }
@@ -40,9 +154,21 @@ pub enum StorageCommands {
 #[derive(Debug)]
 #[cfg_attr(feature = "cli", derive(Parser))]
 #[cfg_attr(feature = "cli", command(version, about))]
+#[cfg_attr(feature = "cli", command(group(ArgGroup::new("keys").required(true).multiple(true).args(["api_key", "keys_file"]))))]
 pub struct Settings {
-    #[cfg_attr(feature = "cli", arg(short, long, env = "RUMIA_API_KEY", value_parser = return_leaked_str))]
-    pub api_key: &'static str,
+    /// Admin API key, which is allowed to use every endpoint
+    #[cfg_attr(feature = "cli", arg(short, long, env = "RUMIA_API_KEY"))]
+    pub api_key: Option<String>,
+
+    /// TOML file of additional named API keys, each with their own scopes and optional expiry
+    #[cfg_attr(feature = "cli", arg(short, long, env = "RUMIA_KEYS_FILE"))]
+    pub keys_file: Option<PathBuf>,
+
+    /// Secret used to sign deletion tokens and download URLs, required when API keys are
+    /// configured. It is kept apart from the API keys, so they can be rotated without invalidating
+    /// tokens
+    #[cfg_attr(feature = "cli", arg(short, long, env = "RUMIA_SECRET"))]
+    pub secret: Option<String>,
 
     #[cfg_attr(
         feature = "cli",
@@ -50,8 +176,11 @@ pub struct Settings {
     )]
     pub port: u16,
 
-    #[cfg_attr(feature = "cli", arg(short, long, env = "RUMIA_URL", default_value = "http://localhost", value_parser = return_leaked_str))]
-    pub url: &'static str,
+    #[cfg_attr(
+        feature = "cli",
+        arg(short, long, env = "RUMIA_URL", default_value = "http://localhost")
+    )]
+    pub url: String,
 
     #[cfg_attr(
         feature = "cli",
@@ -59,61 +188,390 @@ pub struct Settings {
     )]
     pub verbose: bool,
 
+    /// Write logs as human readable text, or as JSON lines
+    #[cfg_attr(
+        feature = "cli",
+        arg(long, env = "RUMIA_LOG_FORMAT", value_enum, default_value_t = LogFormat::Text)
+    )]
+    pub log_format: LogFormat,
+
+    /// File to append an audit record of every upload, delete and authentication failure to, or
+    /// `-` for stdout
+    #[cfg_attr(feature = "cli", arg(long, env = "RUMIA_AUDIT_LOG"))]
+    pub audit_log: Option<PathBuf>,
+
+    /// Base URL of an OTLP/HTTP collector to export traces to, eg `http://localhost:4318`
+    #[cfg_attr(feature = "cli", arg(long, env = "RUMIA_OTLP_ENDPOINT"))]
+    pub otlp_endpoint: Option<String>,
+
+    /// IPv4 or IPv6 address to bind to, along with `port`
     #[cfg_attr(
         feature = "cli",
         arg(short, long, env = "RUMIA_IP", default_value = "0.0.0.0")
     )]
-    pub ip: Ipv4Addr,
+    pub ip: IpAddr,
+
+    /// Addresses to listen on instead of `ip` and `port`, eg `[::]:10032,unix:/run/rumia.sock`
+    #[cfg_attr(
+        feature = "cli",
+        arg(long, env = "RUMIA_LISTEN", value_delimiter = ',')
+    )]
+    pub listen: Vec<ListenAddress>,
+
+    /// Header a trusted reverse proxy sets to the client's IP, eg `X-Real-IP`. Only set this when
+    /// every request comes through that proxy, since clients can send the header themselves
+    #[cfg_attr(feature = "cli", arg(long, env = "RUMIA_IP_HEADER"))]
+    pub ip_header: Option<String>,
+
+    /// Directory to stage resumable uploads in. Defaults to the system temp directory
+    #[cfg_attr(feature = "cli", arg(long, env = "RUMIA_TUS_PATH"))]
+    pub tus_path: Option<PathBuf>,
+
+    /// Only allow files to be downloaded with a signed, time-limited URL
+    #[cfg_attr(
+        feature = "cli",
+        arg(long, env = "RUMIA_PRIVATE", default_value_t = false)
+    )]
+    pub private: bool,
+
+    /// IPs, CIDRs or hostnames which remote URL uploads may reach, even if they are not public
+    #[cfg_attr(
+        feature = "cli",
+        arg(long, env = "RUMIA_EGRESS_ALLOW", value_delimiter = ',')
+    )]
+    pub egress_allow: Vec<String>,
+
+    /// IPs, CIDRs or hostnames which remote URL uploads may never reach
+    #[cfg_attr(
+        feature = "cli",
+        arg(long, env = "RUMIA_EGRESS_DENY", value_delimiter = ',')
+    )]
+    pub egress_deny: Vec<String>,
+
+    /// URL schemes which remote URL uploads may use
+    #[cfg_attr(
+        feature = "cli",
+        arg(
+            long,
+            env = "RUMIA_EGRESS_SCHEMES",
+            value_delimiter = ',',
+            default_value = "http,https"
+        )
+    )]
+    pub egress_schemes: Vec<String>,
+
+    /// Maximum number of redirects to follow when uploading from a remote URL
+    #[cfg_attr(
+        feature = "cli",
+        arg(long, env = "RUMIA_EGRESS_MAX_REDIRECTS", default_value_t = 5)
+    )]
+    pub egress_max_redirects: usize,
+
+    /// Largest file, in bytes, which can be uploaded from a remote URL
+    #[cfg_attr(
+        feature = "cli",
+        arg(long, env = "RUMIA_REMOTE_MAX_SIZE", default_value_t = 200 * 1024 * 1024)
+    )]
+    pub remote_max_size: u64,
+
+    /// How long, in seconds, a remote URL upload may take in total
+    #[cfg_attr(
+        feature = "cli",
+        arg(long, env = "RUMIA_REMOTE_TIMEOUT", default_value_t = 300)
+    )]
+    pub remote_timeout: u64,
+
+    /// How long, in seconds, a remote URL upload may go without receiving any data
+    #[cfg_attr(
+        feature = "cli",
+        arg(long, env = "RUMIA_REMOTE_IDLE_TIMEOUT", default_value_t = 30)
+    )]
+    pub remote_idle_timeout: u64,
+
+    /// How many remote URL uploads can run in the background at once
+    #[cfg_attr(
+        feature = "cli",
+        arg(long, env = "RUMIA_JOB_CONCURRENCY", default_value_t = 4)
+    )]
+    pub job_concurrency: usize,
+
+    /// MIME types which may be uploaded. If empty, any type not denied may be uploaded
+    #[cfg_attr(
+        feature = "cli",
+        arg(long, env = "RUMIA_UPLOAD_ALLOW_TYPES", value_delimiter = ',')
+    )]
+    pub upload_allow_types: Vec<String>,
+
+    /// MIME types which may never be uploaded
+    #[cfg_attr(
+        feature = "cli",
+        arg(
+            long,
+            env = "RUMIA_UPLOAD_DENY_TYPES",
+            value_delimiter = ',',
+            default_value = DEFAULT_DENY_TYPES
+        )
+    )]
+    pub upload_deny_types: Vec<String>,
+
+    /// File extensions which may be uploaded. If empty, any extension not denied may be uploaded
+    #[cfg_attr(
+        feature = "cli",
+        arg(long, env = "RUMIA_UPLOAD_ALLOW_EXTENSIONS", value_delimiter = ',')
+    )]
+    pub upload_allow_extensions: Vec<String>,
+
+    /// File extensions which may never be uploaded
+    #[cfg_attr(
+        feature = "cli",
+        arg(
+            long,
+            env = "RUMIA_UPLOAD_DENY_EXTENSIONS",
+            value_delimiter = ',',
+            default_value = DEFAULT_DENY_EXTENSIONS
+        )
+    )]
+    pub upload_deny_extensions: Vec<String>,
+
+    /// Filenames containing any of these may never be uploaded
+    #[cfg_attr(
+        feature = "cli",
+        arg(
+            long,
+            env = "RUMIA_UPLOAD_DENY_NAMES",
+            value_delimiter = ',',
+            default_value = DEFAULT_DENY_NAMES
+        )
+    )]
+    pub upload_deny_names: Vec<String>,
+
+    /// Largest file, in bytes, which can be uploaded, unless a size limit for its type applies
+    #[cfg_attr(
+        feature = "cli",
+        arg(long, env = "RUMIA_UPLOAD_MAX_SIZE", default_value_t = 200 * 1024 * 1024)
+    )]
+    pub upload_max_size: u64,
+
+    /// Size limits for particular extensions or MIME types, eg `image/*=10MiB,zip=2GiB`
+    #[cfg_attr(
+        feature = "cli",
+        arg(long, env = "RUMIA_UPLOAD_SIZE_LIMITS", value_delimiter = ',')
+    )]
+    pub upload_size_limits: Vec<String>,
+
+    /// Certificate chain to serve HTTPS with, in PEM format. Needs `tls_key`
+    #[cfg_attr(feature = "cli", arg(long, env = "RUMIA_TLS_CERT"))]
+    pub tls_cert: Option<PathBuf>,
+
+    /// Private key for `tls_cert`, in PEM format
+    #[cfg_attr(feature = "cli", arg(long, env = "RUMIA_TLS_KEY"))]
+    pub tls_key: Option<PathBuf>,
+
+    /// CA certificates, in PEM format, which clients must present a certificate signed by
+    #[cfg_attr(feature = "cli", arg(long, env = "RUMIA_TLS_CLIENT_CA"))]
+    pub tls_client_ca: Option<PathBuf>,
+
+    /// How often, in seconds, to check the TLS files for changes and reload them
+    #[cfg_attr(
+        feature = "cli",
+        arg(long, env = "RUMIA_TLS_RELOAD_INTERVAL", default_value_t = 10)
+    )]
+    pub tls_reload_interval: u64,
+
+    /// How often, in seconds, to delete expired files and stale resumable uploads, or 0 to never
+    /// delete them. Each pass lists every stored file and loads its metadata, which with S3 is a
+    /// request per file, and every replica runs its own reaper
+    #[cfg_attr(
+        feature = "cli",
+        arg(long, env = "RUMIA_REAP_INTERVAL", default_value_t = 60)
+    )]
+    pub reap_interval: u64,
 
     #[cfg_attr(feature = "cli", command(subcommand))]
     pub storage_type: StorageCommands,
 }
 
-#[cfg(feature = "cli")]
-fn return_leaked_str(s: &str) -> Result<&'static str, Infallible> {
-    Ok(s.to_owned().leak())
+/// Splits a comma separated list
+pub(crate) fn list(list: &str) -> Vec<String> {
+    list.split(',').map(String::from).collect()
 }
 
-#[cfg(feature = "cli")]
-fn return_leaked_path(s: &str) -> Result<&'static Path, Infallible> {
-    Ok(Box::leak(Box::from(Path::new(s))))
+/// The same defaults as the CLI, with no API keys and in-memory storage, for embedding Rumia with
+/// [`server_with`](crate::server_with)
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            api_key: None,
+            keys_file: None,
+            secret: None,
+            port: 10032,
+            url: String::from("http://localhost"),
+            verbose: false,
+            log_format: LogFormat::Text,
+            audit_log: None,
+            otlp_endpoint: None,
+            ip: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            listen: Vec::new(),
+            ip_header: None,
+            tus_path: None,
+            private: false,
+            egress_allow: Vec::new(),
+            egress_deny: Vec::new(),
+            egress_schemes: vec![String::from("http"), String::from("https")],
+            egress_max_redirects: 5,
+            remote_max_size: 200 * 1024 * 1024,
+            remote_timeout: 300,
+            remote_idle_timeout: 30,
+            job_concurrency: 4,
+            upload_allow_types: Vec::new(),
+            upload_deny_types: list(DEFAULT_DENY_TYPES),
+            upload_allow_extensions: Vec::new(),
+            upload_deny_extensions: list(DEFAULT_DENY_EXTENSIONS),
+            upload_deny_names: list(DEFAULT_DENY_NAMES),
+            upload_max_size: 200 * 1024 * 1024,
+            upload_size_limits: Vec::new(),
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            tls_reload_interval: 10,
+            reap_interval: 60,
+            storage_type: StorageCommands::Debug,
+        }
+    }
 }
 
 impl Settings {
-    pub(crate) fn new() -> Self {
+    /// Reads the settings from CLI args, env vars and the config file
+    pub fn new() -> Self {
         dotenv().ok();
 
         #[cfg(feature = "docker")]
         #[allow(clippy::expect_used)]
         {
+            let file = ConfigFile::load().expect("unable to read the config file");
+            let var = |name: &str| {
+                env::var(name)
+                    .ok()
+                    .or_else(|| file.as_ref().and_then(|file| file.env(name)))
+            };
+            let var_list = |name: &str| var(name).map(|value| list(&value));
+
+            assert!(
+                var("RUMIA_API_KEY").is_some() || var("RUMIA_KEYS_FILE").is_some(),
+                "API key not provided, set RUMIA_API_KEY or RUMIA_KEYS_FILE"
+            );
+
             Settings {
-                api_key: Box::leak(Box::from(
-                    env::var("RUMIA_API_KEY").expect("API key not provided"),
-                )),
+                api_key: var("RUMIA_API_KEY"),
+                keys_file: var("RUMIA_KEYS_FILE").map(PathBuf::from),
+                secret: var("RUMIA_SECRET"),
                 port: 10032,
-                url: env::var("RUMIA_URL")
-                    .unwrap_or(String::from("http://localhost"))
-                    .leak(),
-                verbose: env::var("RUMIA_VERBOSE")
+                url: var("RUMIA_URL").unwrap_or(String::from("http://localhost")),
+                verbose: var("RUMIA_VERBOSE")
                     .unwrap_or(String::from("false"))
                     .to_lowercase()
                     .parse()
                     .expect("unable to parse verbose as boolean"),
-                ip: env::var("RUMIA_IP")
+                log_format: var("RUMIA_LOG_FORMAT")
+                    .unwrap_or(String::from("text"))
+                    .parse()
+                    .expect("unable to parse log format as one of: text, json"),
+                audit_log: var("RUMIA_AUDIT_LOG").map(PathBuf::from),
+                otlp_endpoint: var("RUMIA_OTLP_ENDPOINT"),
+                ip: var("RUMIA_IP")
                     .unwrap_or(String::from("0.0.0.0"))
                     .parse()
-                    .expect("unable to parse IP as IPv4 Addr"),
-                storage_type: match env::var("RUMIA_STORAGE")
+                    .expect("unable to parse IP as an IPv4 or IPv6 address"),
+                listen: var_list("RUMIA_LISTEN")
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|address| address.parse())
+                    .collect::<Result<_, _>>()
+                    .expect("unable to parse listen addresses"),
+                ip_header: var("RUMIA_IP_HEADER"),
+                tus_path: var("RUMIA_TUS_PATH").map(PathBuf::from),
+                private: var("RUMIA_PRIVATE")
+                    .unwrap_or(String::from("false"))
+                    .to_lowercase()
+                    .parse()
+                    .expect("unable to parse private as boolean"),
+                egress_allow: var_list("RUMIA_EGRESS_ALLOW").unwrap_or_default(),
+                egress_deny: var_list("RUMIA_EGRESS_DENY").unwrap_or_default(),
+                egress_schemes: var_list("RUMIA_EGRESS_SCHEMES")
+                    .unwrap_or(vec![String::from("http"), String::from("https")]),
+                egress_max_redirects: var("RUMIA_EGRESS_MAX_REDIRECTS")
+                    .unwrap_or(String::from("5"))
+                    .parse()
+                    .expect("unable to parse egress max redirects as an integer"),
+                remote_max_size: var("RUMIA_REMOTE_MAX_SIZE")
+                    .unwrap_or(String::from("209715200"))
+                    .parse()
+                    .expect("unable to parse remote max size as bytes"),
+                remote_timeout: var("RUMIA_REMOTE_TIMEOUT")
+                    .unwrap_or(String::from("300"))
+                    .parse()
+                    .expect("unable to parse remote timeout as seconds"),
+                remote_idle_timeout: var("RUMIA_REMOTE_IDLE_TIMEOUT")
+                    .unwrap_or(String::from("30"))
+                    .parse()
+                    .expect("unable to parse remote idle timeout as seconds"),
+                job_concurrency: var("RUMIA_JOB_CONCURRENCY")
+                    .unwrap_or(String::from("4"))
+                    .parse()
+                    .expect("unable to parse job concurrency as an integer"),
+                upload_allow_types: var_list("RUMIA_UPLOAD_ALLOW_TYPES").unwrap_or_default(),
+                upload_deny_types: var_list("RUMIA_UPLOAD_DENY_TYPES")
+                    .unwrap_or_else(|| list(DEFAULT_DENY_TYPES)),
+                upload_allow_extensions: var_list("RUMIA_UPLOAD_ALLOW_EXTENSIONS")
+                    .unwrap_or_default(),
+                upload_deny_extensions: var_list("RUMIA_UPLOAD_DENY_EXTENSIONS")
+                    .unwrap_or_else(|| list(DEFAULT_DENY_EXTENSIONS)),
+                upload_deny_names: var_list("RUMIA_UPLOAD_DENY_NAMES")
+                    .unwrap_or_else(|| list(DEFAULT_DENY_NAMES)),
+                upload_max_size: var("RUMIA_UPLOAD_MAX_SIZE")
+                    .unwrap_or(String::from("209715200"))
+                    .parse()
+                    .expect("unable to parse upload max size as bytes"),
+                upload_size_limits: var_list("RUMIA_UPLOAD_SIZE_LIMITS").unwrap_or_default(),
+                tls_cert: var("RUMIA_TLS_CERT").map(PathBuf::from),
+                tls_key: var("RUMIA_TLS_KEY").map(PathBuf::from),
+                tls_client_ca: var("RUMIA_TLS_CLIENT_CA").map(PathBuf::from),
+                tls_reload_interval: var("RUMIA_TLS_RELOAD_INTERVAL")
+                    .unwrap_or(String::from("10"))
+                    .parse()
+                    .expect("unable to parse TLS reload interval as seconds"),
+                reap_interval: var("RUMIA_REAP_INTERVAL")
+                    .unwrap_or(String::from("60"))
+                    .parse()
+                    .expect("unable to parse reap interval as seconds"),
+                storage_type: match var("RUMIA_STORAGE")
                     .unwrap_or(String::from("FILE"))
                     .parse::<StorageType>()
-                    .expect("unable to parse storage as one of: FILE")
+                    .expect("unable to parse storage as one of: FILE, S3")
                 {
                     StorageType::File => StorageCommands::FileSystem {
-                        path: Path::new(
-                            env::var("RUMIA_FILESYSTEM_PATH")
-                                .unwrap_or(String::from("/filestore"))
-                                .leak(),
+                        path: PathBuf::from(
+                            var("RUMIA_FILESYSTEM_PATH").unwrap_or(String::from("/filestore")),
                         ),
+                        min_free: var("RUMIA_FILESYSTEM_MIN_FREE")
+                            .unwrap_or(String::from("104857600"))
+                            .parse()
+                            .expect("unable to parse filesystem min free as an integer"),
+                    },
+                    StorageType::S3 => StorageCommands::S3 {
+                        endpoint: s3_endpoint(
+                            &var("RUMIA_S3_ENDPOINT").expect("S3 endpoint not provided"),
+                        )
+                        .expect("unable to parse S3 endpoint as a URL"),
+                        bucket: var("RUMIA_S3_BUCKET").expect("S3 bucket not provided"),
+                        region: var("RUMIA_S3_REGION").unwrap_or(String::from("us-east-1")),
+                        access_key: var("RUMIA_S3_ACCESS_KEY").expect("S3 access key not provided"),
+                        secret_key: var("RUMIA_S3_SECRET_KEY").expect("S3 secret key not provided"),
+                        path_style: var("RUMIA_S3_PATH_STYLE")
+                            .unwrap_or(String::from("false"))
+                            .to_lowercase()
+                            .parse()
+                            .expect("unable to parse S3 path style as boolean"),
                     },
                 },
             }
@@ -121,7 +579,303 @@ impl Settings {
 
         #[cfg(feature = "cli")]
         {
-            Settings::parse()
+            let file = ConfigFile::load()
+                .unwrap_or_else(|error| Settings::command().error(ErrorKind::Io, error).exit());
+            Settings::parse_with(file.as_ref(), env::args_os().collect())
+                .unwrap_or_else(|error| error.exit())
+        }
+    }
+
+    /// Parses CLI `args`, falling back to env vars and then the config `file`
+    #[cfg(feature = "cli")]
+    fn parse_with(file: Option<&ConfigFile>, mut args: Vec<OsString>) -> Result<Self, clap::Error> {
+        let config = Arg::new("config")
+            .long("config")
+            .env("RUMIA_CONFIG")
+            .value_name("CONFIG")
+            .help("TOML file to read settings from. Env vars and CLI args take priority over it");
+        let mut command = Settings::command().arg(config);
+        if let Some(file) = file {
+            command = file.apply(command);
+        }
+
+        let matches = match command.try_get_matches_from_mut(&args) {
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    ErrorKind::MissingSubcommand
+                        | ErrorKind::DisplayHelpOnMissingArgumentOrSubcommand
+                ) =>
+            {
+                // Env vars can't choose a subcommand, but the config file can
+                match file.and_then(ConfigFile::storage_subcommand) {
+                    Some(subcommand) => {
+                        args.push(OsString::from(subcommand));
+                        command.try_get_matches_from_mut(&args)
+                    }
+                    None => Err(error),
+                }
+            }
+            matches => matches,
+        }?;
+
+        Settings::from_arg_matches(&matches)
+    }
+}
+
+/// Settings read from a TOML config file, which env vars and CLI args take priority over.
+///
+/// Keys are named after the CLI args, with the storage backend and its options in a `[storage]` table
+#[derive(Debug)]
+struct ConfigFile {
+    settings: Table,
+}
+
+impl ConfigFile {
+    /// Reads the file given by `--config` or `RUMIA_CONFIG`, if there is one
+    fn load() -> Result<Option<Self>, String> {
+        let path = config_arg().or_else(|| env::var("RUMIA_CONFIG").ok());
+
+        let Some(path) = path else {
+            return Ok(None);
+        };
+
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|error| format!("unable to read config file {path}: {error}"))?;
+        let settings = contents
+            .parse()
+            .map_err(|error| format!("unable to parse config file {path}: {error}"))?;
+
+        Ok(Some(ConfigFile { settings }))
+    }
+
+    /// A top level setting, such as `egress_allow`
+    fn get(&self, key: &str) -> Option<String> {
+        self.settings.get(key).and_then(to_setting)
+    }
+
+    /// A setting from the `[storage]` table, such as `bucket`
+    fn storage(&self, key: &str) -> Option<String> {
+        self.settings
+            .get("storage")
+            .and_then(Value::as_table)
+            .and_then(|storage| storage.get(key))
+            .and_then(to_setting)
+    }
+
+    /// The value of the env var `name`, eg `RUMIA_S3_BUCKET` is `bucket` in the `[storage]` table
+    #[cfg(feature = "docker")]
+    fn env(&self, name: &str) -> Option<String> {
+        let key = name.strip_prefix("RUMIA_")?.to_lowercase();
+        match key.as_str() {
+            "storage" => self.storage("type"),
+            "filesystem_path" => self.storage("path"),
+            "filesystem_min_free" => self.storage("min_free"),
+            _ => match key.strip_prefix("s3_") {
+                Some(key) => self.storage(key),
+                None => self.get(&key),
+            },
+        }
+    }
+
+    /// Uses the settings in the file as the defaults for `command`
+    #[cfg(feature = "cli")]
+    fn apply(&self, mut command: Command) -> Command {
+        let ids: Vec<String> = command
+            .get_arguments()
+            .map(|arg| arg.get_id().to_string())
+            .collect();
+        for id in ids {
+            if let Some(value) = self.get(&id) {
+                command = command.mut_arg(id, |arg| arg.default_value(value));
+            }
+        }
+
+        // Defaults don't count towards a required group, so check for the keys here instead
+        if self.get("api_key").is_some() || self.get("keys_file").is_some() {
+            command = command.mut_group("keys", |group| group.required(false));
+        }
+
+        for subcommand in ["file-system", "s3"] {
+            command = command.mut_subcommand(subcommand, |mut subcommand| {
+                let ids: Vec<String> = subcommand
+                    .get_arguments()
+                    .map(|arg| arg.get_id().to_string())
+                    .collect();
+                for id in ids {
+                    if let Some(value) = self.storage(&id) {
+                        // Like the keys group, defaults don't count towards required args
+                        subcommand =
+                            subcommand.mut_arg(id, |arg| arg.default_value(value).required(false));
+                    }
+                }
+                subcommand
+            });
+        }
+
+        command
+    }
+
+    /// The subcommand selecting the storage backend chosen by `type` in the `[storage]` table
+    #[cfg(feature = "cli")]
+    fn storage_subcommand(&self) -> Option<&'static str> {
+        match self.storage("type")?.parse().ok()? {
+            StorageType::File => Some("file-system"),
+            StorageType::S3 => Some("s3"),
+        }
+    }
+}
+
+/// Finds `--config <path>` or `--config=<path>` in the CLI args, before they are fully parsed
+#[cfg(feature = "cli")]
+fn config_arg() -> Option<String> {
+    let mut args = env::args();
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next();
         }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(String::from(path));
+        }
+    }
+    None
+}
+
+#[cfg(feature = "docker")]
+fn config_arg() -> Option<String> {
+    None
+}
+
+/// Converts a value from the config file into the same form as an env var, with lists comma
+/// separated
+fn to_setting(value: &Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value.clone()),
+        Value::Integer(value) => Some(value.to_string()),
+        Value::Float(value) => Some(value.to_string()),
+        Value::Boolean(value) => Some(value.to_string()),
+        Value::Array(values) => values
+            .iter()
+            .map(to_setting)
+            .collect::<Option<Vec<_>>>()
+            .map(|values| values.join(",")),
+        Value::Datetime(_) | Value::Table(_) => None,
+    }
+}
+
+#[cfg(all(test, feature = "cli"))]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        api_key = "from-file"
+        port = 1234
+        verbose = true
+        egress_allow = ["10.0.0.0/8", "files.internal"]
+
+        [storage]
+        type = "s3"
+        endpoint = "http://localhost:9000"
+        bucket = "rumia"
+        access_key = "access"
+        secret_key = "secret"
+    "#;
+
+    fn parse(file: Option<&ConfigFile>, args: &[&str]) -> Result<Settings, clap::Error> {
+        let args = std::iter::once("rumia").chain(args.iter().copied());
+        Settings::parse_with(file, args.map(OsString::from).collect())
+    }
+
+    #[test]
+    fn reads_config_file() {
+        let file = ConfigFile {
+            settings: CONFIG.parse().unwrap(),
+        };
+        let settings = parse(Some(&file), &[]).unwrap();
+
+        assert_eq!(settings.api_key.as_deref(), Some("from-file"));
+        assert_eq!(settings.port, 1234);
+        assert!(settings.verbose);
+        assert_eq!(settings.egress_allow, ["10.0.0.0/8", "files.internal"]);
+        assert!(matches!(
+            &settings.storage_type,
+            StorageCommands::S3 { bucket, region, .. } if bucket == "rumia" && region == "us-east-1"
+        ));
+    }
+
+    #[test]
+    fn args_override_config_file() {
+        let file = ConfigFile {
+            settings: CONFIG.parse().unwrap(),
+        };
+        let settings = parse(Some(&file), &["--port", "4321", "s3", "--bucket", "other"]).unwrap();
+
+        assert_eq!(settings.port, 4321);
+        assert!(matches!(
+            &settings.storage_type,
+            StorageCommands::S3 { bucket, endpoint, .. }
+                if bucket == "other" && endpoint.as_str() == "http://localhost:9000/"
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_s3_endpoints() {
+        let args = |endpoint| {
+            parse(
+                None,
+                &[
+                    "--api-key",
+                    "12345",
+                    "s3",
+                    "--endpoint",
+                    endpoint,
+                    "--bucket",
+                    "rumia",
+                    "--access-key",
+                    "access",
+                    "--secret-key",
+                    "secret",
+                ],
+            )
+        };
+        assert!(args("http://minio:9000").is_ok());
+        assert!(args("minio:9000 ").is_err());
+        assert!(args("not a url").is_err());
+    }
+
+    #[test]
+    fn reads_ipv6_and_unix_listen_addresses() {
+        let settings = parse(
+            None,
+            &[
+                "--api-key",
+                "12345",
+                "--ip",
+                "::",
+                "--listen",
+                "[::1]:8000,127.0.0.1:8001,unix:/run/rumia.sock",
+                "file-system",
+                "--path",
+                "/tmp",
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(settings.ip, IpAddr::from(std::net::Ipv6Addr::UNSPECIFIED));
+        assert_eq!(
+            settings.listen,
+            [
+                ListenAddress::Tcp("[::1]:8000".parse().unwrap()),
+                ListenAddress::Tcp("127.0.0.1:8001".parse().unwrap()),
+                ListenAddress::Unix(PathBuf::from("/run/rumia.sock")),
+            ]
+        );
+        assert!("unix:".parse::<ListenAddress>().is_err());
+    }
+
+    #[test]
+    fn requires_storage_without_config_file() {
+        let error = parse(None, &["--api-key", "12345"]).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::MissingSubcommand);
     }
 }
@@ -1,25 +1,462 @@
-mod debug;
+pub(crate) mod debug;
 mod filesystem;
+mod s3;
 
 use crate::error::{DeleteError, LoadError, SaveError};
 use crate::settings::StorageCommands;
-use crate::storage::{debug::DebugStorage, filesystem::FileSystemStorage};
-use rocket::fs::{NamedFile, TempFile};
+use crate::storage::{filesystem::FileSystemStorage, s3::S3Storage};
+use rocket::{
+    Request,
+    fs::TempFile,
+    http::{ContentType, Header},
+    response::{self, Responder, Response},
+    serde::{Deserialize, Serialize, json::serde_json},
+};
+use sha2::{Digest, Sha256};
+use std::{
+    io::{self, Cursor},
+    ops::Range,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    time::SystemTime,
+};
+use time::OffsetDateTime;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use uuid::Uuid;
+
+pub use debug::DebugStorage;
+
+const METADATA_SUFFIX: &str = ".meta.json";
 
 pub enum InputFile<'r> {
     TempFile(&'r mut TempFile<'r>),
     Bytes(&'r [u8]),
+    /// A file on the local filesystem, which is copied rather than moved
+    Path(&'r Path),
+    /// A stream of unknown length, such as a remote download, which can only be read once
+    Stream(Pin<Box<dyn AsyncRead + Send + 'r>>),
+}
+
+/// A file read back from storage, streamed to the client as it is read
+pub struct StoredFile {
+    pub reader: Pin<Box<dyn AsyncRead + Send>>,
+    pub len: u64,
+    pub content_type: ContentType,
+}
+
+impl StoredFile {
+    /// Creates a new `StoredFile`, guessing the content type from the extension of `filename`
+    pub fn new<R: AsyncRead + Send + 'static>(reader: R, len: u64, filename: &str) -> Self {
+        StoredFile {
+            reader: Box::pin(reader),
+            len,
+            content_type: content_type(filename).unwrap_or(ContentType::Binary),
+        }
+    }
+}
+
+/// Metadata stored alongside each uploaded file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Metadata {
+    /// The UUID identifying the file
+    pub hash: String,
+    /// The name the file is stored under, ie `<hash>.<ext>`
+    pub file: String,
+    /// The filename it was uploaded with
+    pub name: String,
+    pub size: u64,
+    pub content_type: String,
+    /// Hex encoded SHA-256 digest of the contents
+    pub sha256: String,
+    /// Name of the API key which uploaded the file
+    pub uploader: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub uploaded: OffsetDateTime,
+    /// When the file expires and should be deleted
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub expires: Option<OffsetDateTime>,
+}
+
+impl InputFile<'_> {
+    /// Reads the whole file, returning its length and hex encoded SHA-256 digest.
+    ///
+    /// Streams cannot be read twice, so return an error
+    pub async fn digest(&mut self) -> io::Result<(u64, String)> {
+        match self {
+            InputFile::TempFile(file) => digest(file.open().await?).await,
+            InputFile::Bytes(bytes) => Ok((bytes.len() as u64, hex::encode(Sha256::digest(bytes)))),
+            InputFile::Path(path) => digest(tokio::fs::File::open(path).await?).await,
+            InputFile::Stream(_) => Err(io::Error::other(
+                "a stream can only be read once, so must be digested as it is saved",
+            )),
+        }
+    }
+
+    /// Reads up to the first `len` bytes of the file.
+    ///
+    /// Streams are read from, then have the bytes which were read put back in front of them
+    pub async fn head(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        let mut head = Vec::with_capacity(len);
+        match self {
+            InputFile::TempFile(file) => {
+                file.open()
+                    .await?
+                    .take(len as u64)
+                    .read_to_end(&mut head)
+                    .await?;
+            }
+            InputFile::Bytes(bytes) => head.extend_from_slice(bytes.get(..len).unwrap_or(bytes)),
+            InputFile::Path(path) => {
+                tokio::fs::File::open(path)
+                    .await?
+                    .take(len as u64)
+                    .read_to_end(&mut head)
+                    .await?;
+            }
+            InputFile::Stream(reader) => {
+                let mut reader = std::mem::replace(reader, Box::pin(tokio::io::empty()));
+                (&mut reader)
+                    .take(len as u64)
+                    .read_to_end(&mut head)
+                    .await?;
+                *self = InputFile::Stream(Box::pin(Cursor::new(head.clone()).chain(reader)));
+            }
+        }
+
+        Ok(head)
+    }
+}
+
+impl Metadata {
+    /// Describes `input`, which is about to be saved as `<hash>.<extension>`
+    pub async fn describe(
+        input: &mut InputFile<'_>,
+        hash: &str,
+        name: &str,
+        extension: &str,
+        uploader: &str,
+    ) -> io::Result<Self> {
+        let (size, sha256) = input.digest().await?;
+        Ok(Metadata::new(hash, name, extension, uploader, size, sha256))
+    }
+
+    pub fn new(
+        hash: &str,
+        name: &str,
+        extension: &str,
+        uploader: &str,
+        size: u64,
+        sha256: String,
+    ) -> Self {
+        let file = format!("{hash}.{extension}");
+
+        Metadata {
+            hash: String::from(hash),
+            content_type: content_type(&file)
+                .unwrap_or(ContentType::Binary)
+                .to_string(),
+            file,
+            name: String::from(name),
+            size,
+            sha256,
+            uploader: String::from(uploader),
+            uploaded: OffsetDateTime::now_utc(),
+            expires: None,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires
+            .is_some_and(|expires| expires <= OffsetDateTime::now_utc())
+    }
+
+    /// Returns the hash of every file with metadata in `names`
+    pub fn hashes(names: &[String]) -> impl Iterator<Item = &str> {
+        names
+            .iter()
+            .filter_map(|name| name.strip_suffix(METADATA_SUFFIX))
+    }
+}
+
+/// One page of names from [`Storage::list`]
+#[derive(Debug, Clone, Default)]
+pub struct Page {
+    pub names: Vec<String>,
+    /// Where the next page starts after, if there are any more names
+    pub next: Option<String>,
+}
+
+impl Page {
+    pub fn new(names: Vec<String>, more: bool) -> Self {
+        let next = if more { names.last().cloned() } else { None };
+        Page { names, next }
+    }
+
+    /// Takes up to `limit` names from `names`, which must already be sorted, starting after `after`
+    pub fn from_sorted<I: IntoIterator<Item = String>>(
+        names: I,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Self {
+        let mut names = names
+            .into_iter()
+            .filter(|name| after.is_none_or(|after| name.as_str() > after))
+            .peekable();
+        let page: Vec<String> = names.by_ref().take(limit).collect();
+        let more = names.peek().is_some();
+        Page::new(page, more)
+    }
+}
+
+/// Details about a stored file, used to answer conditional and range requests
+#[derive(Debug, Clone)]
+pub struct FileInfo {
+    pub len: u64,
+    pub last_modified: Option<SystemTime>,
+    /// Quoted entity tag, eg `"5d41402a"`
+    pub etag: Option<String>,
+}
+
+/// Whether a part of a storage backend is working, reported by `GET /health/ready`
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Check {
+    /// What was checked, eg `storage` or `disk`
+    #[serde(skip)]
+    pub component: &'static str,
+    pub up: bool,
+    /// Why the component is down, or details such as how much space is free
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Check {
+    pub fn up(component: &'static str, detail: Option<String>) -> Self {
+        Check {
+            component,
+            up: true,
+            detail,
+        }
+    }
+
+    pub fn down(component: &'static str, detail: impl Into<String>) -> Self {
+        Check {
+            component,
+            up: false,
+            detail: Some(detail.into()),
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for StoredFile {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        Response::build()
+            .header(self.content_type)
+            .header(Header::new("Content-Length", self.len.to_string()))
+            .streamed_body(self.reader)
+            .ok()
+    }
 }
 #[rocket::async_trait]
 pub trait Storage: Send + Sync {
+    /// Identifies the backend in metrics
+    fn name(&self) -> &'static str {
+        "custom"
+    }
+
     async fn save<'r>(&self, file: InputFile<'r>, filename: &str) -> Result<(), SaveError>;
-    async fn load(&self, filename: &str) -> Result<NamedFile, LoadError>;
+    async fn load(&self, filename: &str) -> Result<StoredFile, LoadError>;
+    async fn stat(&self, filename: &str) -> Result<FileInfo, LoadError>;
     async fn delete(&self, filename: &str) -> Result<(), DeleteError>;
+
+    /// Lists up to `limit` names in storage, including metadata, in lexicographic order starting
+    /// after `after`
+    async fn list(&self, after: Option<&str>, limit: usize) -> Result<Page, LoadError>;
+
+    /// Loads the bytes of `filename` within `range`.
+    ///
+    /// The default implementation reads and discards everything before the range, backends that
+    /// can seek should override it
+    async fn load_range(&self, filename: &str, range: Range<u64>) -> Result<StoredFile, LoadError> {
+        let file = self.load(filename).await?;
+        let mut reader = file.reader;
+
+        tokio::io::copy(&mut (&mut reader).take(range.start), &mut tokio::io::sink())
+            .await
+            .map_err(|e| LoadError::Backend(e.to_string()))?;
+
+        let len = range.end.saturating_sub(range.start);
+        Ok(StoredFile {
+            reader: Box::pin(reader.take(len)),
+            len,
+            content_type: file.content_type,
+        })
+    }
+
+    async fn save_metadata(&self, hash: &str, metadata: &Metadata) -> Result<(), SaveError> {
+        let bytes = serde_json::to_vec(metadata).map_err(SaveError::new)?;
+        self.save(InputFile::Bytes(&bytes), &metadata_name(hash))
+            .await
+    }
+
+    async fn load_metadata(&self, hash: &str) -> Result<Metadata, LoadError> {
+        let mut file = self.load(&metadata_name(hash)).await?;
+        let mut bytes = Vec::new();
+        file.reader
+            .read_to_end(&mut bytes)
+            .await
+            .map_err(|e| LoadError::Backend(e.to_string()))?;
+
+        serde_json::from_slice(&bytes).map_err(|e| LoadError::Backend(e.to_string()))
+    }
+
+    async fn delete_metadata(&self, hash: &str) -> Result<(), DeleteError> {
+        self.delete(&metadata_name(hash)).await
+    }
+
+    /// Checks the backend is able to store files.
+    ///
+    /// The default implementation writes and deletes a small file with [`probe`]
+    async fn health(&self) -> Vec<Check> {
+        vec![probe(self).await]
+    }
+}
+
+/// Checks `storage` can save, read back and delete a small file
+pub async fn probe<S: Storage + ?Sized>(storage: &S) -> Check {
+    const PROBE: &[u8] = b"rumia health probe";
+    let filename = format!("health-probe-{}.tmp", Uuid::new_v4());
+
+    if let Err(e) = storage.save(InputFile::Bytes(PROBE), &filename).await {
+        return Check::down("storage", format!("unable to write: {e}"));
+    }
+    let read = match storage.load(&filename).await {
+        Ok(mut file) => {
+            let mut bytes = Vec::new();
+            file.reader
+                .read_to_end(&mut bytes)
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|_| match bytes == PROBE {
+                    true => Ok(()),
+                    false => Err(String::from("read back different bytes")),
+                })
+        }
+        Err(e) => Err(e.to_string()),
+    };
+    let deleted = storage.delete(&filename).await;
+
+    match (read, deleted) {
+        (Err(e), _) => Check::down("storage", format!("unable to read: {e}")),
+        (_, Err(e)) => Check::down("storage", format!("unable to delete: {e}")),
+        (Ok(()), Ok(())) => Check::up("storage", None),
+    }
+}
+
+/// Lets a caller keep hold of the storage it passes to [`server_with`](crate::server_with), eg to
+/// inspect uploads in tests
+#[rocket::async_trait]
+impl<S: Storage + ?Sized> Storage for Arc<S> {
+    fn name(&self) -> &'static str {
+        (**self).name()
+    }
+
+    async fn save<'r>(&self, file: InputFile<'r>, filename: &str) -> Result<(), SaveError> {
+        (**self).save(file, filename).await
+    }
+
+    async fn load(&self, filename: &str) -> Result<StoredFile, LoadError> {
+        (**self).load(filename).await
+    }
+
+    async fn stat(&self, filename: &str) -> Result<FileInfo, LoadError> {
+        (**self).stat(filename).await
+    }
+
+    async fn delete(&self, filename: &str) -> Result<(), DeleteError> {
+        (**self).delete(filename).await
+    }
+
+    async fn list(&self, after: Option<&str>, limit: usize) -> Result<Page, LoadError> {
+        (**self).list(after, limit).await
+    }
+
+    async fn load_range(&self, filename: &str, range: Range<u64>) -> Result<StoredFile, LoadError> {
+        (**self).load_range(filename, range).await
+    }
+
+    async fn save_metadata(&self, hash: &str, metadata: &Metadata) -> Result<(), SaveError> {
+        (**self).save_metadata(hash, metadata).await
+    }
+
+    async fn load_metadata(&self, hash: &str) -> Result<Metadata, LoadError> {
+        (**self).load_metadata(hash).await
+    }
+
+    async fn delete_metadata(&self, hash: &str) -> Result<(), DeleteError> {
+        (**self).delete_metadata(hash).await
+    }
+
+    async fn health(&self) -> Vec<Check> {
+        (**self).health().await
+    }
+}
+
+/// Reads `reader` to the end, returning how many bytes it had and their hex SHA-256
+async fn digest<R: AsyncRead>(reader: R) -> io::Result<(u64, String)> {
+    let mut reader = std::pin::pin!(reader);
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    let mut len = 0;
+
+    loop {
+        let read = reader.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(buffer.get(..read).unwrap_or_default());
+        len += read as u64;
+    }
+
+    Ok((len, hex::encode(hasher.finalize())))
+}
+
+/// Returns the name metadata for the file `hash` is stored under.
+///
+/// Files are always stored as `<hash>.<ext>`, so this can never clash with an upload
+pub(crate) fn metadata_name(hash: &str) -> String {
+    format!("{hash}{METADATA_SUFFIX}")
+}
+
+fn content_type(filename: &str) -> Option<ContentType> {
+    Path::new(filename)
+        .extension()
+        .and_then(|ext| ContentType::from_extension(&ext.to_string_lossy()))
 }
 
-pub(super) fn init(storage_config: &StorageCommands) -> Box<dyn Storage> {
+/// Creates the storage backend chosen by `storage_config`
+pub fn init(storage_config: &StorageCommands) -> Box<dyn Storage> {
     match storage_config {
-        StorageCommands::FileSystem { path } => Box::new(FileSystemStorage::new(path)),
+        StorageCommands::FileSystem { path, min_free } => {
+            Box::new(FileSystemStorage::new(path).min_free(*min_free))
+        }
+        StorageCommands::S3 {
+            endpoint,
+            bucket,
+            region,
+            access_key,
+            secret_key,
+            path_style,
+        } => Box::new(S3Storage::new(
+            endpoint,
+            bucket,
+            region,
+            access_key,
+            secret_key,
+            *path_style,
+        )),
         StorageCommands::Debug => Box::new(DebugStorage::new()),
     }
 }
@@ -1,23 +1,37 @@
 use crate::error::{DeleteError, LoadError, SaveError};
-use crate::storage::{InputFile, Storage};
-use rocket::fs::NamedFile;
-use std::{collections::HashMap, sync::Arc};
+use crate::storage::{FileInfo, InputFile, Page, Storage, StoredFile};
+use std::{
+    collections::HashMap,
+    io::Cursor,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 use tokio::{io::AsyncReadExt, sync::Mutex};
 
-pub(crate) struct DebugStorage {
-    store: Mutex<HashMap<String, Arc<[u8]>>>,
+#[derive(Clone)]
+struct DebugFile {
+    data: Arc<[u8]>,
+    modified: SystemTime,
+}
+
+/// Keeps files in memory, for tests and trying Rumia out
+#[derive(Default)]
+pub struct DebugStorage {
+    store: Mutex<HashMap<String, DebugFile>>,
 }
 
 #[allow(clippy::unwrap_used)]
 #[rocket::async_trait]
 impl Storage for DebugStorage {
+    fn name(&self) -> &'static str {
+        "debug"
+    }
+
     async fn save<'r>(&self, file: InputFile<'r>, filename: &str) -> Result<(), SaveError> {
-        match file {
-            InputFile::Bytes(bytes) => {
-                self.store
-                    .lock()
-                    .await
-                    .insert(String::from(filename), Arc::from(bytes));
+        let data = match file {
+            InputFile::Bytes(bytes) => Arc::from(bytes),
+            InputFile::Path(path) => {
+                Arc::from(tokio::fs::read(path).await.map_err(SaveError::new)?)
             }
             InputFile::TempFile(file) => {
                 let mut buffer = vec![0; usize::try_from(file.len()).unwrap()];
@@ -29,40 +43,78 @@ impl Storage for DebugStorage {
                     .await
                     .unwrap();
 
-                self.store
-                    .lock()
+                Arc::from(buffer)
+            }
+            InputFile::Stream(mut reader) => {
+                let mut buffer = Vec::new();
+                reader
+                    .read_to_end(&mut buffer)
                     .await
-                    .insert(String::from(filename), Arc::from(buffer));
+                    .map_err(SaveError::new)?;
+
+                Arc::from(buffer)
             }
-        }
+        };
+
+        self.store.lock().await.insert(
+            String::from(filename),
+            DebugFile {
+                data,
+                modified: SystemTime::now(),
+            },
+        );
 
         Ok(())
     }
 
-    async fn load(&self, filename: &str) -> Result<NamedFile, LoadError> {
-        let data;
-        {
-            data = Arc::clone(self.store.lock().await.get(filename).ok_or(
-                LoadError::FileNotExist(format!("file {filename} does not exist")),
-            )?);
-        }
+    async fn load(&self, filename: &str) -> Result<StoredFile, LoadError> {
+        let file = self.get(filename).await?;
+        let len = file.data.len() as u64;
+        Ok(StoredFile::new(Cursor::new(file.data), len, filename))
+    }
+
+    async fn stat(&self, filename: &str) -> Result<FileInfo, LoadError> {
+        let file = self.get(filename).await?;
+        let modified_nanos = file
+            .modified
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_nanos())
+            .unwrap_or_default();
 
-        tokio::fs::write(filename, &*data).await.unwrap();
-        let file = NamedFile::open(filename).await;
-        tokio::fs::remove_file(filename).await.unwrap();
-        file.map_err(|_| LoadError::FileNotExist(format!("file {filename} does not exist")))
+        Ok(FileInfo {
+            len: file.data.len() as u64,
+            last_modified: Some(file.modified),
+            etag: Some(format!("\"{:x}-{modified_nanos:x}\"", file.data.len())),
+        })
     }
 
     async fn delete(&self, filename: &str) -> Result<(), DeleteError> {
         self.store.lock().await.remove(filename);
         Ok(())
     }
+
+    async fn list(&self, after: Option<&str>, limit: usize) -> Result<Page, LoadError> {
+        let mut names: Vec<String> = self.store.lock().await.keys().cloned().collect();
+        names.sort();
+        Ok(Page::from_sorted(names, after, limit))
+    }
 }
 
 impl DebugStorage {
-    pub(super) fn new() -> Self {
+    pub fn new() -> Self {
         DebugStorage {
             store: Mutex::new(HashMap::new()),
         }
     }
+
+    async fn get(&self, filename: &str) -> Result<DebugFile, LoadError> {
+        self.store
+            .lock()
+            .await
+            .get(filename)
+            .cloned()
+            .ok_or(LoadError::FileNotExist(format!(
+                "file {filename} does not exist"
+            )))
+    }
 }
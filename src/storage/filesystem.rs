@@ -1,14 +1,25 @@
-use super::{InputFile, Storage};
+use super::{Check, FileInfo, InputFile, Page, Storage, StoredFile, probe};
 use crate::error::{DeleteError, LoadError, SaveError};
-use rocket::fs::NamedFile;
-use std::path::Path;
+use std::{
+    io::SeekFrom,
+    ops::Range,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 
 pub struct FileSystemStorage {
-    path: &'static Path,
+    path: PathBuf,
+    /// Free space, in bytes, below which the disk is reported as down
+    min_free: u64,
 }
 
 #[rocket::async_trait]
 impl Storage for FileSystemStorage {
+    fn name(&self) -> &'static str {
+        "filesystem"
+    }
+
     async fn save<'r>(&self, file: InputFile<'r>, filename: &str) -> Result<(), SaveError> {
         match file {
             InputFile::TempFile(file) => {
@@ -21,17 +32,37 @@ impl Storage for FileSystemStorage {
                     .await
                     .map_err(SaveError::new)?;
             }
+            InputFile::Path(path) => {
+                tokio::fs::copy(path, self.path.join(filename))
+                    .await
+                    .map_err(SaveError::new)?;
+            }
+            InputFile::Stream(mut reader) => {
+                let mut file = tokio::fs::File::create(self.path.join(filename))
+                    .await
+                    .map_err(SaveError::new)?;
+                tokio::io::copy(&mut reader, &mut file)
+                    .await
+                    .map_err(SaveError::new)?;
+                file.flush().await.map_err(SaveError::new)?;
+            }
         }
 
         Ok(())
     }
 
-    async fn load(&self, filename: &str) -> Result<NamedFile, LoadError> {
+    async fn load(&self, filename: &str) -> Result<StoredFile, LoadError> {
         let file_path = self.path.join(filename);
         if file_path.exists() {
-            NamedFile::open(file_path)
+            let file = tokio::fs::File::open(file_path)
                 .await
-                .map_err(|e| LoadError::PermissionDenied(e.to_string()))
+                .map_err(|e| LoadError::PermissionDenied(e.to_string()))?;
+            let len = file
+                .metadata()
+                .await
+                .map_err(|e| LoadError::PermissionDenied(e.to_string()))?
+                .len();
+            Ok(StoredFile::new(file, len, filename))
         } else {
             Err(LoadError::FileNotExist(format!(
                 "file {filename} does not exist"
@@ -39,17 +70,108 @@ impl Storage for FileSystemStorage {
         }
     }
 
+    async fn stat(&self, filename: &str) -> Result<FileInfo, LoadError> {
+        let metadata = tokio::fs::metadata(self.path.join(filename))
+            .await
+            .map_err(|_| LoadError::FileNotExist(format!("file {filename} does not exist")))?;
+
+        let last_modified = metadata.modified().ok();
+        let modified_nanos = last_modified
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|duration| duration.as_nanos())
+            .unwrap_or_default();
+
+        Ok(FileInfo {
+            len: metadata.len(),
+            last_modified,
+            etag: Some(format!("\"{:x}-{modified_nanos:x}\"", metadata.len())),
+        })
+    }
+
+    /// Directories aren't read in order, so every page reads the whole directory, costing
+    /// O(files) per page. Only the names after `after` are kept, and only the page is sorted
+    async fn list(&self, after: Option<&str>, limit: usize) -> Result<Page, LoadError> {
+        let mut entries = tokio::fs::read_dir(&self.path)
+            .await
+            .map_err(|e| LoadError::PermissionDenied(e.to_string()))?;
+
+        let mut names = Vec::new();
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| LoadError::PermissionDenied(e.to_string()))?
+        {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if after.is_some_and(|after| name.as_str() <= after) {
+                continue;
+            }
+            if entry.file_type().await.is_ok_and(|kind| kind.is_file()) {
+                names.push(name);
+            }
+        }
+
+        let more = names.len() > limit;
+        if more {
+            // Moves the first `limit` names to the front, in no particular order
+            names.select_nth_unstable(limit);
+            names.truncate(limit);
+        }
+        names.sort_unstable();
+        Ok(Page::new(names, more))
+    }
+
+    async fn load_range(&self, filename: &str, range: Range<u64>) -> Result<StoredFile, LoadError> {
+        let mut file = tokio::fs::File::open(self.path.join(filename))
+            .await
+            .map_err(|_| LoadError::FileNotExist(format!("file {filename} does not exist")))?;
+
+        file.seek(SeekFrom::Start(range.start))
+            .await
+            .map_err(|e| LoadError::PermissionDenied(e.to_string()))?;
+
+        let len = range.end.saturating_sub(range.start);
+        Ok(StoredFile::new(file.take(len), len, filename))
+    }
+
     async fn delete(&self, filename: &str) -> Result<(), DeleteError> {
         let file_path = self.path.join(filename);
         tokio::fs::remove_file(file_path)
             .await
             .map_err(DeleteError::new)
     }
+
+    async fn health(&self) -> Vec<Check> {
+        vec![probe(self).await, self.disk().await]
+    }
 }
 
 impl FileSystemStorage {
-    pub(super) fn new(path: &'static Path) -> Self {
-        FileSystemStorage { path }
+    pub(super) fn new(path: &Path) -> Self {
+        FileSystemStorage {
+            path: path.to_path_buf(),
+            min_free: 0,
+        }
+    }
+
+    pub(super) fn min_free(self, min_free: u64) -> Self {
+        FileSystemStorage { min_free, ..self }
+    }
+
+    /// Checks there is at least `min_free` space left on the disk
+    async fn disk(&self) -> Check {
+        let path = self.path.clone();
+        let available = tokio::task::spawn_blocking(move || fs4::available_space(path)).await;
+        match available {
+            Ok(Ok(free)) if free >= self.min_free => {
+                Check::up("disk", Some(format!("{free} bytes free")))
+            }
+            Ok(Ok(free)) => Check::down(
+                "disk",
+                format!("{free} bytes free, less than {}", self.min_free),
+            ),
+            Ok(Err(e)) => Check::down("disk", format!("unable to check free space: {e}")),
+            Err(e) => Check::down("disk", e.to_string()),
+        }
     }
 }
 
@@ -57,8 +179,7 @@ impl FileSystemStorage {
 mod tests {
     use super::*;
     use sha2::{Digest, Sha256};
-    use std::{path::PathBuf, sync::LazyLock};
-    use tokio::io::AsyncReadExt;
+    use std::sync::LazyLock;
 
     const TEST_FILE_NAME: &str = "test.png";
     static FILE_PATH: LazyLock<PathBuf> =
@@ -89,17 +210,74 @@ mod tests {
         assert_eq!(result[..], FILE_HASH[..]);
     }
 
+    #[tokio::test]
+    async fn save_test_stream() {
+        let storage = FileSystemStorage::new(&FILE_PATH);
+        let file = tokio::fs::File::open(&*TEST_FILE).await.unwrap();
+
+        storage
+            .save(InputFile::Stream(Box::pin(file)), "teststream.png")
+            .await
+            .unwrap();
+        let saved_bytes = tokio::fs::read(FILE_PATH.join("teststream.png"))
+            .await
+            .unwrap();
+        let result = Sha256::digest(saved_bytes);
+
+        tokio::fs::remove_file(FILE_PATH.join("teststream.png"))
+            .await
+            .unwrap();
+        assert_eq!(result[..], FILE_HASH[..]);
+    }
+
     #[tokio::test]
     async fn load_test_file() {
         let storage = FileSystemStorage::new(&FILE_PATH);
 
-        let loaded = storage.load(TEST_FILE_NAME).await.unwrap();
-        let mut buffer = vec![0; loaded.metadata().await.unwrap().len() as usize];
-        loaded.take_file().read_exact(&mut buffer).await.unwrap();
+        let mut loaded = storage.load(TEST_FILE_NAME).await.unwrap();
+        let mut buffer = vec![0; loaded.len as usize];
+        loaded.reader.read_exact(&mut buffer).await.unwrap();
         let result = Sha256::digest(buffer);
         assert_eq!(result[..], FILE_HASH[..]);
     }
 
+    #[tokio::test]
+    async fn load_test_file_range() {
+        let storage = FileSystemStorage::new(&FILE_PATH);
+        let bytes = tokio::fs::read(&*TEST_FILE).await.unwrap();
+
+        let mut loaded = storage.load_range(TEST_FILE_NAME, 10..100).await.unwrap();
+        let mut buffer = Vec::new();
+        loaded.reader.read_to_end(&mut buffer).await.unwrap();
+
+        assert_eq!(loaded.len, 90);
+        assert_eq!(buffer[..], bytes[10..100]);
+    }
+
+    #[tokio::test]
+    async fn list_test_files() {
+        let dir = std::env::temp_dir().join(format!("rumia-list-{}", uuid::Uuid::new_v4()));
+        tokio::fs::create_dir_all(dir.join("subdir")).await.unwrap();
+        for name in ["b.png", "a.png", "c.meta.json"] {
+            tokio::fs::write(dir.join(name), name).await.unwrap();
+        }
+        let storage = FileSystemStorage::new(&dir);
+
+        let page = storage.list(None, 100).await.unwrap();
+        assert_eq!(page.names, ["a.png", "b.png", "c.meta.json"]);
+        assert!(page.next.is_none());
+
+        let first = storage.list(None, 2).await.unwrap();
+        assert_eq!(first.names, ["a.png", "b.png"]);
+        assert_eq!(first.next.as_deref(), Some("b.png"));
+
+        let second = storage.list(first.next.as_deref(), 2).await.unwrap();
+        assert_eq!(second.names, ["c.meta.json"]);
+        assert!(second.next.is_none());
+
+        tokio::fs::remove_dir_all(dir).await.unwrap();
+    }
+
     #[tokio::test]
     async fn delete_test_file() {
         let storage = FileSystemStorage::new(&FILE_PATH);
@@ -112,4 +290,25 @@ mod tests {
         storage.delete("delete_test.txt").await.unwrap();
         assert!(!FILE_PATH.join("delete_test.txt").exists());
     }
+
+    #[tokio::test]
+    async fn reports_health() {
+        let checks = FileSystemStorage::new(&FILE_PATH).health().await;
+        assert!(checks.iter().all(|check| check.up), "{checks:?}");
+        assert_eq!(checks.len(), 2);
+
+        let checks = FileSystemStorage::new(&FILE_PATH)
+            .min_free(u64::MAX)
+            .health()
+            .await;
+        let disk = checks
+            .iter()
+            .find(|check| check.component == "disk")
+            .unwrap();
+        assert!(!disk.up);
+
+        let missing = FILE_PATH.join("missing");
+        let checks = FileSystemStorage::new(&missing).health().await;
+        assert!(checks.iter().all(|check| !check.up), "{checks:?}");
+    }
 }
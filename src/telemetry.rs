@@ -0,0 +1,424 @@
+//! OpenTelemetry traces of requests, their handlers, storage operations and remote fetches,
+//! exported over OTLP. Requests with a W3C `traceparent` header continue the caller's trace
+
+use crate::{
+    Context,
+    error::{DeleteError, LoadError, SaveError},
+    logging::RequestId,
+    settings::Settings,
+    storage::{Check, FileInfo, InputFile, Metadata, Page, Storage, StoredFile},
+};
+use opentelemetry::{
+    Context as TraceContext, KeyValue,
+    context::FutureExt,
+    propagation::{Extractor, TextMapPropagator},
+    trace::{SpanKind, Status as SpanStatus, TraceContextExt, Tracer, TracerProvider},
+};
+use opentelemetry_otlp::{ExporterBuildError, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    Resource,
+    propagation::TraceContextPropagator,
+    trace::{SdkTracer, SdkTracerProvider, TracerProviderBuilder},
+};
+use rocket::{
+    Data, Orbit, Request, Response, Rocket, Route,
+    fairing::{Fairing, Info, Kind},
+    http::HeaderMap,
+    route::{self, Handler},
+};
+use std::{borrow::Cow, fmt::Display, ops::Range, sync::Arc};
+
+/// Where a server's spans are sent. Spans are always created, so requests can be followed through
+/// the server, but are only exported if a collector is configured
+#[derive(Clone)]
+pub(crate) struct Telemetry {
+    provider: SdkTracerProvider,
+    tracer: SdkTracer,
+}
+
+/// Starts a span for every request, continuing the trace from its `traceparent` header if it has
+/// one, and exports any spans left over when the server shuts down
+pub(crate) struct RequestSpans;
+
+/// The span of the current request, kept in its local cache
+struct RequestSpan(TraceContext);
+
+/// Runs a route's handler, along with its guards, in a span of its own
+#[derive(Clone)]
+struct Traced {
+    name: Cow<'static, str>,
+    handler: Box<dyn Handler>,
+}
+
+/// Traces each operation on the wrapped storage
+pub(crate) struct TracedStorage {
+    storage: Box<dyn Storage>,
+    telemetry: Telemetry,
+}
+
+/// Reads trace context from the headers of a request
+struct Headers<'a>(&'a HeaderMap<'a>);
+
+impl Default for Telemetry {
+    fn default() -> Self {
+        Telemetry::new(SdkTracerProvider::builder())
+    }
+}
+
+impl Telemetry {
+    /// Exports spans to the OTLP collector from the settings, if there is one
+    pub(crate) fn from_settings(settings: &Settings) -> Result<Self, ExporterBuildError> {
+        let Some(endpoint) = &settings.otlp_endpoint else {
+            return Ok(Telemetry::default());
+        };
+
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+            .build()?;
+        Ok(Telemetry::new(
+            SdkTracerProvider::builder().with_batch_exporter(exporter),
+        ))
+    }
+
+    fn new(builder: TracerProviderBuilder) -> Self {
+        let provider = builder
+            .with_resource(Resource::builder().with_service_name("rumia").build())
+            .build();
+        let tracer = provider.tracer("rumia");
+        Telemetry { provider, tracer }
+    }
+
+    /// Starts a span as a child of the current one. It ends once every clone of the returned
+    /// context has been dropped
+    pub(crate) fn span(
+        &self,
+        name: impl Into<Cow<'static, str>>,
+        kind: SpanKind,
+        attributes: Vec<KeyValue>,
+    ) -> TraceContext {
+        let parent = TraceContext::current();
+        let span = self
+            .tracer
+            .span_builder(name)
+            .with_kind(kind)
+            .with_attributes(attributes)
+            .start_with_context(&self.tracer, &parent);
+        parent.with_span(span)
+    }
+
+    /// Exports every span which has ended, blocking until the collector has received them
+    pub(crate) fn flush(&self) {
+        if let Err(e) = self.provider.force_flush() {
+            warn_!("Unable to export traces: {e}");
+        }
+    }
+}
+
+/// Marks the span of `cx` as failed if `result` is an error
+pub(crate) fn record<T, E: Display>(cx: &TraceContext, result: &Result<T, E>) {
+    if let Err(e) = result {
+        cx.span().set_status(SpanStatus::error(e.to_string()));
+    }
+}
+
+/// Wraps the handler of every route in `routes` in a span named after the route
+pub(crate) fn instrument(routes: Vec<Route>) -> Vec<Route> {
+    routes
+        .into_iter()
+        .map(|mut route| {
+            route.handler = Box::new(Traced {
+                name: route.name.clone().unwrap_or(Cow::Borrowed("handler")),
+                handler: route.handler.clone(),
+            });
+            route
+        })
+        .collect()
+}
+
+impl Extractor for Headers<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get_one(key)
+    }
+
+    /// Only needed by propagators which read every header, which the W3C one doesn't
+    fn keys(&self) -> Vec<&str> {
+        Vec::new()
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for RequestSpans {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request spans",
+            kind: Kind::Request | Kind::Response | Kind::Shutdown,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let Some(context) = request.rocket().state::<Arc<Context>>() else {
+            return;
+        };
+        let parent = TraceContextPropagator::new().extract(&Headers(request.headers()));
+        let method = request.method().as_str();
+        let span = context
+            .telemetry
+            .tracer
+            .span_builder(method)
+            .with_kind(SpanKind::Server)
+            .with_attributes([
+                KeyValue::new("http.request.method", method),
+                KeyValue::new("url.path", request.uri().path().to_string()),
+                KeyValue::new("rumia.request_id", RequestId::of(request).0),
+            ])
+            .start_with_context(&context.telemetry.tracer, &parent);
+        request.local_cache(|| RequestSpan(parent.with_span(span)));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let span = request
+            .local_cache(|| RequestSpan(TraceContext::new()))
+            .0
+            .span();
+        let status = response.status();
+        if let Some(route) = request.route() {
+            span.update_name(format!("{} {}", request.method(), route.uri.path()));
+            span.set_attribute(KeyValue::new("http.route", route.uri.path().to_owned()));
+        }
+        span.set_attribute(KeyValue::new(
+            "http.response.status_code",
+            i64::from(status.code),
+        ));
+        if status.code >= 500 {
+            span.set_status(SpanStatus::error(status.to_string()));
+        }
+        span.end();
+    }
+
+    async fn on_shutdown(&self, rocket: &Rocket<Orbit>) {
+        let Some(context) = rocket.state::<Arc<Context>>().map(Arc::clone) else {
+            return;
+        };
+        tokio::task::spawn_blocking(move || context.telemetry.flush())
+            .await
+            .ok();
+    }
+}
+
+#[rocket::async_trait]
+impl Handler for Traced {
+    async fn handle<'r>(&self, request: &'r Request<'_>, data: Data<'r>) -> route::Outcome<'r> {
+        let parent = &request.local_cache(|| RequestSpan(TraceContext::new())).0;
+        let Some(context) = request.rocket().state::<Arc<Context>>() else {
+            return self.handler.handle(request, data).await;
+        };
+
+        let span = context
+            .telemetry
+            .tracer
+            .span_builder(self.name.clone())
+            .with_attributes([KeyValue::new("code.function.name", self.name.clone())])
+            .start_with_context(&context.telemetry.tracer, parent);
+        let cx = parent.with_span(span);
+        let outcome = self
+            .handler
+            .handle(request, data)
+            .with_context(cx.clone())
+            .await;
+        if let route::Outcome::Error(status) = &outcome {
+            cx.span().set_status(SpanStatus::error(status.to_string()));
+        }
+        outcome
+    }
+}
+
+impl TracedStorage {
+    pub(crate) fn new(storage: Box<dyn Storage>, telemetry: Telemetry) -> Self {
+        TracedStorage { storage, telemetry }
+    }
+
+    async fn trace<T, E: Display>(
+        &self,
+        operation: &'static str,
+        key: &str,
+        future: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, E> {
+        let cx = self.telemetry.span(
+            format!("storage {operation}"),
+            SpanKind::Internal,
+            vec![
+                KeyValue::new("rumia.storage.backend", self.storage.name()),
+                KeyValue::new("rumia.storage.operation", operation),
+                KeyValue::new("rumia.storage.key", key.to_owned()),
+            ],
+        );
+        let result = future.with_context(cx.clone()).await;
+        record(&cx, &result);
+        result
+    }
+}
+
+#[rocket::async_trait]
+impl Storage for TracedStorage {
+    fn name(&self) -> &'static str {
+        self.storage.name()
+    }
+
+    async fn save<'r>(&self, file: InputFile<'r>, filename: &str) -> Result<(), SaveError> {
+        self.trace("save", filename, self.storage.save(file, filename))
+            .await
+    }
+
+    async fn load(&self, filename: &str) -> Result<StoredFile, LoadError> {
+        self.trace("load", filename, self.storage.load(filename))
+            .await
+    }
+
+    async fn stat(&self, filename: &str) -> Result<FileInfo, LoadError> {
+        self.trace("stat", filename, self.storage.stat(filename))
+            .await
+    }
+
+    async fn delete(&self, filename: &str) -> Result<(), DeleteError> {
+        self.trace("delete", filename, self.storage.delete(filename))
+            .await
+    }
+
+    async fn list(&self, after: Option<&str>, limit: usize) -> Result<Page, LoadError> {
+        self.trace("list", after.unwrap_or(""), self.storage.list(after, limit))
+            .await
+    }
+
+    async fn load_range(&self, filename: &str, range: Range<u64>) -> Result<StoredFile, LoadError> {
+        self.trace(
+            "load_range",
+            filename,
+            self.storage.load_range(filename, range),
+        )
+        .await
+    }
+
+    async fn save_metadata(&self, hash: &str, metadata: &Metadata) -> Result<(), SaveError> {
+        self.trace(
+            "save_metadata",
+            hash,
+            self.storage.save_metadata(hash, metadata),
+        )
+        .await
+    }
+
+    async fn load_metadata(&self, hash: &str) -> Result<Metadata, LoadError> {
+        self.trace("load_metadata", hash, self.storage.load_metadata(hash))
+            .await
+    }
+
+    async fn delete_metadata(&self, hash: &str) -> Result<(), DeleteError> {
+        self.trace("delete_metadata", hash, self.storage.delete_metadata(hash))
+            .await
+    }
+
+    async fn health(&self) -> Vec<Check> {
+        let cx = self.telemetry.span(
+            "storage health",
+            SpanKind::Internal,
+            vec![KeyValue::new("rumia.storage.backend", self.storage.name())],
+        );
+        let checks = self.storage.health().with_context(cx.clone()).await;
+        if let Some(down) = checks.iter().find(|check| !check.up) {
+            let detail = down.detail.clone().unwrap_or_default();
+            cx.span()
+                .set_status(SpanStatus::error(format!("{}: {detail}", down.component)));
+        }
+        checks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::{http::Header, local::blocking::Client};
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        sync::mpsc,
+        time::Duration,
+    };
+
+    /// Stands in for an OTLP collector, sending the path and body of every request it receives
+    fn collector() -> (String, mpsc::Receiver<(String, Vec<u8>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = BufReader::new(stream.unwrap());
+                let mut line = String::new();
+                stream.read_line(&mut line).unwrap();
+                let path = line.split(' ').nth(1).unwrap().to_owned();
+
+                let mut len = 0;
+                loop {
+                    line.clear();
+                    stream.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':')
+                        && name.eq_ignore_ascii_case("content-length")
+                    {
+                        len = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; len];
+                stream.read_exact(&mut body).unwrap();
+                stream
+                    .get_mut()
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                    .unwrap();
+                sender.send((path, body)).unwrap();
+            }
+        });
+
+        (endpoint, receiver)
+    }
+
+    #[test]
+    fn exports_spans_in_the_callers_trace() {
+        let (endpoint, exported) = collector();
+        let settings = Settings {
+            api_key: Some(String::from("12345")),
+            secret: Some(String::from("secret")),
+            otlp_endpoint: Some(endpoint),
+            ..Settings::default()
+        };
+        let storage = Box::new(crate::storage::DebugStorage::new());
+        let client = Client::untracked(crate::server_with(settings, storage).unwrap()).unwrap();
+
+        let trace_id = "0af7651916cd43dd8448eb211c80319c";
+        let resp = client
+            .get("/api/files")
+            .header(Header::new("x-api-key", "12345"))
+            .header(Header::new(
+                "traceparent",
+                format!("00-{trace_id}-b7ad6b7169203331-01"),
+            ))
+            .dispatch();
+        assert_eq!(resp.status(), rocket::http::Status::Ok);
+        client
+            .rocket()
+            .state::<Arc<Context>>()
+            .unwrap()
+            .telemetry
+            .flush();
+
+        let (path, body) = exported.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(path, "/v1/traces");
+        let contains = |needle: &[u8]| body.windows(needle.len()).any(|window| window == needle);
+        assert!(contains(&hex::decode(trace_id).unwrap()));
+        for span in ["GET /api/files", "list_files", "storage list"] {
+            assert!(contains(span.as_bytes()), "{span} was not exported");
+        }
+    }
+}
@@ -0,0 +1,332 @@
+//! The certificate HTTPS is served with, reloaded whenever its files change. Connections are
+//! accepted and decrypted by [`Listen`](crate::listen::Listen)
+
+use crate::settings::Settings;
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio::time::{Instant, interval_at};
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        RootCertStore, ServerConfig,
+        pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+        server::WebPkiClientVerifier,
+    },
+};
+
+/// Serves HTTPS with the certificate from [`Files`], reloading it when they change
+pub(crate) struct Tls {
+    files: Files,
+    config: RwLock<Arc<ServerConfig>>,
+    /// When each file was last modified as of the current config, to tell when they are rotated
+    modified: Mutex<Vec<Option<SystemTime>>>,
+}
+
+/// The PEM files HTTPS is configured from
+struct Files {
+    cert: PathBuf,
+    key: PathBuf,
+    /// If set, clients must present a certificate signed by one of these CAs
+    client_ca: Option<PathBuf>,
+}
+
+impl Tls {
+    /// Loads the TLS files from the settings, if HTTPS is enabled
+    pub(crate) fn from_settings(settings: &Settings) -> Result<Option<Self>, String> {
+        let files = match (&settings.tls_cert, &settings.tls_key) {
+            (None, None) if settings.tls_client_ca.is_none() => return Ok(None),
+            (Some(cert), Some(key)) => Files {
+                cert: cert.clone(),
+                key: key.clone(),
+                client_ca: settings.tls_client_ca.clone(),
+            },
+            _ => {
+                return Err(String::from(
+                    "TLS needs both a certificate and a key, set RUMIA_TLS_CERT and RUMIA_TLS_KEY",
+                ));
+            }
+        };
+
+        Tls::new(files).map(Some)
+    }
+
+    fn new(files: Files) -> Result<Self, String> {
+        let modified = files.modified();
+        Ok(Tls {
+            config: RwLock::new(Arc::new(files.load()?)),
+            modified: Mutex::new(modified),
+            files,
+        })
+    }
+
+    /// Reloads the files if any have changed since they were last loaded, returning whether they
+    /// were. If they can't be loaded, eg a new certificate has been written but not yet its key,
+    /// the current config is kept and they are tried again next time
+    pub(crate) fn reload(&self) -> Result<bool, String> {
+        let modified = self.files.modified();
+        let mut current = self.modified.lock().map_err(|e| e.to_string())?;
+        if *current == modified {
+            return Ok(false);
+        }
+
+        let config = self.files.load()?;
+        *self.config.write().map_err(|e| e.to_string())? = Arc::new(config);
+        *current = modified;
+        Ok(true)
+    }
+
+    pub(crate) fn acceptor(&self) -> Option<TlsAcceptor> {
+        let config = self.config.read().ok()?;
+        Some(TlsAcceptor::from(Arc::clone(&config)))
+    }
+
+    /// Checks the files for changes every `period` in the background
+    pub(crate) fn watch(self: Arc<Self>, period: Duration) {
+        let period = period.max(Duration::from_secs(1));
+        tokio::spawn(async move {
+            let mut interval = interval_at(Instant::now() + period, period);
+            loop {
+                interval.tick().await;
+                match self.reload() {
+                    Ok(true) => info_!("Reloaded the TLS certificate"),
+                    Ok(false) => {}
+                    Err(e) => warn_!("Unable to reload the TLS certificate: {e}"),
+                }
+            }
+        });
+    }
+}
+
+impl Files {
+    fn load(&self) -> Result<ServerConfig, String> {
+        let certs = read_certs(&self.cert)?;
+        let key = PrivateKeyDer::from_pem_file(&self.key)
+            .map_err(|e| format!("unable to read TLS key {}: {e}", self.key.display()))?;
+
+        let builder = match &self.client_ca {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in read_certs(path)? {
+                    roots
+                        .add(cert)
+                        .map_err(|e| format!("invalid client CA {}: {e}", path.display()))?;
+                }
+                let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                    .build()
+                    .map_err(|e| format!("invalid client CA {}: {e}", path.display()))?;
+                ServerConfig::builder().with_client_cert_verifier(verifier)
+            }
+            None => ServerConfig::builder().with_no_client_auth(),
+        };
+
+        let mut config = builder
+            .with_single_cert(certs, key)
+            .map_err(|e| format!("invalid TLS certificate or key: {e}"))?;
+        // Connections are forwarded to Rocket as they are, which only speaks HTTP/1.1
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(config)
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        [Some(&self.cert), Some(&self.key), self.client_ca.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(|path| {
+                std::fs::metadata(path)
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+            })
+            .collect()
+    }
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    CertificateDer::pem_file_iter(path)
+        .and_then(Iterator::collect)
+        .map_err(|e| format!("unable to read certificates from {}: {e}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listen::{Forwarder, serve_tcp};
+    use std::net::SocketAddr;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_rustls::{
+        TlsConnector,
+        rustls::{ClientConfig, pki_types::ServerName},
+    };
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("resources/test/tls")
+            .join(name)
+    }
+
+    /// Copies the server certificate and key somewhere they can be rotated
+    fn staged(client_ca: bool) -> Files {
+        let dir = std::env::temp_dir().join(format!("rumia-tls-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::copy(fixture("server.pem"), dir.join("cert.pem")).unwrap();
+        std::fs::copy(fixture("server.key"), dir.join("key.pem")).unwrap();
+
+        Files {
+            cert: dir.join("cert.pem"),
+            key: dir.join("key.pem"),
+            client_ca: client_ca.then(|| fixture("ca.pem")),
+        }
+    }
+
+    /// Serves `tls` in front of a backend which answers every connection with `ok`
+    async fn start(tls: Tls) -> (Arc<Tls>, SocketAddr) {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = backend.accept().await.unwrap();
+                let mut buffer = [0; 1024];
+                let _ = stream.read(&mut buffer).await;
+                let _ = stream
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok",
+                    )
+                    .await;
+            }
+        });
+
+        let tls = Arc::new(tls);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let forwarder = Forwarder::new(Some(Arc::clone(&tls)), backend_addr);
+        tokio::spawn(serve_tcp(listener, forwarder));
+        (tls, addr)
+    }
+
+    /// Makes a request over TLS, returning the name of the certificate the server presented and
+    /// the response
+    async fn request(addr: SocketAddr, client_cert: bool) -> std::io::Result<(String, String)> {
+        let mut roots = RootCertStore::empty();
+        roots.add_parsable_certificates(read_certs(&fixture("ca.pem")).unwrap());
+        let builder = ClientConfig::builder().with_root_certificates(roots);
+        let config = if client_cert {
+            let key = PrivateKeyDer::from_pem_file(fixture("client.key")).unwrap();
+            builder
+                .with_client_auth_cert(read_certs(&fixture("client.pem")).unwrap(), key)
+                .unwrap()
+        } else {
+            builder.with_no_client_auth()
+        };
+
+        let stream = TcpStream::connect(addr).await?;
+        let name = ServerName::try_from("localhost").unwrap();
+        let mut stream = TlsConnector::from(Arc::new(config))
+            .connect(name, stream)
+            .await?;
+        stream
+            .write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+
+        let cert = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .unwrap()
+            .first()
+            .unwrap();
+        let names = ["server", "renewed"];
+        let name = names
+            .into_iter()
+            .find(|name| {
+                read_certs(&fixture(&format!("{name}.pem")))
+                    .unwrap()
+                    .first()
+                    == Some(cert)
+            })
+            .unwrap_or("unknown");
+        Ok((String::from(name), response))
+    }
+
+    #[tokio::test]
+    async fn serves_https_and_reloads_rotated_certificates() {
+        let files = staged(false);
+        let (cert, key) = (files.cert.clone(), files.key.clone());
+        let (tls, addr) = start(Tls::new(files).unwrap()).await;
+
+        let (name, response) = request(addr, false).await.unwrap();
+        assert_eq!(name, "server");
+        assert!(response.starts_with("HTTP/1.1 200 OK") && response.ends_with("ok"));
+        assert_eq!(tls.reload(), Ok(false));
+
+        // Half way through a rotation the new certificate doesn't match the old key
+        std::fs::copy(fixture("renewed.pem"), &cert).unwrap();
+        assert!(tls.reload().is_err());
+        assert_eq!(request(addr, false).await.unwrap().0, "server");
+
+        std::fs::copy(fixture("renewed.key"), &key).unwrap();
+        assert_eq!(tls.reload(), Ok(true));
+        assert_eq!(tls.reload(), Ok(false));
+        assert_eq!(request(addr, false).await.unwrap().0, "renewed");
+    }
+
+    #[tokio::test]
+    async fn requires_client_certificates() {
+        let (_, addr) = start(Tls::new(staged(true)).unwrap()).await;
+
+        assert!(request(addr, false).await.is_err());
+        let (_, response) = request(addr, true).await.unwrap();
+        assert!(response.ends_with("ok"));
+    }
+
+    #[tokio::test]
+    async fn serves_rocket_over_https() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let settings = Settings {
+            ip: std::net::Ipv4Addr::LOCALHOST.into(),
+            port,
+            tls_cert: Some(fixture("server.pem")),
+            tls_key: Some(fixture("server.key")),
+            ..Settings::default()
+        };
+        let storage = Box::new(crate::storage::DebugStorage::new());
+        tokio::spawn(crate::server_with(settings, storage).unwrap().launch());
+
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        for _ in 0..50 {
+            if let Ok((_, response)) = request(addr, false).await {
+                assert!(response.starts_with("HTTP/1.1 200 OK"));
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        unreachable!("the server never answered over HTTPS");
+    }
+
+    #[test]
+    fn needs_a_certificate_and_key() {
+        let settings = Settings {
+            tls_cert: Some(fixture("server.pem")),
+            ..Settings::default()
+        };
+        assert!(Tls::from_settings(&settings).is_err());
+        assert!(Tls::from_settings(&Settings::default()).unwrap().is_none());
+
+        let settings = Settings {
+            tls_cert: Some(fixture("server.pem")),
+            tls_key: Some(fixture("renewed.key")),
+            ..Settings::default()
+        };
+        assert!(Tls::from_settings(&settings).is_err());
+    }
+}
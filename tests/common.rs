@@ -1,11 +1,15 @@
 #![allow(dead_code, clippy::unwrap_used)]
 
 use rocket::{http::ContentType, local::blocking::Client};
-use rumia::{STORAGE, server, storage::InputFile};
+use rumia::{
+    server_with,
+    settings::Settings,
+    storage::{DebugStorage, InputFile, Storage},
+};
 use sha2::{Digest, Sha256};
 use std::{
     path::{Path, PathBuf},
-    sync::LazyLock,
+    sync::{Arc, LazyLock},
 };
 use uuid::Uuid;
 
@@ -18,8 +22,20 @@ pub(crate) static FILE_HASH: LazyLock<[u8; 32]> = LazyLock::new(|| {
     <[u8; 32]>::from(Sha256::digest(bytes))
 });
 
+/// Shared by every client, so files saved directly to it can be downloaded through the server
+pub(crate) static STORAGE: LazyLock<Arc<DebugStorage>> = LazyLock::new(Arc::default);
+
 pub(crate) fn setup_client() -> Client {
-    Client::untracked(server()).unwrap()
+    setup_client_with(Settings {
+        api_key: Some(String::from("12345")),
+        secret: Some(String::from("secret")),
+        verbose: true,
+        ..Settings::default()
+    })
+}
+
+pub(crate) fn setup_client_with(settings: Settings) -> Client {
+    Client::untracked(server_with(settings, Box::new(Arc::clone(&STORAGE))).unwrap()).unwrap()
 }
 
 pub(crate) fn create_new_test_file() -> String {
@@ -38,6 +54,14 @@ pub(crate) fn create_new_test_file() -> String {
 const BOUNDARY: &str = "------------------------ea3bbcf87c101592";
 
 pub(crate) fn get_image_data<T: AsRef<Path>>(filepath: T, fake: bool) -> (ContentType, Vec<u8>) {
+    get_image_data_with_fields(filepath, fake, &[])
+}
+
+pub(crate) fn get_image_data_with_fields<T: AsRef<Path>>(
+    filepath: T,
+    fake: bool,
+    fields: &[(&str, &str)],
+) -> (ContentType, Vec<u8>) {
     let ct = format!("multipart/form-data; boundary={BOUNDARY}")
         .parse::<ContentType>()
         .unwrap();
@@ -58,6 +82,13 @@ pub(crate) fn get_image_data<T: AsRef<Path>>(filepath: T, fake: bool) -> (Conten
     result
         .extend_from_slice("Content-Disposition: form-data; name=\"filename\"\r\n\r\n".as_bytes());
     result.extend_from_slice(format!("{filename}\r\n").as_bytes());
+    for (name, value) in fields {
+        result.extend_from_slice(format!("--{BOUNDARY}\r\n").as_bytes());
+        result.extend_from_slice(
+            format!("Content-Disposition: form-data; name=\"{name}\"\r\n\r\n").as_bytes(),
+        );
+        result.extend_from_slice(format!("{value}\r\n").as_bytes());
+    }
     result.extend_from_slice(format!("--{BOUNDARY}\r\n").as_bytes());
     result.extend_from_slice(
         format!("Content-Disposition: form-data; name=\"file\"; filename=\"{filename}\"\r\n")
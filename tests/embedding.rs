@@ -0,0 +1,207 @@
+#![allow(clippy::unwrap_used)]
+
+#[macro_use]
+extern crate rocket;
+
+mod common;
+
+use crate::common::{STORAGE, TEST_FILE, get_image_data};
+use rocket::{
+    Request,
+    http::{Header, Status},
+    local::blocking::Client,
+};
+use rumia::{
+    Rumia,
+    auth::{Authenticator, Principal, Scope},
+    error::{ApiKeyError, ConfigError},
+    settings::Settings,
+};
+use std::sync::Arc;
+
+/// Trusts a user header set by a proxy in front of the app
+struct ProxyUser;
+
+#[rocket::async_trait]
+impl Authenticator for ProxyUser {
+    async fn authenticate(&self, request: &Request<'_>) -> Result<Principal, ApiKeyError> {
+        match request.headers().get_one("x-user") {
+            Some("reader") => Ok(Principal::new("reader", vec![Scope::Metadata])),
+            Some(user) => Ok(Principal::new(user, vec![Scope::Admin])),
+            None => Err(ApiKeyError::Missing),
+        }
+    }
+}
+
+#[get("/")]
+fn index() -> &'static str {
+    "host app"
+}
+
+fn setup_client(url: &str) -> Client {
+    let rumia = Rumia::new(
+        Settings {
+            url: String::from(url),
+            ..Settings::default()
+        },
+        Box::new(Arc::clone(&STORAGE)),
+    )
+    .unwrap()
+    .authenticator(ProxyUser);
+
+    let rocket = rocket::build()
+        .mount("/", routes![index])
+        .mount("/files", rumia.routes())
+        .attach(rumia.fairing());
+    Client::untracked(rocket).unwrap()
+}
+
+#[test]
+fn mounts_under_a_prefix() {
+    let client = setup_client("");
+    assert_eq!(
+        client.get("/").dispatch().into_string().unwrap(),
+        "host app"
+    );
+
+    let (ct, data) = get_image_data(&*TEST_FILE, false);
+    let resp = client
+        .post("/files/api/upload/file")
+        .header(Header::new("x-user", "alice"))
+        .header(ct)
+        .body(data)
+        .dispatch();
+    assert_eq!(resp.status(), Status::Ok);
+
+    let url = resp.into_string().unwrap();
+    assert!(url.starts_with("/files/attachment/"));
+    assert_eq!(client.get(&url).dispatch().status(), Status::Ok);
+
+    let hash = url.split('/').nth(3).unwrap();
+    let resp = client
+        .get(format!("/files/api/files/{hash}"))
+        .header(Header::new("x-user", "reader"))
+        .dispatch();
+    assert_eq!(resp.status(), Status::Ok);
+    assert!(
+        resp.into_string()
+            .unwrap()
+            .contains("\"uploader\":\"alice\"")
+    );
+}
+
+#[test]
+fn mounts_the_readiness_check() {
+    let client = setup_client("");
+    let resp = client.get("/files/health/ready").dispatch();
+    assert_eq!(resp.status(), Status::Ok);
+    assert!(resp.into_string().unwrap().contains("\"status\":\"ready\""));
+}
+
+#[test]
+fn leaves_metrics_unmounted() {
+    let client = setup_client("");
+    assert_eq!(
+        client.get("/files/metrics").dispatch().status(),
+        Status::NotFound
+    );
+}
+
+#[test]
+fn uses_the_authenticator() {
+    let client = setup_client("https://example.com/");
+
+    let resp = client.get("/files/api/files").dispatch();
+    assert_eq!(resp.status(), Status::Unauthorized);
+
+    let resp = client
+        .get("/files/api/files")
+        .header(Header::new("x-api-key", "12345"))
+        .dispatch();
+    assert_eq!(resp.status(), Status::Unauthorized);
+
+    let (ct, data) = get_image_data(&*TEST_FILE, false);
+    let resp = client
+        .post("/files/api/upload/file")
+        .header(Header::new("x-user", "reader"))
+        .header(ct)
+        .body(data)
+        .dispatch();
+    assert_eq!(resp.status(), Status::Forbidden);
+
+    let (ct, data) = get_image_data(&*TEST_FILE, false);
+    let resp = client
+        .post("/files/api/upload/file")
+        .header(Header::new("x-user", "alice"))
+        .header(ct)
+        .body(data)
+        .dispatch();
+    assert!(
+        resp.into_string()
+            .unwrap()
+            .starts_with("https://example.com/files/attachment/")
+    );
+}
+
+#[test]
+fn needs_a_secret_to_sign_urls() {
+    let client = setup_client("");
+    let resp = client
+        .post("/files/api/sign/39514323-7c35-4e01-95b0-4a6b55c550c1/test.png")
+        .header(Header::new("x-user", "alice"))
+        .dispatch();
+    assert_eq!(resp.status(), Status::ServiceUnavailable);
+}
+
+#[test]
+fn reports_invalid_settings() {
+    let settings = Settings {
+        keys_file: Some(std::path::PathBuf::from("missing-keys.toml")),
+        ..Settings::default()
+    };
+    let error = Rumia::new(settings, Box::new(Arc::clone(&STORAGE)))
+        .err()
+        .unwrap();
+    assert!(matches!(error, ConfigError::KeysFile(_)));
+
+    // The admin key isn't used to sign tokens in place of a secret
+    let settings = Settings {
+        api_key: Some(String::from("12345")),
+        ..Settings::default()
+    };
+    let error = Rumia::new(settings, Box::new(Arc::clone(&STORAGE)))
+        .err()
+        .unwrap();
+    assert!(matches!(error, ConfigError::MissingSecret));
+
+    let keys_file = std::env::temp_dir().join(format!("rumia-keys-{}.toml", uuid::Uuid::new_v4()));
+    std::fs::write(
+        &keys_file,
+        "[[keys]]\nname = \"ci\"\nkey = \"ci-key\"\nscopes = [\"upload\"]\n",
+    )
+    .unwrap();
+    let settings = Settings {
+        keys_file: Some(keys_file.clone()),
+        ..Settings::default()
+    };
+    let error = Rumia::new(settings, Box::new(Arc::clone(&STORAGE)))
+        .err()
+        .unwrap();
+    assert!(matches!(error, ConfigError::MissingSecret));
+    let settings = Settings {
+        keys_file: Some(keys_file.clone()),
+        secret: Some(String::from("secret")),
+        ..Settings::default()
+    };
+    assert!(Rumia::new(settings, Box::new(Arc::clone(&STORAGE))).is_ok());
+    std::fs::remove_file(keys_file).ok();
+
+    let settings = Settings {
+        egress_deny: vec![String::from("10.0.0.0/99")],
+        ..Settings::default()
+    };
+    let error = rumia::server_with(settings, Box::new(Arc::clone(&STORAGE)))
+        .err()
+        .unwrap();
+    assert!(matches!(error, ConfigError::EgressPolicy(_)));
+}
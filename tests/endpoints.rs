@@ -2,17 +2,37 @@ use tokio::io::AsyncReadExt;
 mod common;
 
 use crate::common::{
-    FILE_HASH, FILE_PATH, TEST_FILE, create_new_test_file, get_image_data, setup_client,
+    FILE_HASH, FILE_PATH, STORAGE, TEST_FILE, create_new_test_file, get_image_data,
+    get_image_data_with_fields, setup_client, setup_client_with,
+};
+use rocket::{
+    http::{ContentType, Header, Status},
+    local::blocking::Client,
+    serde::json::Value,
+};
+use rumia::{
+    settings::{Settings, StorageCommands},
+    storage::{InputFile, Metadata, Storage},
 };
-use rocket::http::{Header, Status};
-use rumia::STORAGE;
 use sha2::{Digest, Sha256};
 use std::path::Path;
+use time::{Duration, OffsetDateTime, format_description::well_known::Rfc3339};
 
-const PROTECTED: [(Method, &str); 3] = [
+#[derive(Debug, rocket::serde::Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct FileList {
+    files: Vec<Metadata>,
+    next: Option<String>,
+}
+
+const PROTECTED: [(Method, &str); 7] = [
     (Method::POST, "/api/upload/file"),
     (Method::POST, "/api/upload/https%3A%2F%2Fgoogle.com"),
     (Method::DELETE, "/attachment/543543/test.png"),
+    (Method::POST, "/api/sign/543543/test.png"),
+    (Method::GET, "/api/files/543543"),
+    (Method::GET, "/api/files"),
+    (Method::GET, "/api/jobs/543543"),
 ];
 
 #[allow(clippy::upper_case_acronyms)]
@@ -33,6 +53,36 @@ fn healthcheck() {
     assert_eq!(resp.into_string().unwrap(), "ok");
 }
 
+#[test]
+fn reports_readiness() {
+    let client = setup_client();
+    let resp = client.get("/health/ready").dispatch();
+    assert_eq!(resp.status(), Status::Ok);
+    let body: Value = resp.into_json().unwrap();
+    assert_eq!(body["status"], "ready");
+    assert_eq!(body["backend"], "debug");
+    assert_eq!(body["components"]["storage"]["up"], true);
+}
+
+#[test]
+fn reports_unavailable_storage() {
+    let storage = rumia::storage::init(&StorageCommands::FileSystem {
+        path: FILE_PATH.join("missing"),
+        min_free: 0,
+    });
+    let client =
+        Client::untracked(rumia::server_with(Settings::default(), storage).unwrap()).unwrap();
+
+    assert_eq!(client.get("/health").dispatch().status(), Status::Ok);
+    let resp = client.get("/health/ready").dispatch();
+    assert_eq!(resp.status(), Status::ServiceUnavailable);
+    let body: Value = resp.into_json().unwrap();
+    assert_eq!(body["status"], "unavailable");
+    assert_eq!(body["components"]["storage"]["up"], false);
+    assert!(body["components"]["storage"]["detail"].is_string());
+    assert_eq!(body["components"]["disk"]["up"], false);
+}
+
 #[test]
 fn can_auth() {
     let client = setup_client();
@@ -44,6 +94,50 @@ fn can_auth() {
     assert_eq!(resp.status(), Status::BadRequest);
 }
 
+#[test]
+fn rejects_unknown_key() {
+    let client = setup_client();
+    let resp = client
+        .delete("/attachment/543543/543543.gif".to_string())
+        .header(Header::new("x-api-key", "54321"))
+        .dispatch();
+
+    assert_eq!(resp.status(), Status::Unauthorized);
+}
+
+#[test]
+fn instances_are_configured_separately() {
+    let first = setup_client();
+    let second = setup_client_with(Settings {
+        api_key: Some(String::from("67890")),
+        secret: Some(String::from("secret")),
+        url: String::from("https://files.example.com"),
+        ..Settings::default()
+    });
+
+    let upload = |client: &rocket::local::blocking::Client, key: &str| {
+        let (ct, data) = get_image_data(&*TEST_FILE, false);
+        let resp = client
+            .post("/api/upload/file")
+            .header(Header::new("x-api-key", key.to_owned()))
+            .header(ct)
+            .body(data)
+            .dispatch();
+        (resp.status(), resp.into_string().unwrap_or_default())
+    };
+
+    assert_eq!(upload(&second, "12345").0, Status::Unauthorized);
+    assert_eq!(upload(&first, "67890").0, Status::Unauthorized);
+
+    let (status, url) = upload(&second, "67890");
+    assert_eq!(status, Status::Ok);
+    assert!(url.starts_with("https://files.example.com/attachment/"));
+
+    let (status, url) = upload(&first, "12345");
+    assert_eq!(status, Status::Ok);
+    assert!(url.starts_with("http://localhost/attachment/"));
+}
+
 #[test]
 fn cannot_access_protected_endpoints() {
     let client = setup_client();
@@ -88,15 +182,59 @@ fn upload_file_from_binary() {
 
     let rt = tokio::runtime::Runtime::new().unwrap();
     let result = rt.block_on(async {
-        let loaded = STORAGE.load(&format!("{}.png", strings[4])).await.unwrap();
-        let mut buffer = vec![0; loaded.metadata().await.unwrap().len() as usize];
-        loaded.take_file().read_exact(&mut buffer).await.unwrap();
+        let mut loaded = STORAGE.load(&format!("{}.png", strings[4])).await.unwrap();
+        let mut buffer = vec![0; loaded.len as usize];
+        loaded.reader.read_exact(&mut buffer).await.unwrap();
         Sha256::digest(buffer)
     });
 
     assert_eq!(result[..], FILE_HASH[..]);
 }
 
+#[test]
+fn delete_file_with_deletion_token() {
+    let client = setup_client();
+    let (ct, data) = get_image_data(&*TEST_FILE, false);
+
+    let resp = client
+        .post("/api/upload/file")
+        .header(Header::new("x-api-key", "12345"))
+        .header(ct)
+        .body(data)
+        .dispatch();
+
+    assert_eq!(resp.status(), Status::Ok);
+    let token = resp
+        .headers()
+        .get_one("X-Deletion-Token")
+        .unwrap()
+        .to_owned();
+    let url = resp.into_string().unwrap();
+    let path = url.split_once("/attachment/").unwrap().1;
+
+    let resp = client
+        .delete(format!("/attachment/{path}"))
+        .header(Header::new("x-deletion-token", "not-the-token"))
+        .dispatch();
+    assert_eq!(resp.status(), Status::Unauthorized);
+
+    let other = create_new_test_file();
+    let resp = client
+        .delete(format!("/attachment/{other}/test.png"))
+        .header(Header::new("x-deletion-token", token.clone()))
+        .dispatch();
+    assert_eq!(resp.status(), Status::Unauthorized);
+
+    let resp = client
+        .delete(format!("/attachment/{path}"))
+        .header(Header::new("x-deletion-token", token))
+        .dispatch();
+    assert_eq!(resp.status(), Status::Ok);
+
+    let resp = client.get(format!("/attachment/{path}")).dispatch();
+    assert_eq!(resp.status(), Status::NotFound);
+}
+
 #[test]
 fn upload_file_from_url() {
     let client = setup_client();
@@ -112,9 +250,9 @@ fn upload_file_from_url() {
 
     let rt = tokio::runtime::Runtime::new().unwrap();
     let result = rt.block_on(async {
-        let loaded = STORAGE.load(&format!("{}.png", strings[4])).await.unwrap();
-        let mut buffer = vec![0; loaded.metadata().await.unwrap().len() as usize];
-        loaded.take_file().read_exact(&mut buffer).await.unwrap();
+        let mut loaded = STORAGE.load(&format!("{}.png", strings[4])).await.unwrap();
+        let mut buffer = vec![0; loaded.len as usize];
+        loaded.reader.read_exact(&mut buffer).await.unwrap();
         Sha256::digest(buffer)
     });
 
@@ -136,6 +274,24 @@ fn cannot_upload_bad_binary() {
     assert_eq!(resp.status(), Status::UnsupportedMediaType);
 }
 
+#[test]
+fn cannot_upload_mismatched_content() {
+    let client = setup_client();
+    let renamed = std::env::temp_dir().join(format!("{}.jpg", uuid::Uuid::new_v4()));
+    std::fs::copy(&*TEST_FILE, &renamed).unwrap();
+    let (ct, body) = get_image_data(&renamed, false);
+    std::fs::remove_file(&renamed).unwrap();
+
+    let resp = client
+        .post("/api/upload/file")
+        .header(Header::new("x-api-key", "12345"))
+        .header(ct)
+        .body(body)
+        .dispatch();
+
+    assert_eq!(resp.status(), Status::UnsupportedMediaType);
+}
+
 #[test]
 fn cannot_upload_bad_url() {
     let client = setup_client();
@@ -148,6 +304,270 @@ fn cannot_upload_bad_url() {
     assert_eq!(resp.status(), Status::BadRequest);
 }
 
+#[test]
+fn cannot_upload_internal_url() {
+    let client = setup_client();
+
+    for url in [
+        "http%3A%2F%2F127.0.0.1%3A10032%2Ftest.png",
+        "http%3A%2F%2F169.254.169.254%2Flatest%2Fmeta-data%2Ftest.png",
+        "http%3A%2F%2Flocalhost%2Ftest.png",
+        "ftp%3A%2F%2Fexample.com%2Ftest.png",
+    ] {
+        let resp = client
+            .post(format!("/api/upload/{url}"))
+            .header(Header::new("x-api-key", "12345"))
+            .dispatch();
+
+        assert_eq!(resp.status(), Status::Forbidden, "{url}");
+    }
+}
+
+#[test]
+fn exports_metrics() {
+    let client = setup_client();
+    let (ct, data) = get_image_data(&*TEST_FILE, false);
+    let url = client
+        .post("/api/upload/file")
+        .header(Header::new("x-api-key", "12345"))
+        .header(Header::new("Content-Length", data.len().to_string()))
+        .header(ct)
+        .body(data)
+        .dispatch()
+        .into_string()
+        .unwrap();
+    let path = url.trim_start_matches("http://localhost");
+    assert_eq!(client.get(path).dispatch().status(), Status::Ok);
+    client
+        .post("/api/upload/http%3A%2F%2F127.0.0.1%2Ftest.png")
+        .header(Header::new("x-api-key", "12345"))
+        .dispatch();
+
+    let resp = client.get("/metrics").dispatch();
+    assert_eq!(resp.status(), Status::Ok);
+    assert_eq!(
+        resp.content_type().unwrap().media_type().sub(),
+        "openmetrics-text"
+    );
+
+    let metrics = resp.into_string().unwrap();
+    for line in [
+        "rumia_requests_total{route=\"upload_file\",status=\"200\"} 1",
+        "rumia_requests_total{route=\"get_file\",status=\"200\"} 1",
+        "rumia_requests_total{route=\"upload_file_url\",status=\"403\"} 1",
+        "rumia_remote_fetches_total{outcome=\"forbidden\"} 1",
+        "rumia_storage_operation_duration_seconds_count{backend=\"debug\",operation=\"save\"} 1",
+        "rumia_sent_bytes_total{route=\"get_file\"}",
+        "rumia_received_bytes_total{route=\"upload_file\"}",
+    ] {
+        assert!(metrics.contains(line), "{line} missing from {metrics}");
+    }
+}
+
+#[test]
+fn writes_audit_log() {
+    let audit_log = std::env::temp_dir().join(format!("rumia-audit-{}.log", uuid::Uuid::new_v4()));
+    let client = setup_client_with(Settings {
+        api_key: Some(String::from("12345")),
+        secret: Some(String::from("secret")),
+        audit_log: Some(audit_log.clone()),
+        ..Settings::default()
+    });
+    let (ct, data) = get_image_data(&*TEST_FILE, false);
+    let resp = client
+        .post("/api/upload/file")
+        .header(Header::new("x-api-key", "12345"))
+        .header(Header::new("X-Request-Id", "upload-1"))
+        .header(ct)
+        .body(data)
+        .dispatch();
+    assert_eq!(resp.status(), Status::Ok);
+    assert_eq!(resp.headers().get_one("X-Request-Id"), Some("upload-1"));
+    let token = resp
+        .headers()
+        .get_one("X-Deletion-Token")
+        .unwrap()
+        .to_owned();
+    let url = resp.into_string().unwrap();
+    let path = url.split_once("/attachment/").unwrap().1;
+    let hash = path.split_once('/').unwrap().0;
+
+    let resp = client
+        .delete(format!("/attachment/{path}"))
+        .header(Header::new("x-deletion-token", token))
+        .dispatch();
+    assert_eq!(resp.status(), Status::Ok);
+    let delete_id = resp.headers().get_one("X-Request-Id").unwrap().to_owned();
+    let resp = client
+        .get("/api/files")
+        .header(Header::new("x-api-key", "54321"))
+        .header(Header::new("X-Real-IP", "203.0.113.7"))
+        .dispatch();
+    assert_eq!(resp.status(), Status::Unauthorized);
+
+    let log = std::fs::read_to_string(&audit_log).unwrap();
+    let records: Vec<Value> = log
+        .lines()
+        .map(|line| rocket::serde::json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(records.len(), 3, "{log}");
+
+    assert_eq!(records[0]["event"], "upload");
+    assert_eq!(records[0]["request_id"], "upload-1");
+    assert_eq!(records[0]["key"], "admin");
+    assert_eq!(records[0]["file"], hash);
+    assert_eq!(
+        records[0]["size"],
+        std::fs::metadata(&*TEST_FILE).unwrap().len()
+    );
+    assert_eq!(records[0]["outcome"], "success");
+
+    assert_eq!(records[1]["event"], "delete");
+    assert_eq!(records[1]["request_id"], delete_id.as_str());
+    assert!(records[1]["key"].is_null());
+    assert_eq!(records[1]["file"], hash);
+    assert_eq!(records[1]["outcome"], "success");
+
+    assert_eq!(records[2]["event"], "auth_failure");
+    assert_eq!(records[2]["outcome"], "unknown_key");
+    assert_eq!(records[2]["status"], 401);
+    assert_ne!(records[2]["client_ip"], "203.0.113.7");
+    std::fs::remove_file(audit_log).ok();
+}
+
+#[test]
+fn trusts_the_configured_ip_header() {
+    let audit_log = std::env::temp_dir().join(format!("rumia-audit-{}.log", uuid::Uuid::new_v4()));
+    let client = setup_client_with(Settings {
+        api_key: Some(String::from("12345")),
+        secret: Some(String::from("secret")),
+        audit_log: Some(audit_log.clone()),
+        ip_header: Some(String::from("X-Forwarded-For")),
+        ..Settings::default()
+    });
+    let resp = client
+        .get("/api/files")
+        .header(Header::new("x-api-key", "54321"))
+        .header(Header::new("X-Real-IP", "198.51.100.1"))
+        .header(Header::new("X-Forwarded-For", "198.51.100.1, 203.0.113.7"))
+        .dispatch();
+    assert_eq!(resp.status(), Status::Unauthorized);
+
+    let log = std::fs::read_to_string(&audit_log).unwrap();
+    let record: Value = rocket::serde::json::from_str(log.trim()).unwrap();
+    assert_eq!(record["client_ip"], "203.0.113.7");
+    std::fs::remove_file(audit_log).ok();
+}
+
+#[test]
+fn upload_url_in_background() {
+    let client = setup_client();
+    let resp = client
+        .post("/api/upload/http%3A%2F%2F127.0.0.1%3A10032%2Ftest.png")
+        .header(Header::new("x-api-key", "12345"))
+        .header(Header::new("Prefer", "respond-async"))
+        .dispatch();
+
+    assert_eq!(resp.status(), Status::Accepted);
+    let location = resp.headers().get_one("Location").unwrap();
+    let path = format!("/api/jobs/{}", location.split_once("/api/jobs/").unwrap().1);
+    let job = resp.into_json::<Value>().unwrap();
+    assert_eq!(job["status"], "queued");
+
+    // The job only makes progress while the client is dispatching requests
+    let mut status = Value::Null;
+    for _ in 0..100 {
+        status = client
+            .get(path.as_str())
+            .header(Header::new("x-api-key", "12345"))
+            .dispatch()
+            .into_json::<Value>()
+            .unwrap();
+        if status["status"] == "failed" {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+
+    assert_eq!(status["id"], job["id"]);
+    assert_eq!(status["status"], "failed");
+    assert_eq!(status["code"], 403);
+}
+
+/// Starts a stand-in upstream server which answers every request with `status`, returning the
+/// percent-encoded URL of a file on it
+#[allow(clippy::unwrap_used)]
+fn serve_status(status: &'static str) -> String {
+    use std::io::{Read, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let mut buffer = [0; 1024];
+            let _ = stream.read(&mut buffer);
+            let head = format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\n\r\n");
+            let _ = stream.write_all(head.as_bytes());
+        }
+    });
+
+    format!("http%3A%2F%2F127.0.0.1%3A{}%2Ftest.png", addr.port())
+}
+
+#[test]
+fn unknown_upstream_status_returns_502() {
+    let url = serve_status("520 Unknown Error");
+    let client = setup_client_with(Settings {
+        api_key: Some(String::from("12345")),
+        secret: Some(String::from("secret")),
+        egress_allow: vec![String::from("127.0.0.1")],
+        ..Settings::default()
+    });
+
+    let resp = client
+        .post(format!("/api/upload/{url}"))
+        .header(Header::new("x-api-key", "12345"))
+        .dispatch();
+    assert_eq!(resp.status(), Status::BadGateway);
+
+    let resp = client
+        .post(format!("/api/upload/{url}"))
+        .header(Header::new("x-api-key", "12345"))
+        .header(Header::new("Prefer", "respond-async"))
+        .dispatch();
+    assert_eq!(resp.status(), Status::Accepted);
+    let location = resp.headers().get_one("Location").unwrap();
+    let path = format!("/api/jobs/{}", location.split_once("/api/jobs/").unwrap().1);
+
+    let mut status = Value::Null;
+    for _ in 0..100 {
+        status = client
+            .get(path.as_str())
+            .header(Header::new("x-api-key", "12345"))
+            .dispatch()
+            .into_json::<Value>()
+            .unwrap();
+        if status["status"] == "failed" {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+
+    assert_eq!(status["status"], "failed");
+    assert_eq!(status["code"], 502);
+}
+
+#[test]
+fn unknown_job_returns_404() {
+    let client = setup_client();
+    let resp = client
+        .get(format!("/api/jobs/{}", uuid::Uuid::new_v4()))
+        .header(Header::new("x-api-key", "12345"))
+        .dispatch();
+
+    assert_eq!(resp.status(), Status::NotFound);
+}
+
 #[test]
 fn can_get_test_file() {
     let uuid = create_new_test_file();
@@ -158,6 +578,7 @@ fn can_get_test_file() {
         .dispatch();
 
     assert_eq!(resp.status(), Status::Ok);
+    assert_eq!(resp.content_type(), Some(ContentType::PNG));
 
     let data = resp.into_bytes().unwrap();
 
@@ -190,3 +611,287 @@ fn delete_file() {
     assert_eq!(resp.status(), Status::Ok);
     assert!(!Path::new(&FILE_PATH.join(uuid + ".png")).exists());
 }
+
+#[test]
+fn can_get_test_file_range() {
+    let uuid = create_new_test_file();
+    let bytes = std::fs::read(&*TEST_FILE).unwrap();
+
+    let client = setup_client();
+    let resp = client
+        .get(format!("/attachment/{uuid}/test.png"))
+        .header(Header::new("Range", "bytes=100-199"))
+        .dispatch();
+
+    assert_eq!(resp.status(), Status::PartialContent);
+    assert_eq!(
+        resp.headers().get_one("Content-Range"),
+        Some(format!("bytes 100-199/{}", bytes.len()).as_str())
+    );
+    assert_eq!(resp.into_bytes().unwrap()[..], bytes[100..200]);
+}
+
+#[test]
+fn unsatisfiable_range_returns_416() {
+    let uuid = create_new_test_file();
+
+    let client = setup_client();
+    let resp = client
+        .get(format!("/attachment/{uuid}/test.png"))
+        .header(Header::new("Range", "bytes=99999999-"))
+        .dispatch();
+
+    assert_eq!(resp.status(), Status::RangeNotSatisfiable);
+}
+
+#[test]
+fn revalidate_test_file() {
+    let uuid = create_new_test_file();
+
+    let client = setup_client();
+    let resp = client
+        .get(format!("/attachment/{uuid}/test.png"))
+        .dispatch();
+    let etag = resp.headers().get_one("ETag").unwrap().to_owned();
+    assert!(resp.headers().get_one("Last-Modified").is_some());
+
+    let resp = client
+        .get(format!("/attachment/{uuid}/test.png"))
+        .header(Header::new("If-None-Match", etag))
+        .dispatch();
+
+    assert_eq!(resp.status(), Status::NotModified);
+    assert!(resp.into_bytes().is_none_or(|body| body.is_empty()));
+}
+
+#[test]
+fn upload_file_with_ttl() {
+    let client = setup_client();
+    let (ct, data) = get_image_data_with_fields(&*TEST_FILE, false, &[("ttl", "3600")]);
+
+    let resp = client
+        .post("/api/upload/file")
+        .header(Header::new("x-api-key", "12345"))
+        .header(ct)
+        .body(data)
+        .dispatch();
+
+    assert_eq!(resp.status(), Status::Ok);
+    let url = resp.into_string().unwrap();
+    let hash = url.split('/').nth(4).unwrap().to_owned();
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let metadata = rt
+        .block_on(async { STORAGE.load_metadata(&hash).await })
+        .unwrap();
+    assert_eq!(metadata.file, format!("{hash}.png"));
+    let expires = metadata.expires.unwrap() - OffsetDateTime::now_utc();
+    assert!(expires > Duration::minutes(59) && expires <= Duration::hours(1));
+}
+
+#[test]
+fn rejects_bad_expiry() {
+    let client = setup_client();
+
+    for fields in [
+        [("ttl", "60"), ("expires", "2099-01-01T00:00:00Z")].as_slice(),
+        &[("expires", "2020-01-01T00:00:00Z")],
+        &[("expires", "tomorrow")],
+    ] {
+        let (ct, data) = get_image_data_with_fields(&*TEST_FILE, false, fields);
+        let resp = client
+            .post("/api/upload/file")
+            .header(Header::new("x-api-key", "12345"))
+            .header(ct)
+            .body(data)
+            .dispatch();
+
+        assert_eq!(resp.status(), Status::BadRequest, "{fields:?}");
+    }
+}
+
+#[test]
+fn expired_file_returns_410() {
+    let uuid = create_new_test_file();
+
+    let bytes = std::fs::read(&*TEST_FILE).unwrap();
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let metadata = Metadata::describe(
+            &mut InputFile::Bytes(&bytes),
+            &uuid,
+            "test.png",
+            "png",
+            "test",
+        )
+        .await
+        .unwrap();
+        STORAGE
+            .save_metadata(
+                &uuid,
+                &Metadata {
+                    expires: Some(OffsetDateTime::now_utc() - Duration::minutes(1)),
+                    ..metadata
+                },
+            )
+            .await
+            .unwrap()
+    });
+
+    let client = setup_client();
+    let resp = client
+        .get(format!("/attachment/{uuid}/test.png"))
+        .dispatch();
+
+    assert_eq!(resp.status(), Status::Gone);
+}
+
+#[test]
+fn sign_test_file() {
+    let uuid = create_new_test_file();
+
+    let client = setup_client();
+    let resp = client
+        .post(format!("/api/sign/{uuid}/test.png?ttl=60"))
+        .header(Header::new("x-api-key", "12345"))
+        .dispatch();
+
+    assert_eq!(resp.status(), Status::Ok);
+    let url = resp.into_string().unwrap();
+    assert!(url.contains("?expires="));
+    assert!(url.contains("&signature="));
+
+    let path = url.split_once("/attachment/").unwrap().1;
+    let resp = client.get(format!("/attachment/{path}")).dispatch();
+    assert_eq!(resp.status(), Status::Ok);
+    assert_eq!(
+        Sha256::digest(resp.into_bytes().unwrap())[..],
+        FILE_HASH[..]
+    );
+
+    let resp = client
+        .post("/api/sign/39514323-7c35-4e01-95b0-4a6b55c550c1/test.png")
+        .header(Header::new("x-api-key", "12345"))
+        .dispatch();
+    assert_eq!(resp.status(), Status::NotFound);
+}
+
+#[test]
+fn signed_urls_depend_on_the_secret() {
+    let uuid = create_new_test_file();
+    let private = |secret: &str| {
+        setup_client_with(Settings {
+            api_key: Some(String::from("12345")),
+            secret: Some(String::from(secret)),
+            private: true,
+            ..Settings::default()
+        })
+    };
+    let client = private("secret");
+    let rotated = private("rotated");
+
+    let resp = client
+        .post(format!("/api/sign/{uuid}/test.png?ttl=60"))
+        .header(Header::new("x-api-key", "12345"))
+        .dispatch();
+    assert_eq!(resp.status(), Status::Ok);
+    let url = resp.into_string().unwrap();
+    let path = format!("/attachment/{}", url.split_once("/attachment/").unwrap().1);
+
+    // Both share the admin key, which isn't what URLs are signed with
+    assert_eq!(client.get(path.as_str()).dispatch().status(), Status::Ok);
+    assert_eq!(
+        rotated.get(path.as_str()).dispatch().status(),
+        Status::Forbidden
+    );
+}
+
+#[test]
+fn get_file_metadata() {
+    let client = setup_client();
+    let (ct, data) = get_image_data(&*TEST_FILE, false);
+
+    let resp = client
+        .post("/api/upload/file")
+        .header(Header::new("x-api-key", "12345"))
+        .header(ct)
+        .body(data)
+        .dispatch();
+
+    assert_eq!(resp.status(), Status::Ok);
+    let url = resp.into_string().unwrap();
+    let hash = url.split('/').nth(4).unwrap();
+
+    let resp = client
+        .get(format!("/api/files/{hash}"))
+        .header(Header::new("x-api-key", "12345"))
+        .dispatch();
+
+    assert_eq!(resp.status(), Status::Ok);
+    assert_eq!(resp.content_type(), Some(ContentType::JSON));
+    let metadata: Metadata = resp.into_json().unwrap();
+    assert_eq!(metadata.hash, hash);
+    assert_eq!(metadata.file, format!("{hash}.png"));
+    assert_eq!(metadata.name, "test.png");
+    assert_eq!(metadata.size, std::fs::metadata(&*TEST_FILE).unwrap().len());
+    assert_eq!(metadata.content_type, "image/png");
+    assert_eq!(hex::decode(metadata.sha256).unwrap()[..], FILE_HASH[..]);
+    assert_eq!(metadata.uploader, "admin");
+    assert!(metadata.expires.is_none());
+
+    let resp = client
+        .get("/api/files/39514323-7c35-4e01-95b0-4a6b55c550c1")
+        .header(Header::new("x-api-key", "12345"))
+        .dispatch();
+    assert_eq!(resp.status(), Status::NotFound);
+}
+
+#[test]
+fn list_files() {
+    let client = setup_client();
+    let from = OffsetDateTime::now_utc().format(&Rfc3339).unwrap();
+
+    for _ in 0..3 {
+        let (ct, data) = get_image_data(FILE_PATH.join("list.txt"), true);
+        let resp = client
+            .post("/api/upload/file")
+            .header(Header::new("x-api-key", "12345"))
+            .header(ct)
+            .body(data)
+            .dispatch();
+        assert_eq!(resp.status(), Status::Ok);
+    }
+
+    let list = |query: &str| {
+        let resp = client
+            .get(format!("/api/files?extension=txt&from={from}{query}"))
+            .header(Header::new("x-api-key", "12345"))
+            .dispatch();
+        assert_eq!(resp.status(), Status::Ok);
+        resp.into_json::<FileList>().unwrap()
+    };
+
+    let first = list("&limit=2");
+    assert_eq!(first.files.len(), 2);
+    assert!(first.files.iter().all(|file| file.name == "list.txt"));
+    let cursor = first.next.unwrap();
+
+    let second = list(&format!("&limit=2&cursor={cursor}"));
+    assert_eq!(second.files.len(), 1);
+    assert!(second.next.is_none());
+    assert!(
+        first
+            .files
+            .iter()
+            .all(|file| file.hash != second.files[0].hash)
+    );
+
+    assert!(list("&uploader=nobody").files.is_empty());
+
+    let resp = client
+        .get("/api/files?from=yesterday")
+        .header(Header::new("x-api-key", "12345"))
+        .dispatch();
+    assert_eq!(resp.status(), Status::BadRequest);
+}
@@ -0,0 +1,207 @@
+#![allow(clippy::unwrap_used)]
+
+mod common;
+
+use crate::common::{FILE_HASH, TEST_FILE, setup_client, setup_client_with};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use rocket::{
+    http::{ContentType, Header, Status},
+    local::blocking::Client,
+};
+use rumia::settings::Settings;
+use sha2::{Digest, Sha256};
+
+fn create_upload(client: &Client, filename: &str, length: usize) -> (Status, Option<String>) {
+    let resp = client
+        .post("/api/upload/tus")
+        .header(Header::new("x-api-key", "12345"))
+        .header(Header::new("Tus-Resumable", "1.0.0"))
+        .header(Header::new("Upload-Length", length.to_string()))
+        .header(Header::new(
+            "Upload-Metadata",
+            format!("filename {}", STANDARD.encode(filename)),
+        ))
+        .dispatch();
+
+    let location = resp.headers().get_one("Location").map(|location| {
+        let path = location.split_once("/api/").unwrap().1;
+        format!("/api/{path}")
+    });
+    (resp.status(), location)
+}
+
+fn patch_upload<'c>(
+    client: &'c Client,
+    location: &str,
+    offset: usize,
+    data: &[u8],
+) -> rocket::local::blocking::LocalResponse<'c> {
+    client
+        .patch(location.to_owned())
+        .header(Header::new("x-api-key", "12345"))
+        .header(Header::new("Tus-Resumable", "1.0.0"))
+        .header(Header::new("Upload-Offset", offset.to_string()))
+        .header(ContentType::new("application", "offset+octet-stream"))
+        .body(data)
+        .dispatch()
+}
+
+#[test]
+fn advertises_tus_support() {
+    let client = setup_client();
+    let resp = client.options("/api/upload/tus").dispatch();
+
+    assert_eq!(resp.status(), Status::NoContent);
+    assert_eq!(resp.headers().get_one("Tus-Version"), Some("1.0.0"));
+    assert!(resp.headers().get_one("Tus-Max-Size").is_some());
+}
+
+#[test]
+fn resumable_upload() {
+    let client = setup_client();
+    let bytes = std::fs::read(&*TEST_FILE).unwrap();
+    let (first, second) = bytes.split_at(bytes.len() / 2);
+
+    let (status, location) = create_upload(&client, "test.png", bytes.len());
+    assert_eq!(status, Status::Created);
+    let location = location.unwrap();
+
+    let resp = patch_upload(&client, &location, 0, first);
+    assert_eq!(resp.status(), Status::NoContent);
+    assert_eq!(
+        resp.headers().get_one("Upload-Offset"),
+        Some(first.len().to_string().as_str())
+    );
+    assert!(resp.headers().get_one("X-Attachment-Url").is_none());
+
+    let resp = client
+        .head(location.clone())
+        .header(Header::new("x-api-key", "12345"))
+        .header(Header::new("Tus-Resumable", "1.0.0"))
+        .dispatch();
+    assert_eq!(resp.status(), Status::Ok);
+    assert_eq!(
+        resp.headers().get_one("Upload-Offset"),
+        Some(first.len().to_string().as_str())
+    );
+
+    let resp = patch_upload(&client, &location, first.len(), second);
+    assert_eq!(resp.status(), Status::NoContent);
+    let url = resp.headers().get_one("X-Attachment-Url").unwrap();
+    assert!(url.ends_with("/test.png"));
+    assert!(resp.headers().get_one("X-Deletion-Token").is_some());
+
+    let path = url.split_once("/attachment/").unwrap().1;
+    let resp = client.get(format!("/attachment/{path}")).dispatch();
+    assert_eq!(resp.status(), Status::Ok);
+    assert_eq!(
+        Sha256::digest(resp.into_bytes().unwrap())[..],
+        FILE_HASH[..]
+    );
+}
+
+#[test]
+fn rejects_wrong_offset() {
+    let client = setup_client();
+    let (_, location) = create_upload(&client, "test.png", 10);
+
+    let resp = patch_upload(&client, &location.unwrap(), 5, b"12345");
+    assert_eq!(resp.status(), Status::Conflict);
+}
+
+#[test]
+fn rejects_bad_extension() {
+    let client = setup_client();
+    let (status, location) = create_upload(&client, "test.exe", 10);
+
+    assert_eq!(status, Status::UnsupportedMediaType);
+    assert!(location.is_none());
+}
+
+#[test]
+fn requires_tus_version() {
+    let client = setup_client();
+    let resp = client
+        .post("/api/upload/tus")
+        .header(Header::new("x-api-key", "12345"))
+        .header(Header::new("Upload-Length", "10"))
+        .dispatch();
+
+    assert_eq!(resp.status(), Status::PreconditionFailed);
+}
+
+#[test]
+fn terminate_upload() {
+    let client = setup_client();
+    let (_, location) = create_upload(&client, "test.png", 10);
+    let location = location.unwrap();
+
+    let resp = client
+        .delete(location.clone())
+        .header(Header::new("x-api-key", "12345"))
+        .header(Header::new("Tus-Resumable", "1.0.0"))
+        .dispatch();
+    assert_eq!(resp.status(), Status::NoContent);
+
+    let resp = client
+        .head(location)
+        .header(Header::new("x-api-key", "12345"))
+        .header(Header::new("Tus-Resumable", "1.0.0"))
+        .dispatch();
+    assert_eq!(resp.status(), Status::NotFound);
+}
+
+#[test]
+fn only_the_creator_can_use_an_upload() {
+    let keys_file = std::env::temp_dir().join(format!("rumia-keys-{}.toml", uuid::Uuid::new_v4()));
+    std::fs::write(
+        &keys_file,
+        "[[keys]]\nname = \"other\"\nkey = \"67890\"\nscopes = [\"upload\"]\n",
+    )
+    .unwrap();
+    let client = setup_client_with(Settings {
+        api_key: Some(String::from("12345")),
+        secret: Some(String::from("secret")),
+        keys_file: Some(keys_file.clone()),
+        ..Settings::default()
+    });
+    let bytes = std::fs::read(&*TEST_FILE).unwrap();
+    let (_, location) = create_upload(&client, "test.png", bytes.len());
+    let location = location.unwrap();
+    let resp = patch_upload(&client, &location, 0, &bytes);
+    assert!(resp.headers().get_one("X-Deletion-Token").is_some());
+
+    let resp = client
+        .head(location.clone())
+        .header(Header::new("x-api-key", "67890"))
+        .header(Header::new("Tus-Resumable", "1.0.0"))
+        .dispatch();
+    assert_eq!(resp.status(), Status::NotFound);
+    assert!(resp.headers().get_one("X-Deletion-Token").is_none());
+
+    let resp = client
+        .patch(location.clone())
+        .header(Header::new("x-api-key", "67890"))
+        .header(Header::new("Tus-Resumable", "1.0.0"))
+        .header(Header::new("Upload-Offset", "0"))
+        .header(ContentType::new("application", "offset+octet-stream"))
+        .body(b"12345")
+        .dispatch();
+    assert_eq!(resp.status(), Status::NotFound);
+
+    let resp = client
+        .delete(location.clone())
+        .header(Header::new("x-api-key", "67890"))
+        .header(Header::new("Tus-Resumable", "1.0.0"))
+        .dispatch();
+    assert_eq!(resp.status(), Status::NotFound);
+
+    let resp = client
+        .head(location)
+        .header(Header::new("x-api-key", "12345"))
+        .header(Header::new("Tus-Resumable", "1.0.0"))
+        .dispatch();
+    assert_eq!(resp.status(), Status::Ok);
+    assert!(resp.headers().get_one("X-Deletion-Token").is_some());
+    std::fs::remove_file(keys_file).ok();
+}